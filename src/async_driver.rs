@@ -0,0 +1,198 @@
+//! The async drivers for the state machines, shared by the tokio and
+//! `embedded-io-async` front ends. Each of them only has to provide the
+//! few operations below.
+
+use core::time::Duration;
+
+use ::log::{debug, info};
+
+use crate::machine::{Machine, ReceiverEvent, ReceiverMachine, SenderEvent, SenderMachine};
+use crate::{Checksum, Error, PadTrimmer, Result, SendBuffer, Xmodem, io};
+
+/// The serial channel, with a timer to enforce the protocol timeouts.
+pub(crate) trait AsyncDevice {
+    /// Reads a single byte, or returns `None` if `timeout` expires first.
+    async fn read_byte(&mut self, timeout: Duration) -> io::Result<Option<u8>>;
+
+    async fn write_all(&mut self, buf: &[u8]) -> io::Result<()>;
+
+    async fn flush(&mut self) -> io::Result<()>;
+}
+
+/// The message being sent.
+pub(crate) trait AsyncInput {
+    async fn read(&mut self, buf: &mut [u8]) -> io::Result<usize>;
+}
+
+/// Where the message being received is stored.
+pub(crate) trait AsyncOutput {
+    async fn write_all(&mut self, buf: &[u8]) -> io::Result<()>;
+
+    async fn flush(&mut self) -> io::Result<()>;
+}
+
+pub(crate) async fn send<D, R>(config: &Xmodem, dev: &mut D, stream: &mut R) -> Result<usize>
+where
+    D: AsyncDevice,
+    R: AsyncInput,
+{
+    let mut machine = SenderMachine::new(config);
+    let mut buffer = SendBuffer::new();
+    let mut bytes = 0;
+    let mut last = 0;
+
+    debug!("Starting XMODEM transfer");
+    loop {
+        let event = match machine.poll_event() {
+            Some(event) => Some(event),
+            None => step(dev, &mut machine).await?,
+        };
+        if let Some(SenderEvent::Downgraded(_)) = event {
+            buffer.unload(last);
+            bytes -= last;
+        }
+        match event {
+            Some(SenderEvent::Started(_))
+            | Some(SenderEvent::BlockDone(_))
+            | Some(SenderEvent::Downgraded(_)) => {
+                if machine.is_streaming() {
+                    poll_input(dev, &mut machine).await?;
+                }
+                if buffer.is_empty()
+                    && let Err(e) = fill(&mut buffer, stream).await
+                {
+                    machine.cancel();
+                    flush_output(dev, &mut machine).await.unwrap_or_default();
+                    return Err(Error::Io(e));
+                }
+                if buffer.is_empty() {
+                    debug!("Reached EOF");
+                    machine.finish();
+                } else {
+                    last = buffer.load(&mut machine);
+                    bytes += last;
+                    machine.send_block();
+                }
+                flush_output(dev, &mut machine).await?;
+            }
+            Some(SenderEvent::Finished) => return Ok(bytes),
+            Some(SenderEvent::Retry(_)) | None => {}
+        }
+    }
+}
+
+pub(crate) async fn recv<D, W>(
+    config: &Xmodem,
+    dev: &mut D,
+    outstream: &mut W,
+    checksum: Checksum,
+) -> Result<usize>
+where
+    D: AsyncDevice,
+    W: AsyncOutput,
+{
+    let mut machine = ReceiverMachine::new(config, checksum);
+    let mut trimmer = PadTrimmer::new(config.pad_byte);
+    let mut bytes = 0;
+
+    debug!("Starting XMODEM receive");
+    loop {
+        flush_output(dev, &mut machine).await?;
+
+        match step(dev, &mut machine).await? {
+            Some(ReceiverEvent::Block(_)) => {
+                let (held, data) = if config.trim_padding {
+                    trimmer.block(machine.block())
+                } else {
+                    (0, machine.block())
+                };
+                if let Err(e) = write_block(outstream, config.pad_byte, held, data).await {
+                    machine.cancel();
+                    flush_output(dev, &mut machine).await.unwrap_or_default();
+                    return Err(Error::Io(e));
+                }
+                bytes += held + data.len();
+                machine.accept_block();
+            }
+            Some(ReceiverEvent::Finished) => {
+                flush_output(dev, &mut machine).await?;
+                outstream.flush().await?;
+                info!("XMODEM reception successful");
+                return Ok(bytes);
+            }
+            Some(ReceiverEvent::Retry(_)) | None => {}
+        }
+    }
+}
+
+/// Reads from `stream` until `buffer` is full or the stream ends, like
+/// `SendBuffer::fill`.
+async fn fill<R: AsyncInput>(buffer: &mut SendBuffer, stream: &mut R) -> io::Result<()> {
+    while !buffer.is_full() {
+        match stream.read(buffer.space()).await {
+            Ok(0) => break,
+            Ok(n) => buffer.filled(n),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Writes `held` copies of `pad_byte`, followed by `data`.
+async fn write_block<W: AsyncOutput>(
+    out: &mut W,
+    pad_byte: u8,
+    mut held: usize,
+    data: &[u8],
+) -> io::Result<()> {
+    let pad = [pad_byte; 128];
+    while held > 0 {
+        let n = held.min(pad.len());
+        out.write_all(&pad[..n]).await?;
+        held -= n;
+    }
+    out.write_all(data).await
+}
+
+/// Waits up to the machine's timeout for the next byte from `dev`, feeds it
+/// (or the timeout) to `machine` and writes out the machine's response.
+async fn step<D, M>(dev: &mut D, machine: &mut M) -> Result<Option<M::Event>>
+where
+    D: AsyncDevice,
+    M: Machine,
+{
+    let event = match dev.read_byte(machine.timeout()).await {
+        Ok(Some(b)) => machine.handle_byte(b),
+        Ok(None) => machine.handle_timeout(),
+        Err(e) if e.kind() == io::ErrorKind::TimedOut => machine.handle_timeout(),
+        Err(e) => return Err(Error::Io(e)),
+    };
+    let flushed = flush_output(dev, machine).await;
+    let event = event?;
+    flushed?;
+    Ok(event)
+}
+
+/// Feeds `machine` whatever has arrived from the receiver. With a zero
+/// timeout, the read is only polled once, so this never waits. While
+/// streaming, this is the only way to notice that the receiver canceled
+/// before the EOT.
+async fn poll_input<D: AsyncDevice>(dev: &mut D, machine: &mut SenderMachine) -> Result<()> {
+    while let Some(b) = dev.read_byte(Duration::ZERO).await? {
+        machine.handle_byte(b)?;
+    }
+    Ok(())
+}
+
+/// Writes out everything `machine` has queued for transmission.
+async fn flush_output<D, M>(dev: &mut D, machine: &mut M) -> io::Result<()>
+where
+    D: AsyncDevice,
+    M: Machine,
+{
+    while let Some(out) = machine.poll_output() {
+        dev.write_all(out).await?;
+    }
+    dev.flush().await
+}
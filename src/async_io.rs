@@ -0,0 +1,80 @@
+//! Async drivers for the state machines, built on tokio's `AsyncRead` and
+//! `AsyncWrite`.
+
+use core::time::Duration;
+use std::io;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time;
+
+use crate::async_driver::{self, AsyncDevice, AsyncInput, AsyncOutput};
+use crate::{Checksum, Result, Xmodem};
+
+impl Xmodem {
+    /// Starts the XMODEM transmission without blocking the executor.
+    ///
+    /// This is the async equivalent of [`Xmodem::send`]. The timeouts
+    /// configured in `self` are enforced with tokio timers, regardless of
+    /// how `dev` is configured.
+    pub async fn send_async<D, R>(&mut self, dev: &mut D, stream: &mut R) -> Result<usize>
+    where
+        D: AsyncRead + AsyncWrite + Unpin,
+        R: AsyncRead + Unpin,
+    {
+        async_driver::send(self, &mut Tokio(dev), &mut Tokio(stream)).await
+    }
+
+    /// Receive an XMODEM transmission without blocking the executor.
+    ///
+    /// This is the async equivalent of [`Xmodem::recv`]. The timeouts
+    /// configured in `self` are enforced with tokio timers, regardless of
+    /// how `dev` is configured.
+    pub async fn recv_async<D, W>(
+        &mut self,
+        dev: &mut D,
+        outstream: &mut W,
+        checksum: Checksum,
+    ) -> Result<usize>
+    where
+        D: AsyncRead + AsyncWrite + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        async_driver::recv(self, &mut Tokio(dev), &mut Tokio(outstream), checksum).await
+    }
+}
+
+/// Adapts tokio's traits to the ones of the shared driver.
+struct Tokio<'a, T>(&'a mut T);
+
+impl<T: AsyncRead + AsyncWrite + Unpin> AsyncDevice for Tokio<'_, T> {
+    async fn read_byte(&mut self, timeout: Duration) -> io::Result<Option<u8>> {
+        match time::timeout(timeout, self.0.read_u8()).await {
+            Ok(res) => res.map(Some),
+            Err(_) => Ok(None),
+        }
+    }
+
+    async fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.0.write_all(buf).await
+    }
+
+    async fn flush(&mut self) -> io::Result<()> {
+        self.0.flush().await
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncInput for Tokio<'_, T> {
+    async fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf).await
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncOutput for Tokio<'_, T> {
+    async fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.0.write_all(buf).await
+    }
+
+    async fn flush(&mut self) -> io::Result<()> {
+        self.0.flush().await
+    }
+}
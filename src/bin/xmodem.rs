@@ -0,0 +1,318 @@
+//! Sends and receives files with XMODEM, over a serial port or over stdin
+//! and stdout like `sx` and `rx` from lrzsz.
+
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Stdout, Write};
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::Duration;
+
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use xmodem::{BlockLength, Checksum, RetryCause, TransferObserver, Xmodem};
+
+/// How long a read on the link waits before reporting `TimedOut`, so that
+/// the protocol timeouts are enforced.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Exit code for a transfer that failed or was canceled.
+const EXIT_TRANSFER: u8 = 1;
+/// Exit code for a file or device that couldn't be opened.
+const EXIT_OPEN: u8 = 3;
+
+#[derive(Parser)]
+#[command(version, about = "Send and receive files with XMODEM")]
+#[command(after_help = "Exit status is 0 on success, 1 if the transfer failed \
+    or was canceled, 2 on invalid arguments and 3 if a file or the device \
+    couldn't be opened.")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Send a file
+    Send {
+        /// The file to send
+        file: PathBuf,
+        /// Use 1024-byte blocks (XMODEM-1K)
+        #[arg(short = 'k', long = "1k")]
+        one_k: bool,
+        #[command(flatten)]
+        opts: Options,
+    },
+    /// Receive a file
+    Recv {
+        /// Where to write the received file
+        file: PathBuf,
+        /// The check to ask the sender for
+        #[arg(short, long, value_enum, default_value_t = CheckArg::Crc)]
+        checksum: CheckArg,
+        /// Remove the padding at the end of the last block
+        #[arg(short, long)]
+        trim: bool,
+        #[command(flatten)]
+        opts: Options,
+    },
+}
+
+#[derive(Args)]
+struct Options {
+    /// Serial device to use instead of stdin and stdout
+    #[arg(short, long)]
+    device: Option<String>,
+    /// Baud rate of the serial device
+    #[arg(short, long, default_value_t = 115_200)]
+    baud: u32,
+    /// Parity of the serial device
+    #[arg(long, value_enum, default_value_t = ParityArg::None)]
+    parity: ParityArg,
+    /// Flow control of the serial device
+    #[arg(long, value_enum, default_value_t = FlowArg::None)]
+    flow_control: FlowArg,
+    /// Give up after this many errors
+    #[arg(long, default_value_t = 16)]
+    max_errors: u32,
+    /// Seconds to wait for the other end to start the transfer
+    #[arg(long, default_value_t = 10)]
+    timeout: u64,
+    /// Don't show progress
+    #[arg(short, long)]
+    quiet: bool,
+}
+
+#[derive(Copy, Clone, ValueEnum)]
+enum CheckArg {
+    /// 8-bit checksum
+    Sum,
+    /// CRC-16
+    Crc,
+}
+
+#[derive(Copy, Clone, ValueEnum)]
+enum ParityArg {
+    None,
+    Odd,
+    Even,
+}
+
+#[derive(Copy, Clone, ValueEnum)]
+enum FlowArg {
+    None,
+    Software,
+    Hardware,
+}
+
+trait Device: Read + Write {}
+
+impl<T: Read + Write> Device for T {}
+
+/// The link over stdin and stdout. Stdin is read on a separate thread, so
+/// that reads can time out.
+struct Stdio {
+    input: Receiver<io::Result<Vec<u8>>>,
+    pending: Vec<u8>,
+    output: Stdout,
+}
+
+impl Stdio {
+    fn new() -> Stdio {
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let mut stdin = io::stdin().lock();
+            let mut buf = [0; 4096];
+            loop {
+                let res = stdin.read(&mut buf).map(|n| buf[..n].to_vec());
+                let done = !matches!(res, Ok(ref data) if !data.is_empty());
+                if tx.send(res).is_err() || done {
+                    break;
+                }
+            }
+        });
+        Stdio {
+            input: rx,
+            pending: Vec::new(),
+            output: io::stdout(),
+        }
+    }
+}
+
+impl Read for Stdio {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pending.is_empty() {
+            match self.input.recv_timeout(POLL_INTERVAL) {
+                Ok(data) => self.pending = data?,
+                Err(RecvTimeoutError::Timeout) => return Err(io::ErrorKind::TimedOut.into()),
+                Err(RecvTimeoutError::Disconnected) => return Ok(0),
+            }
+        }
+        let n = buf.len().min(self.pending.len());
+        buf[..n].copy_from_slice(&self.pending[..n]);
+        self.pending.drain(..n);
+        Ok(n)
+    }
+}
+
+impl Write for Stdio {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // Stdout is line buffered, and the drivers never flush
+        let n = self.output.write(buf)?;
+        self.output.flush()?;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.output.flush()
+    }
+}
+
+impl Options {
+    fn xmodem(&self) -> Xmodem {
+        let mut xmodem = Xmodem::new();
+        xmodem.max_errors = self.max_errors;
+        xmodem.handshake_timeout = Duration::from_secs(self.timeout);
+        xmodem
+    }
+}
+
+fn open_link(opts: &Options) -> io::Result<Box<dyn Device>> {
+    let Some(path) = &opts.device else {
+        return Ok(Box::new(Stdio::new()));
+    };
+    let parity = match opts.parity {
+        ParityArg::None => serialport::Parity::None,
+        ParityArg::Odd => serialport::Parity::Odd,
+        ParityArg::Even => serialport::Parity::Even,
+    };
+    let flow_control = match opts.flow_control {
+        FlowArg::None => serialport::FlowControl::None,
+        FlowArg::Software => serialport::FlowControl::Software,
+        FlowArg::Hardware => serialport::FlowControl::Hardware,
+    };
+    let port = serialport::new(path, opts.baud)
+        .parity(parity)
+        .flow_control(flow_control)
+        .timeout(POLL_INTERVAL)
+        .open()?;
+    Ok(Box::new(port))
+}
+
+/// Shows the progress of the transfer on stderr.
+struct Progress {
+    quiet: bool,
+    total: Option<u64>,
+    bytes: u64,
+    retries: u32,
+}
+
+impl Progress {
+    fn new(quiet: bool, total: Option<u64>) -> Progress {
+        Progress {
+            quiet,
+            total,
+            bytes: 0,
+            retries: 0,
+        }
+    }
+
+    fn show(&self) {
+        if self.quiet {
+            return;
+        }
+        match self.total {
+            Some(total) => eprint!("\r{} of {} bytes", self.bytes, total),
+            None => eprint!("\r{} bytes", self.bytes),
+        }
+        if self.retries > 0 {
+            eprint!(", {} retries", self.retries);
+        }
+    }
+}
+
+impl TransferObserver for Progress {
+    fn handshake(&mut self, checksum: Checksum, block_length: BlockLength) {
+        if !self.quiet {
+            eprintln!(
+                "Transferring with {:?} and {}-byte blocks",
+                checksum, block_length as usize
+            );
+        }
+    }
+
+    fn block(&mut self, _blockno: u32, offset: u64, len: usize) {
+        self.bytes = offset + len as u64;
+        self.show();
+    }
+
+    fn retry(&mut self, _cause: RetryCause) {
+        self.retries += 1;
+        self.show();
+    }
+
+    fn finished(&mut self, _result: &xmodem::Result<usize>) {
+        if !self.quiet && self.bytes > 0 {
+            eprintln!();
+        }
+    }
+}
+
+fn run(command: Command) -> Result<(), (u8, String)> {
+    let open_error = |what: &PathBuf| {
+        let what = what.display().to_string();
+        move |e: io::Error| (EXIT_OPEN, format!("{}: {}", what, e))
+    };
+    match command {
+        Command::Send { file, one_k, opts } => {
+            let input = File::open(&file).map_err(open_error(&file))?;
+            let total = input.metadata().ok().map(|m| m.len());
+            let mut dev = open_link(&opts).map_err(|e| (EXIT_OPEN, e.to_string()))?;
+            let mut xmodem = opts.xmodem();
+            if one_k {
+                xmodem.block_length = BlockLength::OneK;
+            }
+            let mut progress = Progress::new(opts.quiet, total);
+            xmodem
+                .send_with_observer(&mut dev, &mut BufReader::new(input), &mut progress)
+                .map_err(|e| (EXIT_TRANSFER, e.to_string()))?;
+        }
+        Command::Recv {
+            file,
+            checksum,
+            trim,
+            opts,
+        } => {
+            let output = File::create(&file).map_err(open_error(&file))?;
+            let mut dev = open_link(&opts).map_err(|e| (EXIT_OPEN, e.to_string()))?;
+            let mut xmodem = opts.xmodem();
+            xmodem.trim_padding = trim;
+            let checksum = match checksum {
+                CheckArg::Sum => Checksum::Standard,
+                CheckArg::Crc => Checksum::CRC16,
+            };
+            let mut progress = Progress::new(opts.quiet, None);
+            let mut output = BufWriter::new(output);
+            let res = xmodem
+                .recv_with_observer(&mut dev, &mut output, checksum, &mut progress)
+                .and_then(|_| Ok(output.flush()?));
+            if let Err(e) = res {
+                drop(output);
+                let _ = fs::remove_file(&file);
+                return Err((EXIT_TRANSFER, e.to_string()));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    match run(cli.command) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err((code, message)) => {
+            eprintln!("xmodem: {}", message);
+            ExitCode::from(code)
+        }
+    }
+}
@@ -0,0 +1,137 @@
+//! Integrity checks at the end of each packet.
+
+use core::fmt;
+
+use crate::Checksum;
+
+/// The longest check value supported at the end of a packet.
+pub const MAX_CHECK_LEN: usize = 8;
+
+/// An integrity check computed over the data of each block and sent after
+/// it.
+///
+/// Besides the checks negotiated in the handshake ([`Sum8`] and [`Crc16`],
+/// selected through [`Checksum`]), this allows talking to devices that use
+/// XMODEM framing with a different trailer, such as a [`Crc32`]. Set
+/// [`Xmodem::block_check`](crate::Xmodem::block_check) to use one.
+pub trait BlockCheck: Sync {
+    /// The number of bytes the check value takes up, at most
+    /// [`MAX_CHECK_LEN`].
+    fn size(&self) -> usize;
+
+    /// Computes the check value of `data` into `out`, which is
+    /// [`size`](Self::size) bytes long, in the order it is sent.
+    fn compute(&self, data: &[u8], out: &mut [u8]);
+
+    /// Whether `check` is the correct check value for `data`.
+    fn verify(&self, data: &[u8], check: &[u8]) -> bool {
+        let mut expected = [0; MAX_CHECK_LEN];
+        let expected = &mut expected[..self.size()];
+        self.compute(data, expected);
+        expected == check
+    }
+}
+
+impl fmt::Debug for dyn BlockCheck {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "BlockCheck({} bytes)", self.size())
+    }
+}
+
+/// The original XMODEM checksum: the sum of the data bytes, modulo 256.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Sum8;
+
+impl BlockCheck for Sum8 {
+    fn size(&self) -> usize {
+        1
+    }
+
+    fn compute(&self, data: &[u8], out: &mut [u8]) {
+        out[0] = data.iter().fold(0, |x, &y| x.wrapping_add(y));
+    }
+}
+
+/// CRC-16/XMODEM (polynomial 0x1021, initial value 0), sent big-endian.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Crc16;
+
+impl BlockCheck for Crc16 {
+    fn size(&self) -> usize {
+        2
+    }
+
+    fn compute(&self, data: &[u8], out: &mut [u8]) {
+        out.copy_from_slice(&crc16(data).to_be_bytes());
+    }
+}
+
+/// CRC-32 as used by Ethernet, zip and ZMODEM (reflected polynomial
+/// 0xEDB88320, initial value and final XOR 0xFFFFFFFF), sent
+/// little-endian.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Crc32;
+
+impl BlockCheck for Crc32 {
+    fn size(&self) -> usize {
+        4
+    }
+
+    fn compute(&self, data: &[u8], out: &mut [u8]) {
+        out.copy_from_slice(&crc32(data).to_le_bytes());
+    }
+}
+
+/// The check for the mode agreed on in the handshake.
+impl BlockCheck for Checksum {
+    fn size(&self) -> usize {
+        match self {
+            Checksum::Standard => Sum8.size(),
+            Checksum::CRC16 | Checksum::Auto => Crc16.size(),
+        }
+    }
+
+    fn compute(&self, data: &[u8], out: &mut [u8]) {
+        match self {
+            Checksum::Standard => Sum8.compute(data, out),
+            Checksum::CRC16 | Checksum::Auto => Crc16.compute(data, out),
+        }
+    }
+}
+
+pub(crate) fn crc16(data: &[u8]) -> u16 {
+    crc16::State::<crc16::XMODEM>::calculate(data)
+}
+
+pub(crate) fn crc32(data: &[u8]) -> u32 {
+    !crc32_update(!0, data)
+}
+
+/// Feeds `data` to a running CRC-32, without the initial and final
+/// inversion.
+pub(crate) fn crc32_update(mut crc: u32, data: &[u8]) -> u32 {
+    for &byte in data {
+        crc = CRC32_TABLE[usize::from((crc as u8) ^ byte)] ^ (crc >> 8);
+    }
+    crc
+}
+
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
@@ -0,0 +1,94 @@
+//! Clocks used by the blocking drivers to enforce timeouts.
+
+use core::time::Duration;
+
+/// A monotonic clock.
+///
+/// The blocking drivers use a clock to enforce the timeouts configured in
+/// [`Xmodem`](crate::Xmodem) themselves, instead of relying on the device
+/// to report `ErrorKind::TimedOut`. On `no_std` targets this is typically
+/// backed by a hardware timer or a tick counter.
+pub trait Clock {
+    /// The time elapsed since an arbitrary, fixed point in the past.
+    fn now(&mut self) -> Duration;
+}
+
+impl<C: Clock + ?Sized> Clock for &mut C {
+    fn now(&mut self) -> Duration {
+        (**self).now()
+    }
+}
+
+/// A [`Clock`] backed by `std::time::Instant`.
+#[cfg(feature = "std")]
+#[derive(Copy, Clone, Debug)]
+pub struct StdClock {
+    start: std::time::Instant,
+}
+
+#[cfg(feature = "std")]
+impl StdClock {
+    pub fn new() -> Self {
+        StdClock {
+            start: std::time::Instant::now(),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl Default for StdClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(feature = "std")]
+impl Clock for StdClock {
+    fn now(&mut self) -> Duration {
+        self.start.elapsed()
+    }
+}
+
+/// The clock used when the caller doesn't provide one: a [`StdClock`] with
+/// `std`, and none (relying on the device's own timeouts) without.
+#[derive(Default)]
+pub(crate) struct DefaultClock {
+    #[cfg(feature = "std")]
+    clock: StdClock,
+}
+
+impl DefaultClock {
+    pub(crate) fn get(&mut self) -> Option<&mut dyn Clock> {
+        #[cfg(feature = "std")]
+        return Some(&mut self.clock);
+        #[cfg(not(feature = "std"))]
+        return None;
+    }
+}
+
+/// An async timer, used by the `embedded-io-async` drivers to enforce
+/// timeouts without blocking the executor.
+///
+/// With embassy this is a thin wrapper around `embassy_time::Timer`:
+///
+/// ```ignore
+/// struct EmbassyTimer;
+///
+/// impl xmodem::AsyncTimer for EmbassyTimer {
+///     async fn sleep(&mut self, duration: core::time::Duration) {
+///         embassy_time::Timer::after_micros(duration.as_micros() as u64).await
+///     }
+/// }
+/// ```
+#[cfg(feature = "embedded-io-async")]
+pub trait AsyncTimer {
+    /// Completes once `duration` has elapsed.
+    fn sleep(&mut self, duration: Duration) -> impl Future<Output = ()>;
+}
+
+#[cfg(feature = "embedded-io-async")]
+impl<T: AsyncTimer + ?Sized> AsyncTimer for &mut T {
+    fn sleep(&mut self, duration: Duration) -> impl Future<Output = ()> {
+        (**self).sleep(duration)
+    }
+}
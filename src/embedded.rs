@@ -0,0 +1,104 @@
+//! Adapters for the serial traits of the embedded Rust ecosystem.
+//!
+//! These wrap a HAL's serial peripheral so that it can be passed as the
+//! device to [`Xmodem::send`](crate::Xmodem::send) and
+//! [`Xmodem::recv`](crate::Xmodem::recv) and friends. Both report
+//! `WouldBlock` while no data is available, so the timeouts are enforced by
+//! the [`Clock`](crate::Clock) passed to `send_with_clock` or
+//! `recv_with_clock`.
+
+#[cfg(feature = "embedded-hal-nb")]
+use embedded_hal_nb::nb;
+
+use crate::io;
+
+/// Wraps a device implementing the `embedded-io` traits.
+///
+/// Reading requires `embedded_io::ReadReady`, so that reads never block.
+#[cfg(feature = "embedded-io")]
+#[derive(Debug)]
+pub struct EmbeddedIo<T>(pub T);
+
+#[cfg(feature = "embedded-io")]
+impl<T: embedded_io::Read + embedded_io::ReadReady> io::Read for EmbeddedIo<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if !self.0.read_ready().map_err(from_embedded_io)? {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        self.0.read(buf).map_err(from_embedded_io)
+    }
+}
+
+#[cfg(feature = "embedded-io")]
+impl<T: embedded_io::Write> io::Write for EmbeddedIo<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf).map_err(from_embedded_io)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush().map_err(from_embedded_io)
+    }
+}
+
+#[cfg(feature = "embedded-io")]
+pub(crate) fn from_embedded_io<E: embedded_io::Error>(err: E) -> io::Error {
+    match err.kind() {
+        embedded_io::ErrorKind::TimedOut => io::ErrorKind::TimedOut.into(),
+        embedded_io::ErrorKind::Interrupted => io::ErrorKind::Interrupted.into(),
+        _ => io::Error::other("embedded-io error"),
+    }
+}
+
+/// Wraps a serial peripheral implementing the `embedded-hal-nb` traits.
+///
+/// Reads return the bytes that are available without blocking. Writes
+/// block until each byte has been accepted by the peripheral.
+#[cfg(feature = "embedded-hal-nb")]
+#[derive(Debug)]
+pub struct NbSerial<T>(pub T);
+
+#[cfg(feature = "embedded-hal-nb")]
+impl<T: embedded_hal_nb::serial::Read> io::Read for NbSerial<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        for (n, byte) in buf.iter_mut().enumerate() {
+            match self.0.read() {
+                Ok(b) => *byte = b,
+                Err(nb::Error::WouldBlock) if n == 0 => {
+                    return Err(io::ErrorKind::WouldBlock.into());
+                }
+                Err(nb::Error::WouldBlock) => return Ok(n),
+                Err(nb::Error::Other(e)) => return Err(from_serial(e)),
+            }
+        }
+        Ok(buf.len())
+    }
+}
+
+#[cfg(feature = "embedded-hal-nb")]
+impl<T: embedded_hal_nb::serial::Write> io::Write for NbSerial<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for &byte in buf {
+            nb::block!(self.0.write(byte)).map_err(from_serial)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        nb::block!(self.0.flush()).map_err(from_serial)
+    }
+}
+
+#[cfg(feature = "embedded-hal-nb")]
+fn from_serial<E: embedded_hal_nb::serial::Error>(err: E) -> io::Error {
+    use embedded_hal_nb::serial::ErrorKind;
+    io::Error::other(match err.kind() {
+        ErrorKind::Overrun => "serial overrun",
+        ErrorKind::FrameFormat => "serial framing error",
+        ErrorKind::Parity => "serial parity error",
+        ErrorKind::Noise => "serial noise",
+        _ => "serial error",
+    })
+}
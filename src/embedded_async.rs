@@ -0,0 +1,110 @@
+//! Async drivers for the state machines, built on the `embedded-io-async`
+//! traits so that they run on `no_std` executors such as embassy.
+
+use core::future::{Future, poll_fn};
+use core::pin::pin;
+use core::task::Poll;
+use core::time::Duration;
+
+use embedded_io_async::{Read, Write};
+
+use crate::async_driver::{self, AsyncDevice, AsyncInput, AsyncOutput};
+use crate::embedded::from_embedded_io;
+use crate::{AsyncTimer, Checksum, Result, Xmodem, io};
+
+impl Xmodem {
+    /// Starts the XMODEM transmission without blocking the executor.
+    ///
+    /// This is the equivalent of [`Xmodem::send`] for the `embedded-io-async`
+    /// traits. The timeouts configured in `self` are enforced with `timer`.
+    pub async fn send_embedded_async<D, R, T>(
+        &mut self,
+        dev: &mut D,
+        stream: &mut R,
+        timer: &mut T,
+    ) -> Result<usize>
+    where
+        D: Read + Write,
+        R: Read,
+        T: AsyncTimer,
+    {
+        let mut dev = Device { dev, timer };
+        async_driver::send(self, &mut dev, &mut Stream(stream)).await
+    }
+
+    /// Receive an XMODEM transmission without blocking the executor.
+    ///
+    /// This is the equivalent of [`Xmodem::recv`] for the `embedded-io-async`
+    /// traits. The timeouts configured in `self` are enforced with `timer`.
+    pub async fn recv_embedded_async<D, W, T>(
+        &mut self,
+        dev: &mut D,
+        outstream: &mut W,
+        checksum: Checksum,
+        timer: &mut T,
+    ) -> Result<usize>
+    where
+        D: Read + Write,
+        W: Write,
+        T: AsyncTimer,
+    {
+        let mut dev = Device { dev, timer };
+        async_driver::recv(self, &mut dev, &mut Stream(outstream), checksum).await
+    }
+}
+
+/// The serial channel, with the timer that bounds the reads from it.
+struct Device<'a, D, T> {
+    dev: &'a mut D,
+    timer: &'a mut T,
+}
+
+impl<D: Read + Write, T: AsyncTimer> AsyncDevice for Device<'_, D, T> {
+    async fn read_byte(&mut self, timeout: Duration) -> io::Result<Option<u8>> {
+        let mut byte = [0];
+        let result = {
+            let mut read = pin!(self.dev.read(&mut byte));
+            let mut sleep = pin!(self.timer.sleep(timeout));
+            poll_fn(|cx| {
+                if let Poll::Ready(result) = read.as_mut().poll(cx) {
+                    return Poll::Ready(Some(result));
+                }
+                sleep.as_mut().poll(cx).map(|()| None)
+            })
+            .await
+        };
+        match result {
+            None => Ok(None),
+            Some(Ok(0)) => Err(io::ErrorKind::UnexpectedEof.into()),
+            Some(Ok(_)) => Ok(Some(byte[0])),
+            Some(Err(e)) => Err(from_embedded_io(e)),
+        }
+    }
+
+    async fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.dev.write_all(buf).await.map_err(from_embedded_io)
+    }
+
+    async fn flush(&mut self) -> io::Result<()> {
+        self.dev.flush().await.map_err(from_embedded_io)
+    }
+}
+
+/// Adapts the message stream to the shared driver.
+struct Stream<'a, T>(&'a mut T);
+
+impl<T: Read> AsyncInput for Stream<'_, T> {
+    async fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf).await.map_err(from_embedded_io)
+    }
+}
+
+impl<T: Write> AsyncOutput for Stream<'_, T> {
+    async fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.0.write_all(buf).await.map_err(from_embedded_io)
+    }
+
+    async fn flush(&mut self) -> io::Result<()> {
+        self.0.flush().await.map_err(from_embedded_io)
+    }
+}
@@ -1,6 +1,8 @@
 #![cfg_attr(not(feature = "std"), no_std)]
 
-use core::convert::{From, TryFrom};
+use core::convert::From;
+use core::fmt;
+use core::time::Duration;
 
 #[cfg(not(feature = "std"))]
 /// In a `no_std` environment, `std::io` is not available.  We
@@ -15,9 +17,30 @@ pub mod io {
     #[derive(Clone, Copy, Debug, Eq, PartialEq)]
     pub enum ErrorKind {
         TimedOut,
+        WouldBlock,
+        Interrupted,
+        UnexpectedEof,
         Other,
     }
 
+    impl ErrorKind {
+        fn as_str(self) -> &'static str {
+            match self {
+                ErrorKind::TimedOut => "timed out",
+                ErrorKind::WouldBlock => "operation would block",
+                ErrorKind::Interrupted => "operation interrupted",
+                ErrorKind::UnexpectedEof => "unexpected end of file",
+                ErrorKind::Other => "other error",
+            }
+        }
+    }
+
+    impl fmt::Display for ErrorKind {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str(self.as_str())
+        }
+    }
+
     #[derive(Debug)]
     pub struct Error {
         kind: ErrorKind,
@@ -29,11 +52,21 @@ pub mod io {
             Error { kind, message }
         }
 
+        pub fn other(message: &'static str) -> Error {
+            Error::new(ErrorKind::Other, message)
+        }
+
         pub fn kind(&self) -> ErrorKind {
             self.kind
         }
     }
 
+    impl From<ErrorKind> for Error {
+        fn from(kind: ErrorKind) -> Error {
+            Error::new(kind, kind.as_str())
+        }
+    }
+
     impl fmt::Display for Error {
         fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
             write!(f, "IO error {:?}: {}", self.kind, self.message)
@@ -44,21 +77,104 @@ pub mod io {
 
     pub trait Read {
         fn read(&mut self, buf: &mut [u8]) -> Result<usize>;
-        fn read_exact(&mut self, buf: &mut [u8]) -> Result<()>;
+
+        fn read_exact(&mut self, mut buf: &mut [u8]) -> Result<()> {
+            while !buf.is_empty() {
+                match self.read(buf) {
+                    Ok(0) => break,
+                    Ok(n) => buf = &mut buf[n..],
+                    Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                    Err(e) => return Err(e),
+                }
+            }
+            if !buf.is_empty() {
+                return Err(Error::new(
+                    ErrorKind::UnexpectedEof,
+                    "failed to fill whole buffer",
+                ));
+            }
+            Ok(())
+        }
     }
 
     pub trait Write {
         fn flush(&mut self) -> Result<()>;
         fn write(&mut self, buf: &[u8]) -> Result<usize>;
-        fn write_all(&mut self, buf: &[u8]) -> Result<()>;
+
+        fn write_all(&mut self, mut buf: &[u8]) -> Result<()> {
+            while !buf.is_empty() {
+                match self.write(buf) {
+                    Ok(0) => {
+                        return Err(Error::new(ErrorKind::Other, "failed to write whole buffer"));
+                    }
+                    Ok(n) => buf = &buf[n..],
+                    Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                    Err(e) => return Err(e),
+                }
+            }
+            Ok(())
+        }
+    }
+
+    impl<R: Read + ?Sized> Read for &mut R {
+        #[inline]
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+            (**self).read(buf)
+        }
+
+        #[inline]
+        fn read_exact(&mut self, buf: &mut [u8]) -> Result<()> {
+            (**self).read_exact(buf)
+        }
+    }
+
+    impl<W: Write + ?Sized> Write for &mut W {
+        #[inline]
+        fn flush(&mut self) -> Result<()> {
+            (**self).flush()
+        }
+
+        #[inline]
+        fn write(&mut self, buf: &[u8]) -> Result<usize> {
+            (**self).write(buf)
+        }
+
+        #[inline]
+        fn write_all(&mut self, buf: &[u8]) -> Result<()> {
+            (**self).write_all(buf)
+        }
+    }
+
+    // Credit where due: these are mostly taken from `std::io`.
+    impl Read for &[u8] {
+        #[inline]
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+            let n = usize::min(buf.len(), self.len());
+            let (a, b) = self.split_at(n);
+            buf[..n].copy_from_slice(a);
+            *self = b;
+            Ok(n)
+        }
+
+        #[inline]
+        fn read_exact(&mut self, buf: &mut [u8]) -> Result<()> {
+            if buf.len() > self.len() {
+                *self = &self[self.len()..];
+                return Err(Error::new(
+                    ErrorKind::UnexpectedEof,
+                    "failed to fill whole buffer",
+                ));
+            }
+            self.read(buf)?;
+            Ok(())
+        }
     }
 
-    // Credit where due: this is mostly taken from `std::io`.
     impl Write for &mut [u8] {
         #[inline]
         fn write(&mut self, data: &[u8]) -> Result<usize> {
             let n = usize::min(data.len(), self.len());
-            let dst = mem::replace(self, &mut []);
+            let dst = mem::take(self);
             let (a, b) = dst.split_at_mut(n);
             a.copy_from_slice(&data[..n]);
             *self = b;
@@ -84,11 +200,49 @@ use std::io;
 
 use io::{Read, Write};
 
-use ::log::{debug, error, info, warn};
-
-// TODO: Send CAN byte after too many errors
-// TODO: Handle CAN bytes while sending
-// TODO: Implement Error for Error
+use ::log::{debug, info};
+
+#[cfg(any(feature = "async", feature = "embedded-io-async"))]
+mod async_driver;
+#[cfg(feature = "async")]
+mod async_io;
+mod check;
+mod clock;
+#[cfg(any(feature = "embedded-io", feature = "embedded-hal-nb"))]
+mod embedded;
+#[cfg(feature = "embedded-io-async")]
+mod embedded_async;
+mod machine;
+mod observer;
+mod report;
+mod sink;
+#[cfg(feature = "std")]
+pub mod testing;
+mod transfer;
+mod ymodem;
+mod zmodem;
+
+pub use check::{BlockCheck, Crc16, Crc32, MAX_CHECK_LEN, Sum8};
+#[cfg(feature = "embedded-io-async")]
+pub use clock::AsyncTimer;
+pub use clock::Clock;
+#[cfg(feature = "std")]
+pub use clock::StdClock;
+#[cfg(feature = "embedded-io")]
+pub use embedded::EmbeddedIo;
+#[cfg(feature = "embedded-hal-nb")]
+pub use embedded::NbSerial;
+
+use clock::DefaultClock;
+pub use machine::{ReceiverEvent, ReceiverMachine, RetryCause, SenderEvent, SenderMachine};
+pub use observer::{CancelToken, TransferObserver};
+pub use report::{RetryCounts, TransferReport};
+pub use sink::{BlockAction, BlockSink};
+pub use transfer::Transfer;
+pub use ymodem::{FileInfo, Ymodem};
+pub use zmodem::Zmodem;
+
+use machine::Machine;
 
 const SOH: u8 = 0x01;
 const STX: u8 = 0x02;
@@ -96,7 +250,29 @@ const EOT: u8 = 0x04;
 const ACK: u8 = 0x06;
 const NAK: u8 = 0x15;
 const CAN: u8 = 0x18;
+const BS: u8 = 0x08;
 const CRC: u8 = 0x43;
+const STREAM: u8 = 0x47;
+
+/// The length of a packet header: start byte, sequence number and its
+/// complement.
+const HEADER_LEN: usize = 3;
+
+/// The most CAN bytes sent to abort a transfer.
+const MAX_CANCEL_LEN: usize = 16;
+
+/// How long to wait before polling a device again after it kept returning
+/// `WouldBlock`.
+#[cfg(feature = "std")]
+const POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// How many times to poll such a device before starting to wait
+/// `POLL_INTERVAL` between polls.
+#[cfg(feature = "std")]
+const SPIN_POLLS: u32 = 1000;
+
+/// The largest possible packet: a 1024-byte block with the longest check.
+const MAX_PACKET_LEN: usize = HEADER_LEN + 1024 + MAX_CHECK_LEN;
 
 pub type Result<T> = core::result::Result<T, Error>;
 
@@ -106,147 +282,231 @@ pub enum Error {
 
     /// The number of communications errors exceeded `max_errors` in a
     /// single transmission.
-    ExhaustedRetries,
+    ExhaustedRetries(Phase),
 
     /// The transmission was canceled by the other end of the channel.
-    Canceled,
+    Canceled(Phase),
+
+    /// The transmission was aborted on this end, through
+    /// [`TransferObserver::is_canceled`].
+    Aborted,
+
+    /// A [`BlockSink`] refused a block with [`BlockAction::Abort`]. The
+    /// transmission was canceled.
+    Rejected(Phase),
 
     /// Data was received that is not appropriate to the transfer state.
     Invalid,
 
-    /// A packet was received with mismatched sequence numbers.
-    SequenceMismatch,
+    /// An intact packet was received with a sequence number that is neither
+    /// the expected one nor a repeat of the previous one. The transfer was
+    /// canceled. Packets with a corrupted header are rejected with a NAK
+    /// instead.
+    SequenceMismatch {
+        expected: u8,
+        received: u8,
+    },
 
-    /// A packet was received with an incorrect checksum or CRC16.
+    /// A packet was received with an incorrect checksum or CRC.
     Checksum,
 }
 
+/// The part of a transfer in which an error occurred.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Phase {
+    /// Before the first block, while the receiver asks the sender to start.
+    Handshake,
+
+    /// While transferring the block with the given number (counting from 1,
+    /// without wrapping at 256).
+    Block(u32),
+
+    /// While the sender ends the transmission.
+    Eot,
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Phase::Handshake => f.write_str("during the handshake"),
+            Phase::Block(blockno) => write!(f, "at block {}", blockno),
+            Phase::Eot => f.write_str("at the end of the transmission"),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(err) => write!(f, "IO error: {}", err),
+            Error::ExhaustedRetries(phase) => write!(f, "too many errors {}", phase),
+            Error::Canceled(phase) => write!(f, "canceled by the other end {}", phase),
+            Error::Aborted => f.write_str("aborted on this end"),
+            Error::Rejected(phase) => write!(f, "rejected by the sink {}", phase),
+            Error::Invalid => f.write_str("invalid data received"),
+            Error::SequenceMismatch { expected, received } => write!(
+                f,
+                "received block with sequence number {} while expecting {}",
+                received, expected
+            ),
+            Error::Checksum => f.write_str("checksum mismatch"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
 impl From<io::Error> for Error {
     fn from(err: io::Error) -> Error {
         Error::Io(err)
     }
 }
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum Checksum {
     Standard,
     CRC16,
+
+    /// Receive only: ask for CRC16, and fall back to the standard checksum
+    /// if the sender doesn't respond after `crc_attempts` requests. Packets
+    /// are never sent or checked in this mode; it is resolved to one of
+    /// the others during the handshake.
+    Auto,
 }
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum BlockLength {
     Standard = 128,
     OneK = 1024,
 }
 
-struct XmodemPacket {
-    pub seqno: u8,
-    data: XmodemBuffer,
-}
+impl BlockLength {
+    /// The smallest buffer that holds a packet with blocks of this length,
+    /// for [`SenderMachine::with_buffer`], [`ReceiverMachine::with_buffer`]
+    /// and [`Xmodem::recv_with_buffer`].
+    pub const fn packet_buffer_len(self) -> usize {
+        HEADER_LEN + self as usize + MAX_CHECK_LEN
+    }
 
-#[allow(clippy::large_enum_variant)]
-enum XmodemBuffer {
-    Standard([u8; 128]),
-    OneK([u8; 1024]),
-}
+    /// The smallest buffer for [`Xmodem::send_with_buffer`] with blocks of
+    /// this length. Besides the packet, it holds the next block read from
+    /// the input.
+    pub const fn send_buffer_len(self) -> usize {
+        self.packet_buffer_len() + self as usize
+    }
 
-impl XmodemPacket {
-    pub fn new(l: BlockLength, pad: u8) -> Self {
-        match l {
-            BlockLength::Standard => XmodemPacket {
-                seqno: 0,
-                data: XmodemBuffer::Standard([pad; 128]),
-            },
-            BlockLength::OneK => XmodemPacket {
-                seqno: 0,
-                data: XmodemBuffer::OneK([pad; 1024]),
-            },
+    /// The longest blocks that fit in a buffer of `len` bytes, given the
+    /// space each block needs.
+    fn fitting(len: usize, needed: fn(BlockLength) -> usize) -> BlockLength {
+        assert!(
+            len >= needed(BlockLength::Standard),
+            "buffer of {} bytes is too small for 128-byte blocks",
+            len
+        );
+        if len >= needed(BlockLength::OneK) {
+            BlockLength::OneK
+        } else {
+            BlockLength::Standard
         }
     }
+}
 
-    fn recv_next<R: Read>(r: &mut R, c: Checksum) -> Result<Option<Self>> {
-        let mut ret = match get_byte(r)? {
-            SOH => Self::new(BlockLength::Standard, 0),
-            STX => Self::new(BlockLength::OneK, 0),
-            EOT => return Ok(None),
-            _ => return Err(Error::Invalid),
+/// A single XMODEM packet, stored as it appears on the wire: header,
+/// data block and checksum.
+struct XmodemPacket<B = [u8; MAX_PACKET_LEN]> {
+    pub seqno: u8,
+    block_length: BlockLength,
+    frame: B,
+}
+
+impl<B: AsRef<[u8]> + AsMut<[u8]>> XmodemPacket<B> {
+    /// Creates a packet stored in `frame`, which must be large enough for
+    /// blocks of length `l`.
+    pub fn with_buffer(frame: B, l: BlockLength, pad: u8) -> Self {
+        BlockLength::fitting(frame.as_ref().len(), BlockLength::packet_buffer_len);
+        let mut packet = XmodemPacket {
+            seqno: 0,
+            block_length: l,
+            frame,
         };
+        packet.reset(l, pad);
+        packet
+    }
 
-        /*
-         * The next two bytes are the packet sequence number mod 256
-         * and the 1's complement of that.  If they don't match we'll
-         * return an error later; we still need to read the packet
-         * to maintain proper transaction state.
-         */
-        let recv_seqno = get_byte(r)?;
-        let recv_seqno1c = get_byte(r)?;
-
-        r.read_exact(ret.as_mut())?;
-
-        let checksum_ok = match c {
-            Checksum::Standard => {
-                let recv_checksum = get_byte(r)?;
-                calc_checksum(ret.as_ref()) == recv_checksum
-            }
-            Checksum::CRC16 => {
-                calc_crc(ret.as_ref()) == u16::from_be_bytes([get_byte(r)?, get_byte(r)?])
-            }
+    /// The longest blocks that fit in the frame buffer.
+    fn max_block_length(&self) -> BlockLength {
+        BlockLength::fitting(self.frame.as_ref().len(), BlockLength::packet_buffer_len)
+    }
+
+    /// Prepares the packet for a new block of length `l`, filling the data
+    /// with `pad`.
+    fn reset(&mut self, l: BlockLength, pad: u8) {
+        self.block_length = l;
+        self.frame.as_mut()[0] = match l {
+            BlockLength::Standard => SOH,
+            BlockLength::OneK => STX,
         };
+        self.as_mut().fill(pad);
+    }
 
-        if 0xFF - recv_seqno != recv_seqno1c {
-            return Err(Error::SequenceMismatch);
-        }
+    /// The length of the packet on the wire when using check `c`.
+    fn frame_len(&self, c: &dyn BlockCheck) -> usize {
+        HEADER_LEN + self.block_length as usize + c.size()
+    }
 
-        if checksum_ok {
-            ret.seqno = recv_seqno;
-            return Ok(Some(ret));
-        }
+    /// Fills in the header and check value for sending with check `c`.
+    fn encode(&mut self, c: &dyn BlockCheck) {
+        let end = HEADER_LEN + self.block_length as usize;
+        let frame = self.frame.as_mut();
+        frame[1] = self.seqno;
+        frame[2] = 0xFF - self.seqno;
+        let (data, check) = frame[HEADER_LEN..].split_at_mut(end - HEADER_LEN);
+        c.compute(data, &mut check[..c.size()]);
+    }
 
-        Err(Error::Checksum)
+    /// The whole packet as it appears on the wire.
+    fn frame(&self, c: &dyn BlockCheck) -> &[u8] {
+        &self.frame.as_ref()[..self.frame_len(c)]
     }
 
-    fn send<W: Write>(&self, w: &mut W, c: Checksum) -> Result<()> {
-        let header: [u8; 3] = [
-            match self.data {
-                XmodemBuffer::Standard(_) => SOH,
-                XmodemBuffer::OneK(_) => STX,
-            },
-            self.seqno,
-            0xFF - self.seqno,
-        ];
+    /// Checks a packet that was received into the frame buffer, and sets
+    /// `seqno` if it is valid.
+    fn decode(&mut self, c: &dyn BlockCheck) -> Result<()> {
+        let end = HEADER_LEN + self.block_length as usize;
+        let frame = self.frame.as_ref();
+        let checksum_ok = c.verify(&frame[HEADER_LEN..end], &frame[end..end + c.size()]);
 
-        debug!("Sending block {}", self.seqno);
-        w.write_all(&header)?;
-        w.write_all(self.as_ref())?;
+        let (recv_seqno, recv_seqno1c) = (frame[1], frame[2]);
+        if 0xFF - recv_seqno != recv_seqno1c {
+            return Err(Error::Invalid);
+        }
 
-        match c {
-            Checksum::Standard => {
-                w.write_all(&[calc_checksum(self.as_ref())])?;
-            }
-            Checksum::CRC16 => {
-                w.write_all(&calc_crc(self.as_ref()).to_be_bytes())?;
-            }
+        if checksum_ok {
+            self.seqno = recv_seqno;
+            return Ok(());
         }
 
-        Ok(())
+        Err(Error::Checksum)
     }
 }
 
-impl AsRef<[u8]> for XmodemPacket {
+impl<B: AsRef<[u8]>> AsRef<[u8]> for XmodemPacket<B> {
     fn as_ref(&self) -> &[u8] {
-        match self.data {
-            XmodemBuffer::Standard(ref b) => b,
-            XmodemBuffer::OneK(ref b) => b,
-        }
+        &self.frame.as_ref()[HEADER_LEN..HEADER_LEN + self.block_length as usize]
     }
 }
 
-impl AsMut<[u8]> for XmodemPacket {
+impl<B: AsMut<[u8]>> AsMut<[u8]> for XmodemPacket<B> {
     fn as_mut(&mut self) -> &mut [u8] {
-        match self.data {
-            XmodemBuffer::Standard(ref mut b) => b,
-            XmodemBuffer::OneK(ref mut b) => b,
-        }
+        &mut self.frame.as_mut()[HEADER_LEN..HEADER_LEN + self.block_length as usize]
     }
 }
 
@@ -267,10 +527,64 @@ pub struct Xmodem {
     /// blocks (standard  XMODEM) or 1024-byte blocks (XMODEM-1k).
     pub block_length: BlockLength,
 
-    /// The checksum mode used by XMODEM. This is determined by the
-    /// receiver.
-    checksum_mode: Checksum,
-    errors: u32,
+    /// How long to wait for the start of the transfer: the sender waiting
+    /// for the receiver's NAK or 'C', or the receiver waiting for the first
+    /// block. Each timeout counts against `max_errors`.
+    pub handshake_timeout: Duration,
+
+    /// How long to wait between two bytes of the same packet.
+    pub byte_timeout: Duration,
+
+    /// How long to wait for the response to a block: the sender waiting
+    /// for the ACK, or the receiver waiting for the next block.
+    pub ack_timeout: Duration,
+
+    /// How long the sender waits for the ACK of its EOT.
+    pub eot_timeout: Duration,
+
+    /// When receiving with [`Checksum::Auto`], the number of times the
+    /// receiver asks for CRC16 before falling back to the standard
+    /// checksum.
+    pub crc_attempts: u32,
+
+    /// When receiving, drop the trailing `pad_byte`s of the final block
+    /// instead of writing them out. This is off by default, since it also
+    /// strips any of those bytes that were really part of the message; use
+    /// [`recv_exact`](Self::recv_exact) if the length is known.
+    pub trim_padding: bool,
+
+    /// The number of CAN bytes sent to tell the other end to abort the
+    /// transfer, at most 16. Receivers need at least two in a row.
+    pub cancel_count: u32,
+
+    /// Follow the CAN bytes by as many backspaces, to erase them from the
+    /// other end's command line in case it already exited the transfer.
+    pub cancel_backspaces: bool,
+
+    /// With 1024-byte blocks, send the end of the message in 128-byte
+    /// blocks if it fits in seven of them, rather than padding a 1K block.
+    /// Also switch to 128-byte blocks for the rest of the transfer after
+    /// `downgrade_errors` consecutive errors on a 1K block, starting with
+    /// the data of the block that failed.
+    pub downgrade_blocks: bool,
+
+    /// See `downgrade_blocks`.
+    pub downgrade_errors: u32,
+
+    /// When receiving, ask for XMODEM-G: the sender streams the blocks
+    /// without waiting for ACKs, and any error aborts the transfer. This
+    /// always uses CRC16 and is only suitable for error-free links. Senders
+    /// stream whenever the receiver asks for it, regardless of this flag.
+    /// The async senders notice a cancel from the receiver between blocks;
+    /// the blocking ones can't read without waiting, so they notice it when
+    /// sending the EOT.
+    pub streaming: bool,
+
+    /// Replaces the check value at the end of each packet, regardless of
+    /// the checksum mode agreed on in the handshake. Both ends have to use
+    /// the same one; this is for devices using XMODEM framing with another
+    /// trailer, such as a [`Crc32`].
+    pub block_check: Option<&'static dyn BlockCheck>,
 }
 
 impl Xmodem {
@@ -280,33 +594,119 @@ impl Xmodem {
             max_errors: 16,
             pad_byte: 0x1a,
             block_length: BlockLength::Standard,
-            checksum_mode: Checksum::Standard,
-            errors: 0,
+            handshake_timeout: Duration::from_secs(10),
+            byte_timeout: Duration::from_secs(1),
+            ack_timeout: Duration::from_secs(10),
+            eot_timeout: Duration::from_secs(10),
+            crc_attempts: 3,
+            trim_padding: false,
+            cancel_count: 8,
+            cancel_backspaces: false,
+            downgrade_blocks: false,
+            downgrade_errors: 3,
+            streaming: false,
+            block_check: None,
         }
     }
 
+    /// Starts a transfer with this configuration, to which a
+    /// caller-provided buffer, a clock and an observer can be added in any
+    /// combination. The other `send` and `recv` methods are shortcuts for
+    /// it.
+    pub fn transfer<'a>(&self) -> Transfer<'a> {
+        Transfer::new(self)
+    }
+
     /// Starts the XMODEM transmission.
     ///
     /// `dev` should be the serial communication channel (e.g. the serial
     /// device). `stream` should be the message to send (e.g. a file).
     ///
+    /// This is a blocking driver for a [`SenderMachine`]; use the machine
+    /// directly if blocking IO is not an option.
+    ///
     /// # Timeouts
-    /// This method has no way of setting the timeout of `dev`, so it's up
-    /// to the caller to set the timeout of the device before calling this
-    /// method. Timeouts on receiving bytes will be counted against
-    /// `max_errors`, but timeouts on transmitting bytes will be considered
-    /// a fatal error.
+    /// With the `std` feature, the timeouts configured in `self` are
+    /// enforced using the system clock: `dev` may return `TimedOut`,
+    /// `WouldBlock` or `Interrupted` errors from `read` until they expire,
+    /// but it must not block forever. Without `std`, only the timeouts of
+    /// the device itself are used; see [`send_with_clock`](Self::send_with_clock).
+    /// Timeouts on receiving bytes will be counted against `max_errors`,
+    /// but timeouts on transmitting bytes will be considered a fatal error.
+    ///
+    /// See [`transfer`](Self::transfer) to combine a caller-provided
+    /// buffer, a clock and an observer.
     pub fn send<D: Read + Write, R: Read>(&mut self, dev: &mut D, stream: &mut R) -> Result<usize> {
-        self.errors = 0;
+        self.transfer().send(dev, stream)
+    }
+
+    /// Starts the XMODEM transmission, reporting its progress to
+    /// `observer`. Otherwise this is the same as [`send`](Self::send).
+    pub fn send_with_observer<D: Read + Write, R: Read, O: TransferObserver>(
+        &mut self,
+        dev: &mut D,
+        stream: &mut R,
+        observer: &mut O,
+    ) -> Result<usize> {
+        self.transfer().observer(observer).send(dev, stream)
+    }
+
+    /// Starts the XMODEM transmission, returning statistics about it along
+    /// with the result, so that they are available when the transfer
+    /// fails too. Otherwise this is the same as [`send`](Self::send).
+    ///
+    /// The elapsed time is only measured with the `std` feature.
+    pub fn send_with_report<D: Read + Write, R: Read>(
+        &mut self,
+        dev: &mut D,
+        stream: &mut R,
+    ) -> (TransferReport, Result<usize>) {
+        let mut clock = DefaultClock::default();
+        let start = clock.get().map(|c| c.now());
+        let mut report = TransferReport::default();
+        let mut transfer = self.transfer().observer(&mut report);
+        if let Some(clock) = clock.get() {
+            transfer = transfer.clock(clock);
+        }
+        let result = transfer.send(dev, stream);
+        report.elapsed = elapsed(clock.get(), start);
+        (report, result)
+    }
 
-        debug!("Starting XMODEM transfer");
-        self.start_send(dev)?;
-        debug!("First byte received. Sending stream.");
-        let bytes = self.send_stream(dev, stream)?;
-        debug!("Sending EOT");
-        self.finish_send(dev)?;
+    /// Starts the XMODEM transmission, enforcing the timeouts configured in
+    /// `self` with `clock`.
+    ///
+    /// `dev` may return `TimedOut`, `WouldBlock` or `Interrupted` errors
+    /// from `read` until the timeout expires. Otherwise this is the same as
+    /// [`send`](Self::send).
+    pub fn send_with_clock<D: Read + Write, R: Read, C: Clock>(
+        &mut self,
+        dev: &mut D,
+        stream: &mut R,
+        clock: &mut C,
+    ) -> Result<usize> {
+        self.transfer().clock(clock).send(dev, stream)
+    }
 
-        Ok(bytes)
+    /// Starts the XMODEM transmission, working in `buffer` instead of on
+    /// the stack.
+    ///
+    /// `buffer` must hold at least
+    /// [`BlockLength::Standard.send_buffer_len()`](BlockLength::send_buffer_len)
+    /// bytes. If it is too small for
+    /// [`BlockLength::OneK.send_buffer_len()`](BlockLength::send_buffer_len),
+    /// 128-byte blocks are sent regardless of `block_length`. Otherwise
+    /// this is the same as [`send`](Self::send).
+    ///
+    /// # Panics
+    /// If `buffer` is too small for 128-byte blocks.
+    pub fn send_with_buffer<D: Read + Write, R: Read>(
+        &mut self,
+        dev: &mut D,
+        stream: &mut R,
+        buffer: &mut [u8],
+    ) -> Result<usize> {
+        self.transfer().buffer(buffer).send(dev, stream)
     }
 
     /// Receive an XMODEM transmission.
@@ -316,221 +716,456 @@ impl Xmodem {
     /// `checksum` indicates which checksum mode should be used;
     /// Checksum::Standard is the original wrapping 8-bit checksum; you
     /// probably want CRC16 if the remote supports it (which, in 2021, it's
-    /// all but certain to do).
+    /// all but certain to do). Checksum::Auto asks for CRC16 and falls back
+    /// to the standard checksum for senders that don't support it.
+    ///
+    /// This is a blocking driver for a [`ReceiverMachine`]; use the machine
+    /// directly if blocking IO is not an option.
     ///
     /// # Timeouts
-    /// This method has no way of setting the timeout of `dev`, so it's up
-    /// to the caller to set the timeout of the device before calling this
-    /// method. Timeouts on receiving bytes will be counted against
-    /// `max_errors`, but timeouts on transmitting bytes will be considered
-    /// a fatal error.
+    /// Timeouts are handled as in [`send`](Self::send); see
+    /// [`recv_with_clock`](Self::recv_with_clock) to provide a clock
+    /// without `std`.
     pub fn recv<D: Read + Write, W: Write>(
         &mut self,
         dev: &mut D,
         outstream: &mut W,
         checksum: Checksum,
     ) -> Result<usize> {
-        self.errors = 0;
-        self.checksum_mode = checksum;
-        debug!("Starting XMODEM receive");
-        dev.write_all(&[match self.checksum_mode {
-            Checksum::Standard => NAK,
-            Checksum::CRC16 => CRC,
-        }])?;
-        debug!("NCG sent. Receiving stream.");
-        let mut seqno: u32 = 1;
-        let mut bytes: usize = 0;
-        loop {
-            if self.errors >= self.max_errors {
-                error!(
-                    "Exhausted max retries ({}) while waiting for data packet {}",
-                    self.max_errors, seqno
-                );
-                dev.write_all(&[CAN, CAN]).unwrap_or_default();
-                return Err(Error::ExhaustedRetries);
-            }
-
-            let packet = match XmodemPacket::recv_next(dev, self.checksum_mode) {
-                Ok(Some(x)) => {
-                    if u32::from(x.seqno) != (seqno & 0xFF) {
-                        dev.write_all(&[CAN, CAN])?;
-                        return Err(Error::Canceled);
-                    }
-                    x
-                }
-                Ok(None) => {
-                    dev.write_all(&[ACK])?;
-                    break;
-                }
-                Err(Error::Io(e)) => match e.kind() {
-                    io::ErrorKind::TimedOut => {
-                        self.errors += 1;
-                        warn!("Timeout!");
-                        continue;
-                    }
-                    _ => return Err(Error::Io(e)),
-                },
-                Err(Error::Checksum) => {
-                    dev.write_all(&[NAK])?;
-                    self.errors += 1;
-                    continue;
-                }
-                Err(Error::SequenceMismatch) => {
-                    dev.write_all(&[CAN, CAN])?;
+        self.transfer().recv(dev, outstream, checksum)
+    }
 
-                    /* XXX Is this the right code? */
-                    return Err(Error::Canceled);
-                }
-                Err(Error::Invalid) => {
-                    warn!("Unrecognized symbol!");
-                    continue;
-                }
-                Err(e) => return Err(e),
-            };
+    /// Receive an XMODEM transmission, reporting its progress to
+    /// `observer`. Otherwise this is the same as [`recv`](Self::recv).
+    pub fn recv_with_observer<D: Read + Write, W: Write, O: TransferObserver>(
+        &mut self,
+        dev: &mut D,
+        outstream: &mut W,
+        checksum: Checksum,
+        observer: &mut O,
+    ) -> Result<usize> {
+        self.transfer()
+            .observer(observer)
+            .recv(dev, outstream, checksum)
+    }
 
-            outstream.write_all(packet.as_ref()).map_err(|e| {
-                dev.write_all(&[CAN, CAN]).unwrap_or_default();
-                Error::Io(e)
-            })?;
-            dev.write_all(&[ACK])?;
-            seqno = seqno.wrapping_add(1);
-            bytes += packet.as_ref().len();
+    /// Receive an XMODEM transmission, returning statistics about it along
+    /// with the result, so that they are available when the transfer
+    /// fails too. Otherwise this is the same as [`recv`](Self::recv).
+    ///
+    /// The elapsed time is only measured with the `std` feature.
+    pub fn recv_with_report<D: Read + Write, W: Write>(
+        &mut self,
+        dev: &mut D,
+        outstream: &mut W,
+        checksum: Checksum,
+    ) -> (TransferReport, Result<usize>) {
+        let mut clock = DefaultClock::default();
+        let start = clock.get().map(|c| c.now());
+        let mut report = TransferReport::default();
+        let mut transfer = self.transfer().observer(&mut report);
+        if let Some(clock) = clock.get() {
+            transfer = transfer.clock(clock);
         }
-
-        Ok(bytes)
+        let result = transfer.recv(dev, outstream, checksum);
+        report.elapsed = elapsed(clock.get(), start);
+        (report, result)
     }
 
-    fn start_send<D: Read + Write>(&mut self, dev: &mut D) -> Result<()> {
-        let mut cancels = 0;
-        loop {
-            match get_byte_timeout(dev)? {
-                Some(NAK) => {
-                    debug!("Standard checksum requested");
-                    self.checksum_mode = Checksum::Standard;
-                    return Ok(());
-                }
-                Some(CRC) => {
-                    debug!("16-bit CRC requested");
-                    self.checksum_mode = Checksum::CRC16;
-                    return Ok(());
-                }
-                Some(CAN) => {
-                    warn!("Cancel (CAN) byte received");
-                    cancels += 1;
-                }
-                Some(c) => warn!("Unknown byte received at start of XMODEM transfer: {}", c),
-                None => warn!("Timed out waiting for start of XMODEM transfer."),
-            }
-
-            self.errors += 1;
+    /// Receive an XMODEM transmission of exactly `length` bytes.
+    ///
+    /// Anything the sender transmits beyond `length`, such as the padding
+    /// of the last block, is discarded, and `trim_padding` is ignored.
+    /// Returns the number of bytes written to `outstream`, which is less
+    /// than `length` if the sender ended the transmission early. Otherwise
+    /// this is the same as [`recv`](Self::recv).
+    pub fn recv_exact<D: Read + Write, W: Write>(
+        &mut self,
+        dev: &mut D,
+        outstream: &mut W,
+        checksum: Checksum,
+        length: u64,
+    ) -> Result<usize> {
+        self.transfer()
+            .length(length)
+            .recv(dev, outstream, checksum)
+    }
 
-            if cancels >= 2 {
-                error!(
-                    "Transmission canceled: received two cancel (CAN) bytes at start of XMODEM transfer"
-                );
-                return Err(Error::Canceled);
-            }
+    /// Receive an XMODEM transmission, enforcing the timeouts configured in
+    /// `self` with `clock`.
+    ///
+    /// `dev` may return `TimedOut`, `WouldBlock` or `Interrupted` errors
+    /// from `read` until the timeout expires. Otherwise this is the same as
+    /// [`recv`](Self::recv).
+    pub fn recv_with_clock<D: Read + Write, W: Write, C: Clock>(
+        &mut self,
+        dev: &mut D,
+        outstream: &mut W,
+        checksum: Checksum,
+        clock: &mut C,
+    ) -> Result<usize> {
+        self.transfer().clock(clock).recv(dev, outstream, checksum)
+    }
 
-            if self.errors >= self.max_errors {
-                error!(
-                    "Exhausted max retries ({}) at start of XMODEM transfer.",
-                    self.max_errors
-                );
-                if let Err(err) = dev.write_all(&[CAN]) {
-                    warn!("Error sending CAN byte: {}", err);
-                }
-                return Err(Error::ExhaustedRetries);
-            }
-        }
+    /// Receive an XMODEM transmission, storing the packets in `buffer`
+    /// instead of on the stack.
+    ///
+    /// `buffer` must hold at least
+    /// [`BlockLength::Standard.packet_buffer_len()`](BlockLength::packet_buffer_len)
+    /// bytes. If it is too small for
+    /// [`BlockLength::OneK.packet_buffer_len()`](BlockLength::packet_buffer_len),
+    /// 1024-byte blocks are rejected with a NAK, so the sender has to use
+    /// 128-byte blocks, or fall back to them with `downgrade_blocks`.
+    /// Otherwise this is the same as [`recv`](Self::recv).
+    ///
+    /// # Panics
+    /// If `buffer` is too small for 128-byte blocks.
+    pub fn recv_with_buffer<D: Read + Write, W: Write>(
+        &mut self,
+        dev: &mut D,
+        outstream: &mut W,
+        checksum: Checksum,
+        buffer: &mut [u8],
+    ) -> Result<usize> {
+        self.transfer()
+            .buffer(buffer)
+            .recv(dev, outstream, checksum)
     }
 
-    fn send_stream<D: Read + Write, R: Read>(
+    /// Receive an XMODEM transmission, handing each block to `sink` as it
+    /// arrives. The sink decides whether the block is acknowledged, repeated
+    /// by the sender or the transfer canceled; see [`BlockAction`].
+    ///
+    /// Returns the number of bytes accepted by the sink, including the
+    /// padding of the last block, which is never trimmed. Timeouts are
+    /// handled as in [`recv`](Self::recv). See [`transfer`](Self::transfer)
+    /// to add a buffer, a clock or an observer.
+    pub fn recv_with_sink<D: Read + Write, S: BlockSink>(
         &mut self,
         dev: &mut D,
-        stream: &mut R,
+        sink: &mut S,
+        checksum: Checksum,
     ) -> Result<usize> {
-        let mut seqno: u32 = 0;
-        let mut bytes: usize = 0;
-        loop {
-            let mut packet = XmodemPacket::new(self.block_length, self.pad_byte);
-
-            let n = stream.read(packet.as_mut())?;
-            if n == 0 {
-                debug!("Reached EOF");
-                return Ok(bytes);
-            }
+        self.transfer().recv_to_sink(dev, sink, checksum)
+    }
+}
 
-            seqno += 1;
-            packet.seqno = u8::try_from(seqno & 0xFF).unwrap();
-            packet.send(dev, self.checksum_mode)?;
-            bytes += n;
+impl Default for Xmodem {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
-            match get_byte_timeout(dev)? {
-                Some(ACK) => {
-                    debug!("Received ACK for block {}", seqno);
-                    continue;
-                }
-                // TODO handle CAN bytes
-                Some(b) => {
-                    warn!("Expected ACK, got {}", b);
-                }
-                None => warn!("Timeout waiting for ACK for block {}", seqno),
+/// Drives `machine` until the receiver acknowledges the end of the
+/// transmission, sending the contents of `stream`. Returns the number of
+/// bytes read from `stream`.
+fn run_sender<D, R, P, B>(
+    dev: &mut D,
+    mut clock: Option<&mut dyn Clock>,
+    machine: &mut SenderMachine<P>,
+    buffer: &mut SendBuffer<B>,
+    stream: &mut R,
+    observer: &mut dyn TransferObserver,
+) -> Result<usize>
+where
+    D: Read + Write,
+    R: Read,
+    P: AsRef<[u8]> + AsMut<[u8]>,
+    B: AsRef<[u8]> + AsMut<[u8]>,
+{
+    let mut bytes = 0;
+    let mut last = 0;
+    loop {
+        let event = match machine.poll_event() {
+            Some(event) => Some(event),
+            None => match step(dev, clock.as_deref_mut(), machine, observer) {
+                Err(Error::Aborted) => return Err(abort(dev, machine)),
+                event => event?,
+            },
+        };
+        if observer.is_canceled() {
+            return Err(abort(dev, machine));
+        }
+
+        match event {
+            Some(SenderEvent::Started(checksum)) => {
+                observer.handshake(checksum, machine.block_length())
             }
+            Some(SenderEvent::BlockDone(blockno)) => {
+                observer.block(blockno, (bytes - last) as u64, last)
+            }
+            Some(SenderEvent::Retry(cause)) => {
+                observer.retry(cause);
+                continue;
+            }
+            Some(SenderEvent::Downgraded(cause)) => {
+                observer.retry(cause);
+                buffer.unload(last);
+                bytes -= last;
+            }
+            Some(SenderEvent::Finished) => return Ok(bytes),
+            None => continue,
+        }
+
+        if buffer.is_empty()
+            && let Err(e) = buffer.fill(stream)
+        {
+            machine.cancel();
+            flush_output(dev, machine).unwrap_or_default();
+            return Err(Error::Io(e));
+        }
+        if buffer.is_empty() {
+            debug!("Reached EOF");
+            machine.finish();
+        } else {
+            last = buffer.load(machine);
+            machine.send_block();
+            bytes += last;
+        }
+        flush_output(dev, machine)?;
+    }
+}
+
+/// Data read from the input stream but not sent yet. The sender reads up
+/// to a whole 1K block ahead, so that it knows how much is left when
+/// picking the length of the next block.
+struct SendBuffer<B = [u8; 1024]> {
+    buf: B,
+    start: usize,
+    end: usize,
+    /// Where the data of the last block loaded started.
+    loaded: usize,
+}
+
+impl SendBuffer {
+    fn new() -> Self {
+        Self::with_buffer([0; 1024])
+    }
+}
+
+impl<B: AsRef<[u8]> + AsMut<[u8]>> SendBuffer<B> {
+    /// Reads ahead into `buf`, which should hold the longest block.
+    fn with_buffer(buf: B) -> Self {
+        SendBuffer {
+            buf,
+            start: 0,
+            end: 0,
+            loaded: 0,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
+
+    fn is_full(&self) -> bool {
+        self.end == self.buf.as_ref().len()
+    }
+
+    /// The free space to read more data into.
+    fn space(&mut self) -> &mut [u8] {
+        &mut self.buf.as_mut()[self.end..]
+    }
 
-            self.errors += 1;
+    /// Marks `n` more bytes of [`space`](Self::space) as read.
+    fn filled(&mut self, n: usize) {
+        self.end += n;
+    }
 
-            if self.errors >= self.max_errors {
-                error!(
-                    "Exhausted max retries ({}) while sending block {} in XMODEM transfer",
-                    self.max_errors, seqno
-                );
-                return Err(Error::ExhaustedRetries);
+    /// Reads from `stream` until the buffer is full or the stream ends, so
+    /// that streams returning short reads don't end up as padded blocks in
+    /// the middle of the message.
+    fn fill<R: Read>(&mut self, stream: &mut R) -> io::Result<()> {
+        while !self.is_full() {
+            match stream.read(self.space()) {
+                Ok(0) => break,
+                Ok(n) => self.filled(n),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
             }
         }
+        Ok(())
     }
 
-    fn finish_send<D: Read + Write>(&mut self, dev: &mut D) -> Result<()> {
-        loop {
-            dev.write_all(&[EOT])?;
+    /// Moves the data for the next block into `machine`, returning the
+    /// number of bytes.
+    fn load<P: AsRef<[u8]> + AsMut<[u8]>>(&mut self, machine: &mut SenderMachine<P>) -> usize {
+        let data = &self.buf.as_ref()[self.start..self.end];
+        let block = machine.next_block_for(data.len());
+        let n = data.len().min(block.len());
+        block[..n].copy_from_slice(&data[..n]);
+        self.loaded = self.start;
+        self.start += n;
+        if self.is_empty() {
+            self.start = 0;
+            self.end = 0;
+        }
+        n
+    }
 
-            match get_byte_timeout(dev)? {
-                Some(ACK) => {
-                    info!("XMODEM transmission successful");
-                    return Ok(());
-                }
-                Some(b) => {
-                    warn!("Expected ACK, got {}", b);
+    /// Puts back the `n` bytes taken by the last [`load`](Self::load), when
+    /// the machine asks for them again after [`SenderEvent::Downgraded`].
+    /// The buffer is only refilled once empty, so they are still there.
+    fn unload(&mut self, n: usize) {
+        self.start = self.loaded;
+        self.end = self.end.max(self.loaded + n);
+    }
+}
+
+/// Drives `machine` until the sender ends the transmission, passing each
+/// block with its number and offset to `write`. That returns the number of
+/// bytes it stored, or `None` to have the block repeated. The transfer is
+/// canceled if `write` fails.
+fn run_receiver<D, P, F>(
+    dev: &mut D,
+    mut clock: Option<&mut dyn Clock>,
+    machine: &mut ReceiverMachine<P>,
+    observer: &mut dyn TransferObserver,
+    mut write: F,
+) -> Result<usize>
+where
+    D: Read + Write,
+    P: AsRef<[u8]> + AsMut<[u8]>,
+    F: FnMut(u32, u64, &[u8]) -> Result<Option<usize>>,
+{
+    let mut bytes: usize = 0;
+    let mut offset: u64 = 0;
+    let mut started = false;
+    loop {
+        flush_output(dev, machine)?;
+
+        let event = match step(dev, clock.as_deref_mut(), machine, observer) {
+            Err(Error::Aborted) => return Err(abort(dev, machine)),
+            event => event?,
+        };
+        if observer.is_canceled() {
+            return Err(abort(dev, machine));
+        }
+
+        match event {
+            Some(ReceiverEvent::Block(blockno)) => {
+                if !started {
+                    started = true;
+                    observer.handshake(machine.checksum(), machine.block_length());
                 }
-                None => {
-                    warn!("Timeout waiting for ACK for EOT")
+                let len = machine.block().len();
+                match write(blockno, offset, machine.block()) {
+                    Ok(Some(n)) => bytes += n,
+                    Ok(None) => {
+                        let res = machine.reject_block();
+                        flush_output(dev, machine)?;
+                        res?;
+                        observer.retry(RetryCause::Rejected);
+                        continue;
+                    }
+                    Err(e) => {
+                        machine.cancel();
+                        flush_output(dev, machine).unwrap_or_default();
+                        return Err(e);
+                    }
                 }
+                machine.accept_block();
+                observer.block(blockno, offset, len);
+                offset += len as u64;
             }
-
-            self.errors += 1;
-
-            if self.errors >= self.max_errors {
-                error!(
-                    "Exhausted max retries ({}) while waiting for ACK for EOT",
-                    self.max_errors
-                );
-                return Err(Error::ExhaustedRetries);
+            Some(ReceiverEvent::Retry(cause)) => observer.retry(cause),
+            Some(ReceiverEvent::Finished) => {
+                flush_output(dev, machine)?;
+                info!("XMODEM reception successful");
+                return Ok(bytes);
             }
+            None => {}
         }
     }
 }
 
-impl Default for Xmodem {
-    fn default() -> Self {
-        Self::new()
+/// The time since `start`, or zero without a clock.
+fn elapsed(clock: Option<&mut dyn Clock>, start: Option<Duration>) -> Duration {
+    match (clock, start) {
+        (Some(clock), Some(start)) => clock.now().saturating_sub(start),
+        _ => Duration::ZERO,
+    }
+}
+
+/// Holds back the trailing padding of each block until the next one
+/// arrives, so that only the padding of the final block is dropped.
+struct PadTrimmer {
+    pad_byte: u8,
+    held: usize,
+}
+
+impl PadTrimmer {
+    fn new(pad_byte: u8) -> Self {
+        PadTrimmer { pad_byte, held: 0 }
     }
+
+    /// Splits off the padding at the end of `data`. Returns the number of
+    /// padding bytes held back from the previous block, which have to be
+    /// written before the rest of `data`.
+    fn block<'a>(&mut self, data: &'a [u8]) -> (usize, &'a [u8]) {
+        let len = data
+            .iter()
+            .rposition(|&b| b != self.pad_byte)
+            .map_or(0, |i| i + 1);
+        let held = core::mem::replace(&mut self.held, data.len() - len);
+        (held, &data[..len])
+    }
+}
+
+/// Writes `count` copies of `pad_byte`.
+fn write_padding<W: Write>(out: &mut W, pad_byte: u8, mut count: usize) -> io::Result<()> {
+    let pad = [pad_byte; 128];
+    while count > 0 {
+        let n = count.min(pad.len());
+        out.write_all(&pad[..n])?;
+        count -= n;
+    }
+    Ok(())
+}
+
+/// Writes as much of `data` as fits in the `remaining` expected bytes,
+/// returning the number of bytes written.
+fn write_truncated<W: Write>(out: &mut W, remaining: &mut u64, data: &[u8]) -> io::Result<usize> {
+    let n = usize::try_from(*remaining).map_or(data.len(), |r| r.min(data.len()));
+    out.write_all(&data[..n])?;
+    *remaining -= n as u64;
+    Ok(n)
+}
+
+/// Waits for the next byte from `dev`, feeds it (or the timeout) to
+/// `machine` and writes out the machine's response. Errors reported by the
+/// machine take precedence over errors writing its response. Fails with
+/// `Error::Aborted`, leaving `machine` alone, if `observer` cancels the
+/// transfer while waiting.
+fn step<D: Read + Write, M: Machine>(
+    dev: &mut D,
+    clock: Option<&mut (dyn Clock + '_)>,
+    machine: &mut M,
+    observer: &mut dyn TransferObserver,
+) -> Result<Option<M::Event>> {
+    let event = match get_byte_timeout(dev, clock, machine.timeout(), observer)? {
+        Some(b) => machine.handle_byte(b),
+        None => machine.handle_timeout(),
+    };
+    let flushed = flush_output(dev, machine);
+    let event = event?;
+    flushed?;
+    Ok(event)
 }
 
-fn calc_checksum(data: &[u8]) -> u8 {
-    data.iter().fold(0, |x, &y| x.wrapping_add(y))
+/// Cancels the transfer on behalf of the observer.
+fn abort<D: Write, M: Machine>(dev: &mut D, machine: &mut M) -> Error {
+    machine.cancel();
+    flush_output(dev, machine).unwrap_or_default();
+    Error::Aborted
 }
 
-fn calc_crc(data: &[u8]) -> u16 {
-    crc16::State::<crc16::XMODEM>::calculate(data)
+/// Writes out everything `machine` has queued for transmission.
+fn flush_output<D: Write, M: Machine>(dev: &mut D, machine: &mut M) -> io::Result<()> {
+    while let Some(out) = machine.poll_output() {
+        dev.write_all(out)?;
+    }
+    Ok(())
 }
 
 fn get_byte<R: Read>(reader: &mut R) -> io::Result<u8> {
@@ -539,16 +1174,61 @@ fn get_byte<R: Read>(reader: &mut R) -> io::Result<u8> {
     Ok(buff[0])
 }
 
-/// Turns timeout errors into `Ok(None)`
-fn get_byte_timeout<R: Read>(reader: &mut R) -> io::Result<Option<u8>> {
-    match get_byte(reader) {
-        Ok(c) => Ok(Some(c)),
-        Err(err) => {
-            if err.kind() == io::ErrorKind::TimedOut {
-                Ok(None)
-            } else {
-                Err(err)
+/// Reads a byte, returning `Ok(None)` on timeout.
+///
+/// With a clock, `TimedOut`, `WouldBlock` and `Interrupted` errors are
+/// retried until `timeout` has elapsed, unless `observer` cancels the
+/// transfer in the meantime. Without one, the device's own timeout is used.
+fn get_byte_timeout<R: Read>(
+    reader: &mut R,
+    clock: Option<&mut (dyn Clock + '_)>,
+    timeout: Duration,
+    observer: &mut dyn TransferObserver,
+) -> Result<Option<u8>> {
+    let Some(clock) = clock else {
+        return match get_byte(reader) {
+            Ok(c) => Ok(Some(c)),
+            Err(err) if err.kind() == io::ErrorKind::TimedOut => Ok(None),
+            Err(err) => Err(Error::Io(err)),
+        };
+    };
+
+    let deadline = clock.now().saturating_add(timeout);
+    #[cfg(feature = "std")]
+    let mut polls = 0u32;
+    loop {
+        match get_byte(reader) {
+            Ok(c) => return Ok(Some(c)),
+            Err(err) if is_transient(&err) => {
+                if observer.is_canceled() {
+                    return Err(Error::Aborted);
+                }
+                if clock.now() >= deadline {
+                    return Ok(None);
+                }
+                // Don't burn a core on a device that never blocks: the next
+                // byte is usually close, but back off if it isn't.
+                #[cfg(feature = "std")]
+                {
+                    polls += 1;
+                    if polls < SPIN_POLLS {
+                        std::thread::yield_now();
+                    } else {
+                        std::thread::sleep(POLL_INTERVAL);
+                    }
+                }
+                #[cfg(not(feature = "std"))]
+                core::hint::spin_loop();
             }
+            Err(err) => return Err(Error::Io(err)),
         }
     }
 }
+
+/// Whether a read error just means that no data is available yet.
+fn is_transient(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted
+    )
+}
@@ -0,0 +1,929 @@
+//! Sans-IO state machines implementing the XMODEM protocol.
+//!
+//! [`SenderMachine`] and [`ReceiverMachine`] contain all of the protocol
+//! logic but never perform any IO themselves. The caller feeds them the
+//! bytes received from the other end of the channel (and tells them when
+//! waiting for a byte timed out), then writes whatever they queue with
+//! `poll_output` to the channel. This makes them usable from interrupt
+//! handlers, RTOS task loops or async executors, where blocking `Read` and
+//! `Write` implementations are not an option. [`Xmodem::send`] and
+//! [`Xmodem::recv`] are thin blocking drivers built on top of them.
+//!
+//! [`Xmodem::send`]: crate::Xmodem::send
+//! [`Xmodem::recv`]: crate::Xmodem::recv
+
+use core::time::Duration;
+
+use ::log::{debug, error, info, warn};
+
+use crate::{
+    ACK, BS, BlockCheck, BlockLength, CAN, CRC, Checksum, EOT, Error, HEADER_LEN, MAX_CANCEL_LEN,
+    MAX_PACKET_LEN, NAK, Phase, Result, SOH, STREAM, STX, Xmodem, XmodemPacket,
+};
+
+/// The interface shared by the sender and receiver, used by the drivers.
+pub(crate) trait Machine {
+    type Event;
+
+    fn handle_byte(&mut self, byte: u8) -> Result<Option<Self::Event>>;
+    fn handle_timeout(&mut self) -> Result<Option<Self::Event>>;
+    fn poll_output(&mut self) -> Option<&[u8]>;
+    fn timeout(&self) -> Duration;
+    fn cancel(&mut self);
+}
+
+/// A short sequence of control bytes waiting to be transmitted.
+#[derive(Default)]
+struct ControlBytes {
+    buf: [u8; 2 * MAX_CANCEL_LEN],
+    len: usize,
+}
+
+impl ControlBytes {
+    fn set(&mut self, bytes: &[u8]) {
+        self.buf[..bytes.len()].copy_from_slice(bytes);
+        self.len = bytes.len();
+    }
+
+    /// Queues the sequence telling the other end to abort, as configured
+    /// in `config`.
+    fn cancel(&mut self, config: &Xmodem) {
+        let count = (config.cancel_count as usize).min(MAX_CANCEL_LEN);
+        self.buf[..count].fill(CAN);
+        self.len = count;
+        if config.cancel_backspaces {
+            self.buf[count..2 * count].fill(BS);
+            self.len = 2 * count;
+        }
+    }
+
+    fn take(&mut self) -> Option<&[u8]> {
+        let len = core::mem::take(&mut self.len);
+        if len == 0 {
+            None
+        } else {
+            Some(&self.buf[..len])
+        }
+    }
+}
+
+/// Why a [`SenderMachine`] or [`ReceiverMachine`] had to repeat itself.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum RetryCause {
+    /// Nothing was received from the other end in time.
+    Timeout,
+
+    /// The receiver asked for the block again.
+    Nak,
+
+    /// A block arrived with a bad checksum or a corrupted header.
+    Checksum,
+
+    /// The sender repeated a block that was already received.
+    Sequence,
+
+    /// An unexpected byte was received instead of a response.
+    Garbage,
+
+    /// The receiving application rejected a block, with
+    /// [`ReceiverMachine::reject_block`].
+    Rejected,
+}
+
+/// Progress reported by a [`SenderMachine`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SenderEvent {
+    /// The receiver started the transfer and asked for the given checksum
+    /// mode. The machine is ready for the first block.
+    Started(Checksum),
+
+    /// The receiver acknowledged the block with the given number (counting
+    /// from 1, without wrapping at 256). The machine is ready for the next
+    /// block. When streaming, this is reported by
+    /// [`SenderMachine::poll_event`] as soon as the block was sent.
+    BlockDone(u32),
+
+    /// The receiver didn't respond as expected, so the last block, EOT or
+    /// (during the handshake) nothing is sent again.
+    Retry(RetryCause),
+
+    /// Like [`Retry`](Self::Retry), but a 1024-byte block failed once too
+    /// often with `downgrade_blocks`. Rather than sending it again, the
+    /// machine switched to 128-byte blocks and is ready for the same data
+    /// again, to send it as 128-byte blocks under the same block number
+    /// and the following ones.
+    Downgraded(RetryCause),
+
+    /// The receiver acknowledged the end of the transmission.
+    Finished,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum SenderState {
+    Handshake,
+    Ready,
+    WaitAck,
+    WaitEotAck,
+    Done,
+    Aborted,
+}
+
+/// The sending side of an XMODEM transfer.
+///
+/// After the machine reports [`SenderEvent::Started`] or
+/// [`SenderEvent::BlockDone`], fill the buffer returned by
+/// [`next_block`](Self::next_block) and call
+/// [`send_block`](Self::send_block), or call [`finish`](Self::finish) if
+/// there is no more data.
+///
+/// The packet being sent is stored in a buffer of type `B`, by default an
+/// array large enough for 1024-byte blocks. Use
+/// [`with_buffer`](Self::with_buffer) to provide a smaller one, or one in
+/// static memory.
+pub struct SenderMachine<B = [u8; MAX_PACKET_LEN]> {
+    config: Xmodem,
+    state: SenderState,
+    checksum: Checksum,
+    errors: u32,
+    block_errors: u32,
+    got_can: bool,
+    blockno: u32,
+    block_length: BlockLength,
+    streaming: bool,
+    block_sent: bool,
+    packet: XmodemPacket<B>,
+    packet_pending: bool,
+    control: ControlBytes,
+}
+
+impl SenderMachine {
+    /// Creates a sender using the parameters in `config`. The machine
+    /// waits for the receiver to start the transfer.
+    pub fn new(config: &Xmodem) -> Self {
+        Self::with_buffer(config, [0; MAX_PACKET_LEN])
+    }
+}
+
+impl<B: AsRef<[u8]> + AsMut<[u8]>> SenderMachine<B> {
+    /// Like [`new`](SenderMachine::new), storing the packet in `buffer`.
+    ///
+    /// `buffer` must hold at least
+    /// [`BlockLength::Standard.packet_buffer_len()`](BlockLength::packet_buffer_len)
+    /// bytes. If it is too small for 1024-byte blocks, 128-byte blocks are
+    /// sent regardless of `block_length`.
+    ///
+    /// # Panics
+    /// If `buffer` is too small for 128-byte blocks.
+    pub fn with_buffer(config: &Xmodem, buffer: B) -> Self {
+        let packet = XmodemPacket::with_buffer(buffer, BlockLength::Standard, config.pad_byte);
+        let block_length = match packet.max_block_length() {
+            BlockLength::OneK => config.block_length,
+            BlockLength::Standard => BlockLength::Standard,
+        };
+        SenderMachine {
+            config: *config,
+            state: SenderState::Handshake,
+            checksum: Checksum::Standard,
+            errors: 0,
+            block_errors: 0,
+            got_can: false,
+            blockno: 0,
+            block_length,
+            streaming: false,
+            block_sent: false,
+            packet,
+            packet_pending: false,
+            control: ControlBytes::default(),
+        }
+    }
+
+    /// The checksum mode requested by the receiver.
+    pub fn checksum(&self) -> Checksum {
+        self.checksum
+    }
+
+    /// Whether the receiver asked for XMODEM-G streaming, where blocks are
+    /// sent back to back without waiting for ACKs.
+    pub fn is_streaming(&self) -> bool {
+        self.streaming
+    }
+
+    /// The length of the blocks being sent. This starts out as configured,
+    /// but with `downgrade_blocks` it changes to [`BlockLength::Standard`]
+    /// after repeated errors.
+    pub fn block_length(&self) -> BlockLength {
+        self.block_length
+    }
+
+    /// Sets the number of the next block to send. YMODEM starts with a
+    /// header in block 0.
+    pub(crate) fn set_next_blockno(&mut self, blockno: u32) {
+        self.blockno = blockno.wrapping_sub(1);
+    }
+
+    /// Returns `true` once the receiver has acknowledged the end of the
+    /// transmission.
+    pub fn is_finished(&self) -> bool {
+        self.state == SenderState::Done
+    }
+
+    /// How long to wait for the next byte from the receiver before calling
+    /// [`handle_timeout`](Self::handle_timeout).
+    pub fn timeout(&self) -> Duration {
+        match self.state {
+            SenderState::Handshake => self.config.handshake_timeout,
+            SenderState::WaitEotAck => self.config.eot_timeout,
+            _ => self.config.ack_timeout,
+        }
+    }
+
+    /// Handles a byte received from the receiver.
+    pub fn handle_byte(&mut self, byte: u8) -> Result<Option<SenderEvent>> {
+        match self.state {
+            // While streaming, the receiver may cancel at any time
+            SenderState::Ready if self.streaming => {}
+            SenderState::Ready | SenderState::Done | SenderState::Aborted => return Ok(None),
+            _ => {}
+        }
+
+        // The receiver aborts the transfer with two consecutive CAN bytes.
+        // A single one may just be line noise, so wait for the next byte
+        // before deciding what to do.
+        if byte == CAN {
+            if self.got_can {
+                error!("Transmission canceled: received two cancel (CAN) bytes");
+                let phase = self.phase();
+                self.state = SenderState::Aborted;
+                return Err(Error::Canceled(phase));
+            }
+            warn!("Cancel (CAN) byte received");
+            self.got_can = true;
+            return Ok(None);
+        }
+        self.got_can = false;
+
+        match self.state {
+            SenderState::Handshake => match byte {
+                NAK => {
+                    debug!("Standard checksum requested");
+                    self.start(Checksum::Standard)
+                }
+                CRC => {
+                    debug!("16-bit CRC requested");
+                    self.start(Checksum::CRC16)
+                }
+                STREAM => {
+                    debug!("Streaming with 16-bit CRC requested");
+                    self.streaming = true;
+                    self.start(Checksum::CRC16)
+                }
+                c => {
+                    warn!("Unknown byte received at start of XMODEM transfer: {}", c);
+                    self.handshake_error(RetryCause::Garbage)
+                }
+            },
+            SenderState::WaitAck => match byte {
+                ACK => {
+                    debug!("Received ACK for block {}", self.blockno);
+                    self.block_errors = 0;
+                    self.state = SenderState::Ready;
+                    Ok(Some(SenderEvent::BlockDone(self.blockno)))
+                }
+                NAK => {
+                    warn!("Received NAK for block {}", self.blockno);
+                    self.block_error(RetryCause::Nak)
+                }
+                b => {
+                    warn!("Expected ACK, got {}", b);
+                    self.block_error(RetryCause::Garbage)
+                }
+            },
+            SenderState::WaitEotAck => match byte {
+                ACK => {
+                    info!("XMODEM transmission successful");
+                    self.state = SenderState::Done;
+                    Ok(Some(SenderEvent::Finished))
+                }
+                NAK => {
+                    warn!("Received NAK for EOT");
+                    self.eot_error(RetryCause::Nak)
+                }
+                b => {
+                    warn!("Expected ACK, got {}", b);
+                    self.eot_error(RetryCause::Garbage)
+                }
+            },
+            SenderState::Ready | SenderState::Done | SenderState::Aborted => Ok(None),
+        }
+    }
+
+    /// Handles a timeout while waiting for a byte from the receiver.
+    pub fn handle_timeout(&mut self) -> Result<Option<SenderEvent>> {
+        self.got_can = false;
+        match self.state {
+            SenderState::Handshake => {
+                warn!("Timed out waiting for start of XMODEM transfer.");
+                self.handshake_error(RetryCause::Timeout)
+            }
+            SenderState::WaitAck => {
+                warn!("Timeout waiting for ACK for block {}", self.blockno);
+                self.block_error(RetryCause::Timeout)
+            }
+            SenderState::WaitEotAck => {
+                warn!("Timeout waiting for ACK for EOT");
+                self.eot_error(RetryCause::Timeout)
+            }
+            SenderState::Ready | SenderState::Done | SenderState::Aborted => Ok(None),
+        }
+    }
+
+    /// Returns the data buffer of the next block, filled with `pad_byte`.
+    ///
+    /// Only valid once the machine is ready for the next block.
+    pub fn next_block(&mut self) -> &mut [u8] {
+        self.next_block_for(usize::MAX)
+    }
+
+    /// Like [`next_block`](Self::next_block), for when only `remaining`
+    /// bytes of data are left. With `downgrade_blocks`, a 128-byte block is
+    /// returned instead of a mostly empty 1024-byte one.
+    pub fn next_block_for(&mut self, remaining: usize) -> &mut [u8] {
+        debug_assert_eq!(self.state, SenderState::Ready);
+        let block_length = if self.config.downgrade_blocks
+            && remaining <= BlockLength::OneK as usize - BlockLength::Standard as usize
+        {
+            BlockLength::Standard
+        } else {
+            self.block_length
+        };
+        self.packet.reset(block_length, self.config.pad_byte);
+        self.packet.as_mut()
+    }
+
+    /// Queues the block prepared with [`next_block`](Self::next_block) for
+    /// transmission.
+    pub fn send_block(&mut self) {
+        debug_assert_eq!(self.state, SenderState::Ready);
+        self.blockno = self.blockno.wrapping_add(1);
+        self.packet.seqno = (self.blockno & 0xFF) as u8;
+        let checksum = self.checksum;
+        self.packet.encode(block_check(&self.config, &checksum));
+        self.packet_pending = true;
+        if self.streaming {
+            self.block_sent = true;
+        } else {
+            self.state = SenderState::WaitAck;
+        }
+    }
+
+    /// Ends the transmission after the last block was acknowledged.
+    pub fn finish(&mut self) {
+        debug_assert_eq!(self.state, SenderState::Ready);
+        debug!("Sending EOT");
+        self.control.set(&[EOT]);
+        self.state = SenderState::WaitEotAck;
+    }
+
+    /// Aborts the transfer, telling the receiver to stop.
+    pub fn cancel(&mut self) {
+        self.packet_pending = false;
+        self.block_sent = false;
+        self.control.cancel(&self.config);
+        self.state = SenderState::Aborted;
+    }
+
+    /// Returns the next bytes to transmit to the receiver, if any. The
+    /// returned bytes are considered sent.
+    pub fn poll_output(&mut self) -> Option<&[u8]> {
+        if self.packet_pending {
+            self.packet_pending = false;
+            debug!("Sending block {}", self.packet.seqno);
+            return Some(self.packet.frame(block_check(&self.config, &self.checksum)));
+        }
+        self.control.take()
+    }
+
+    /// Returns progress that doesn't depend on the receiver. While
+    /// streaming, this reports [`SenderEvent::BlockDone`] once a block was
+    /// taken by [`poll_output`](Self::poll_output), without waiting for an
+    /// ACK. Call this before waiting for the next byte.
+    pub fn poll_event(&mut self) -> Option<SenderEvent> {
+        if self.block_sent && !self.packet_pending {
+            self.block_sent = false;
+            return Some(SenderEvent::BlockDone(self.blockno));
+        }
+        None
+    }
+
+    fn phase(&self) -> Phase {
+        match self.state {
+            SenderState::Handshake => Phase::Handshake,
+            SenderState::WaitEotAck | SenderState::Done => Phase::Eot,
+            SenderState::Ready | SenderState::WaitAck | SenderState::Aborted => {
+                Phase::Block(self.blockno)
+            }
+        }
+    }
+
+    fn start(&mut self, checksum: Checksum) -> Result<Option<SenderEvent>> {
+        self.checksum = checksum;
+        self.state = SenderState::Ready;
+        Ok(Some(SenderEvent::Started(checksum)))
+    }
+
+    fn handshake_error(&mut self, cause: RetryCause) -> Result<Option<SenderEvent>> {
+        self.errors += 1;
+
+        if self.errors >= self.config.max_errors {
+            error!(
+                "Exhausted max retries ({}) at start of XMODEM transfer.",
+                self.config.max_errors
+            );
+            self.cancel();
+            return Err(Error::ExhaustedRetries(Phase::Handshake));
+        }
+
+        Ok(Some(SenderEvent::Retry(cause)))
+    }
+
+    fn block_error(&mut self, cause: RetryCause) -> Result<Option<SenderEvent>> {
+        self.errors += 1;
+        self.block_errors += 1;
+
+        if self.errors >= self.config.max_errors {
+            error!(
+                "Exhausted max retries ({}) while sending block {} in XMODEM transfer",
+                self.config.max_errors, self.blockno
+            );
+            self.cancel();
+            return Err(Error::ExhaustedRetries(Phase::Block(self.blockno)));
+        }
+
+        if self.config.downgrade_blocks
+            && self.block_length == BlockLength::OneK
+            && self.block_errors >= self.config.downgrade_errors
+        {
+            info!("Too many errors on 1K blocks, falling back to 128-byte blocks");
+            self.block_length = BlockLength::Standard;
+            if self.packet.block_length == BlockLength::OneK {
+                // Take the block back, to send its data in short blocks
+                self.blockno = self.blockno.wrapping_sub(1);
+                self.block_errors = 0;
+                self.state = SenderState::Ready;
+                return Ok(Some(SenderEvent::Downgraded(cause)));
+            }
+        }
+
+        debug!("Retransmitting block {}", self.blockno);
+        self.packet_pending = true;
+        Ok(Some(SenderEvent::Retry(cause)))
+    }
+
+    fn eot_error(&mut self, cause: RetryCause) -> Result<Option<SenderEvent>> {
+        self.errors += 1;
+
+        if self.errors >= self.config.max_errors {
+            error!(
+                "Exhausted max retries ({}) while waiting for ACK for EOT",
+                self.config.max_errors
+            );
+            self.cancel();
+            return Err(Error::ExhaustedRetries(Phase::Eot));
+        }
+
+        self.control.set(&[EOT]);
+        Ok(Some(SenderEvent::Retry(cause)))
+    }
+}
+
+impl<B: AsRef<[u8]> + AsMut<[u8]>> Machine for SenderMachine<B> {
+    type Event = SenderEvent;
+
+    fn handle_byte(&mut self, byte: u8) -> Result<Option<SenderEvent>> {
+        SenderMachine::handle_byte(self, byte)
+    }
+
+    fn handle_timeout(&mut self) -> Result<Option<SenderEvent>> {
+        SenderMachine::handle_timeout(self)
+    }
+
+    fn poll_output(&mut self) -> Option<&[u8]> {
+        SenderMachine::poll_output(self)
+    }
+
+    fn timeout(&self) -> Duration {
+        SenderMachine::timeout(self)
+    }
+
+    fn cancel(&mut self) {
+        SenderMachine::cancel(self)
+    }
+}
+
+/// Progress reported by a [`ReceiverMachine`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ReceiverEvent {
+    /// A valid block with the given number (counting from 1, without
+    /// wrapping at 256) was received. Its data is available from
+    /// [`ReceiverMachine::block`] until it is accepted.
+    Block(u32),
+
+    /// A block has to be sent again, or was sent again by the sender. The
+    /// machine already queued the response asking for it, if any.
+    Retry(RetryCause),
+
+    /// The sender ended the transmission, and the end was acknowledged.
+    Finished,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum ReceiverState {
+    Idle,
+    Packet,
+    /// Discarding a 1K packet that doesn't fit in the buffer.
+    Skip,
+    Block,
+    Done,
+    Aborted,
+}
+
+/// The receiving side of an XMODEM transfer.
+///
+/// When the machine reports [`ReceiverEvent::Block`], store the data
+/// returned by [`block`](Self::block) and call
+/// [`accept_block`](Self::accept_block) to acknowledge it, or
+/// [`cancel`](Self::cancel) to abort the transfer.
+///
+/// Like the [`SenderMachine`], the packet being received is stored in a
+/// buffer of type `B`; see [`with_buffer`](Self::with_buffer).
+pub struct ReceiverMachine<B = [u8; MAX_PACKET_LEN]> {
+    config: Xmodem,
+    state: ReceiverState,
+    checksum: Checksum,
+    auto: bool,
+    streaming: bool,
+    handshakes: u32,
+    errors: u32,
+    got_can: bool,
+    started: bool,
+    blockno: u32,
+    packet: XmodemPacket<B>,
+    max_block_length: BlockLength,
+    received: usize,
+    control: ControlBytes,
+}
+
+impl ReceiverMachine {
+    /// Creates a receiver using the parameters in `config` and the given
+    /// checksum mode. The byte requesting the start of the transfer is
+    /// queued immediately.
+    pub fn new(config: &Xmodem, checksum: Checksum) -> Self {
+        Self::with_buffer(config, checksum, [0; MAX_PACKET_LEN])
+    }
+}
+
+impl<B: AsRef<[u8]> + AsMut<[u8]>> ReceiverMachine<B> {
+    /// Like [`new`](ReceiverMachine::new), storing the packet in `buffer`.
+    ///
+    /// `buffer` must hold at least
+    /// [`BlockLength::Standard.packet_buffer_len()`](BlockLength::packet_buffer_len)
+    /// bytes. If it is too small for 1024-byte blocks, they are skipped and
+    /// rejected with a NAK, until a sender using `downgrade_blocks` falls
+    /// back to 128-byte blocks.
+    ///
+    /// # Panics
+    /// If `buffer` is too small for 128-byte blocks.
+    pub fn with_buffer(config: &Xmodem, checksum: Checksum, buffer: B) -> Self {
+        let packet = XmodemPacket::with_buffer(buffer, BlockLength::Standard, 0);
+        let mut machine = ReceiverMachine {
+            config: *config,
+            state: ReceiverState::Idle,
+            checksum: match checksum {
+                _ if config.streaming => Checksum::CRC16,
+                Checksum::Auto => Checksum::CRC16,
+                c => c,
+            },
+            auto: checksum == Checksum::Auto && !config.streaming,
+            streaming: config.streaming,
+            handshakes: 0,
+            errors: 0,
+            got_can: false,
+            started: false,
+            blockno: 1,
+            max_block_length: packet.max_block_length(),
+            packet,
+            received: 0,
+            control: ControlBytes::default(),
+        };
+        machine.request_start();
+        machine
+    }
+
+    /// The checksum mode in use. With [`Checksum::Auto`], this changes to
+    /// [`Checksum::Standard`] if the sender didn't respond to the CRC16
+    /// requests.
+    pub fn checksum(&self) -> Checksum {
+        self.checksum
+    }
+
+    /// The length of the last block received.
+    pub fn block_length(&self) -> BlockLength {
+        self.packet.block_length
+    }
+
+    /// Returns `true` once the sender has ended the transmission.
+    pub fn is_finished(&self) -> bool {
+        self.state == ReceiverState::Done
+    }
+
+    /// How long to wait for the next byte from the sender before calling
+    /// [`handle_timeout`](Self::handle_timeout).
+    pub fn timeout(&self) -> Duration {
+        match self.state {
+            ReceiverState::Packet | ReceiverState::Skip => self.config.byte_timeout,
+            _ if !self.started => self.config.handshake_timeout,
+            _ => self.config.ack_timeout,
+        }
+    }
+
+    /// Sets the number of the next block expected from the sender. YMODEM
+    /// starts with a header in block 0.
+    pub(crate) fn set_next_blockno(&mut self, blockno: u32) {
+        self.blockno = blockno;
+    }
+
+    /// Handles a byte received from the sender.
+    pub fn handle_byte(&mut self, byte: u8) -> Result<Option<ReceiverEvent>> {
+        match self.state {
+            ReceiverState::Idle => {
+                // The sender aborts the transfer with two consecutive CAN
+                // bytes, just like the receiver.
+                if byte == CAN {
+                    if self.got_can {
+                        error!("Transmission canceled: received two cancel (CAN) bytes");
+                        self.state = ReceiverState::Aborted;
+                        return Err(Error::Canceled(self.phase()));
+                    }
+                    warn!("Cancel (CAN) byte received");
+                    self.got_can = true;
+                    return Ok(None);
+                }
+                self.got_can = false;
+
+                let block_length = match byte {
+                    SOH => BlockLength::Standard,
+                    STX if self.max_block_length == BlockLength::OneK => BlockLength::OneK,
+                    STX => {
+                        warn!("No room for a 1K block, skipping it");
+                        self.started = true;
+                        self.received = 1;
+                        self.state = ReceiverState::Skip;
+                        return Ok(None);
+                    }
+                    EOT => {
+                        self.control.set(&[ACK]);
+                        self.state = ReceiverState::Done;
+                        return Ok(Some(ReceiverEvent::Finished));
+                    }
+                    _ => {
+                        warn!("Unrecognized symbol!");
+                        return Ok(None);
+                    }
+                };
+                self.packet.reset(block_length, 0);
+                self.started = true;
+                self.received = 1;
+                self.state = ReceiverState::Packet;
+                Ok(None)
+            }
+            ReceiverState::Packet => {
+                self.packet.frame.as_mut()[self.received] = byte;
+                self.received += 1;
+                if self.received
+                    == self
+                        .packet
+                        .frame_len(block_check(&self.config, &self.checksum))
+                {
+                    self.state = ReceiverState::Idle;
+                    self.packet_received()
+                } else {
+                    Ok(None)
+                }
+            }
+            ReceiverState::Skip => {
+                self.received += 1;
+                let check = block_check(&self.config, &self.checksum);
+                if self.received < HEADER_LEN + BlockLength::OneK as usize + check.size() {
+                    return Ok(None);
+                }
+                self.state = ReceiverState::Idle;
+                if self.streaming {
+                    // The sender won't fall back to shorter blocks
+                    error!("Can't receive 1K block {} while streaming", self.blockno);
+                    let phase = self.phase();
+                    self.cancel();
+                    return Err(Error::ExhaustedRetries(phase));
+                }
+                self.control.set(&[NAK]);
+                self.retry(RetryCause::Garbage)
+            }
+            ReceiverState::Block | ReceiverState::Done | ReceiverState::Aborted => Ok(None),
+        }
+    }
+
+    /// Handles a timeout while waiting for a byte from the sender.
+    pub fn handle_timeout(&mut self) -> Result<Option<ReceiverEvent>> {
+        match self.state {
+            ReceiverState::Idle | ReceiverState::Packet | ReceiverState::Skip => {
+                warn!("Timeout!");
+                self.got_can = false;
+                self.state = ReceiverState::Idle;
+                if self.streaming && self.started {
+                    // The sender won't repeat anything
+                    error!("Timeout while streaming block {}", self.blockno);
+                    let phase = self.phase();
+                    self.cancel();
+                    return Err(Error::ExhaustedRetries(phase));
+                }
+                self.retry(RetryCause::Timeout)?;
+                if !self.started {
+                    self.request_start();
+                }
+                Ok(Some(ReceiverEvent::Retry(RetryCause::Timeout)))
+            }
+            ReceiverState::Block | ReceiverState::Done | ReceiverState::Aborted => Ok(None),
+        }
+    }
+
+    /// The data of the block reported by the last [`ReceiverEvent::Block`].
+    pub fn block(&self) -> &[u8] {
+        self.packet.as_ref()
+    }
+
+    /// Acknowledges the block reported by the last [`ReceiverEvent::Block`].
+    pub fn accept_block(&mut self) {
+        debug_assert_eq!(self.state, ReceiverState::Block);
+        if !self.streaming {
+            self.control.set(&[ACK]);
+        }
+        self.blockno = self.blockno.wrapping_add(1);
+        self.state = ReceiverState::Idle;
+    }
+
+    /// Asks the sender to repeat the block reported by the last
+    /// [`ReceiverEvent::Block`] instead of acknowledging it. This counts as
+    /// an error, and fails once `max_errors` is reached.
+    ///
+    /// When streaming, the sender can't repeat anything, so the transfer
+    /// is canceled.
+    pub fn reject_block(&mut self) -> Result<()> {
+        debug_assert_eq!(self.state, ReceiverState::Block);
+        self.state = ReceiverState::Idle;
+        if self.streaming {
+            error!("Block {} rejected while streaming", self.blockno);
+            let phase = self.phase();
+            self.cancel();
+            return Err(Error::ExhaustedRetries(phase));
+        }
+        warn!("Block {} rejected", self.blockno);
+        self.control.set(&[NAK]);
+        self.retry(RetryCause::Rejected).map(|_| ())
+    }
+
+    /// Aborts the transfer, telling the sender to stop.
+    pub fn cancel(&mut self) {
+        self.control.cancel(&self.config);
+        self.state = ReceiverState::Aborted;
+    }
+
+    /// Returns the next bytes to transmit to the sender, if any. The
+    /// returned bytes are considered sent.
+    pub fn poll_output(&mut self) -> Option<&[u8]> {
+        self.control.take()
+    }
+
+    /// Queues the byte asking the sender to start the transfer, falling
+    /// back from CRC16 if negotiating.
+    fn request_start(&mut self) {
+        if self.auto
+            && self.checksum == Checksum::CRC16
+            && self.handshakes >= self.config.crc_attempts
+        {
+            info!("Sender didn't respond to CRC16 request, falling back to standard checksum");
+            self.checksum = Checksum::Standard;
+        }
+        self.handshakes += 1;
+        self.control.set(&[match self.checksum {
+            _ if self.streaming => STREAM,
+            Checksum::Standard => NAK,
+            Checksum::CRC16 | Checksum::Auto => CRC,
+        }]);
+        debug!("NCG sent. Receiving stream.");
+    }
+
+    fn packet_received(&mut self) -> Result<Option<ReceiverEvent>> {
+        let checksum = self.checksum;
+        match self.packet.decode(block_check(&self.config, &checksum)) {
+            Ok(()) => {
+                let seqno = u32::from(self.packet.seqno);
+                if seqno == (self.blockno & 0xFF) {
+                    self.state = ReceiverState::Block;
+                    return Ok(Some(ReceiverEvent::Block(self.blockno)));
+                }
+
+                // The sender repeats the previous block if our ACK got
+                // lost; acknowledge it again and drop the data.
+                if !self.streaming && seqno == (self.blockno.wrapping_sub(1) & 0xFF) {
+                    warn!(
+                        "Received duplicate of block {}",
+                        self.blockno.wrapping_sub(1)
+                    );
+                    self.control.set(&[ACK]);
+                    return Ok(Some(ReceiverEvent::Retry(RetryCause::Sequence)));
+                }
+
+                self.sequence_mismatch(self.packet.seqno)
+            }
+            Err(e) if self.streaming => {
+                error!("Corrupted packet while streaming block {}", self.blockno);
+                self.cancel();
+                Err(e)
+            }
+            // A bad checksum, or a header damaged by line noise: the sender
+            // just has to try again.
+            Err(_) => {
+                self.control.set(&[NAK]);
+                self.retry(RetryCause::Checksum)
+            }
+        }
+    }
+
+    /// Cancels the transfer because of an intact packet with the sequence
+    /// number `received`, which is neither the expected block nor a repeat.
+    fn sequence_mismatch(&mut self, received: u8) -> Result<Option<ReceiverEvent>> {
+        let expected = (self.blockno & 0xFF) as u8;
+        error!(
+            "Received block {} while expecting block {}",
+            received, expected
+        );
+        self.cancel();
+        Err(Error::SequenceMismatch { expected, received })
+    }
+
+    fn phase(&self) -> Phase {
+        if self.started {
+            Phase::Block(self.blockno)
+        } else {
+            Phase::Handshake
+        }
+    }
+
+    fn retry(&mut self, cause: RetryCause) -> Result<Option<ReceiverEvent>> {
+        self.errors += 1;
+
+        if self.errors >= self.config.max_errors {
+            error!(
+                "Exhausted max retries ({}) while waiting for data packet {}",
+                self.config.max_errors, self.blockno
+            );
+            let phase = self.phase();
+            self.cancel();
+            return Err(Error::ExhaustedRetries(phase));
+        }
+
+        Ok(Some(ReceiverEvent::Retry(cause)))
+    }
+}
+
+impl<B: AsRef<[u8]> + AsMut<[u8]>> Machine for ReceiverMachine<B> {
+    type Event = ReceiverEvent;
+
+    fn handle_byte(&mut self, byte: u8) -> Result<Option<ReceiverEvent>> {
+        ReceiverMachine::handle_byte(self, byte)
+    }
+
+    fn handle_timeout(&mut self) -> Result<Option<ReceiverEvent>> {
+        ReceiverMachine::handle_timeout(self)
+    }
+
+    fn poll_output(&mut self) -> Option<&[u8]> {
+        ReceiverMachine::poll_output(self)
+    }
+
+    fn timeout(&self) -> Duration {
+        ReceiverMachine::timeout(self)
+    }
+
+    fn cancel(&mut self) {
+        ReceiverMachine::cancel(self)
+    }
+}
+
+/// The check used at the end of packets: the one for the negotiated
+/// checksum mode, unless the config overrides it.
+fn block_check<'a>(config: &Xmodem, checksum: &'a Checksum) -> &'a dyn BlockCheck {
+    match config.block_check {
+        Some(check) => check,
+        None => checksum,
+    }
+}
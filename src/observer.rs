@@ -0,0 +1,141 @@
+//! Progress notifications and cancellation for the blocking drivers.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::{BlockLength, Checksum, Result, RetryCause};
+
+/// Receives progress notifications during a transfer, e.g. to drive a
+/// progress bar.
+///
+/// All methods do nothing by default, so implementations only need to
+/// override the ones they are interested in. `()` is an observer that
+/// ignores everything.
+pub trait TransferObserver {
+    /// The handshake completed, and the transfer uses `checksum` and
+    /// blocks of `block_length`.
+    fn handshake(&mut self, checksum: Checksum, block_length: BlockLength) {
+        let _ = (checksum, block_length);
+    }
+
+    /// A block was acknowledged by the receiver (when sending) or accepted
+    /// (when receiving). `blockno` counts from 1 without wrapping at 256,
+    /// `offset` is the position of the block's data in the stream and `len`
+    /// the number of bytes of data in it.
+    fn block(&mut self, blockno: u32, offset: u64, len: usize) {
+        let _ = (blockno, offset, len);
+    }
+
+    /// Something had to be sent again because of `cause`.
+    fn retry(&mut self, cause: RetryCause) {
+        let _ = cause;
+    }
+
+    /// The transfer ended with `result`, successfully or not.
+    fn finished(&mut self, result: &Result<usize>) {
+        let _ = result;
+    }
+
+    /// Polled by the driver after handling each byte or timeout. Once this
+    /// returns `true`, the driver tells the other end to stop and returns
+    /// [`Error::Aborted`](crate::Error::Aborted).
+    fn is_canceled(&mut self) -> bool {
+        false
+    }
+}
+
+impl TransferObserver for () {}
+
+impl<T: TransferObserver + ?Sized> TransferObserver for &mut T {
+    fn handshake(&mut self, checksum: Checksum, block_length: BlockLength) {
+        (**self).handshake(checksum, block_length)
+    }
+
+    fn block(&mut self, blockno: u32, offset: u64, len: usize) {
+        (**self).block(blockno, offset, len)
+    }
+
+    fn retry(&mut self, cause: RetryCause) {
+        (**self).retry(cause)
+    }
+
+    fn finished(&mut self, result: &Result<usize>) {
+        (**self).finished(result)
+    }
+
+    fn is_canceled(&mut self) -> bool {
+        (**self).is_canceled()
+    }
+}
+
+/// Notifies both observers; the transfer is canceled if either of them
+/// cancels it.
+impl<A: TransferObserver, B: TransferObserver> TransferObserver for (A, B) {
+    fn handshake(&mut self, checksum: Checksum, block_length: BlockLength) {
+        self.0.handshake(checksum, block_length);
+        self.1.handshake(checksum, block_length);
+    }
+
+    fn block(&mut self, blockno: u32, offset: u64, len: usize) {
+        self.0.block(blockno, offset, len);
+        self.1.block(blockno, offset, len);
+    }
+
+    fn retry(&mut self, cause: RetryCause) {
+        self.0.retry(cause);
+        self.1.retry(cause);
+    }
+
+    fn finished(&mut self, result: &Result<usize>) {
+        self.0.finished(result);
+        self.1.finished(result);
+    }
+
+    fn is_canceled(&mut self) -> bool {
+        self.0.is_canceled() || self.1.is_canceled()
+    }
+}
+
+/// A flag to abort a running transfer from another thread or an interrupt
+/// handler.
+///
+/// Pass a reference to it as the observer of a transfer, e.g. to
+/// [`Xmodem::send_with_observer`](crate::Xmodem::send_with_observer), or
+/// combine it with another observer in a tuple. The flag is checked
+/// between bytes and whenever a read from the device returns while waiting
+/// for one, so the transfer stops at the latest once the device's read
+/// returns.
+#[derive(Debug, Default)]
+pub struct CancelToken {
+    canceled: AtomicBool,
+}
+
+impl CancelToken {
+    /// Creates a token that is not canceled. This is `const`, so the token
+    /// can live in a `static`.
+    pub const fn new() -> Self {
+        CancelToken {
+            canceled: AtomicBool::new(false),
+        }
+    }
+
+    /// Requests the transfer to stop.
+    pub fn cancel(&self) {
+        self.canceled.store(true, Ordering::Relaxed);
+    }
+
+    /// Returns `true` once [`cancel`](Self::cancel) was called.
+    pub fn is_canceled(&self) -> bool {
+        self.canceled.load(Ordering::Relaxed)
+    }
+
+    /// Clears the flag, so the token can be used for another transfer.
+    pub fn reset(&self) {
+        self.canceled.store(false, Ordering::Relaxed);
+    }
+}
+
+impl TransferObserver for &CancelToken {
+    fn is_canceled(&mut self) -> bool {
+        CancelToken::is_canceled(self)
+    }
+}
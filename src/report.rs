@@ -0,0 +1,107 @@
+//! Statistics gathered over a whole transfer.
+
+use core::time::Duration;
+
+use crate::{BlockLength, Checksum, Result, RetryCause, TransferObserver};
+
+/// The number of retries of a transfer, by cause.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct RetryCounts {
+    /// See [`RetryCause::Timeout`].
+    pub timeout: u32,
+
+    /// See [`RetryCause::Nak`].
+    pub nak: u32,
+
+    /// See [`RetryCause::Checksum`].
+    pub checksum: u32,
+
+    /// See [`RetryCause::Sequence`].
+    pub sequence: u32,
+
+    /// See [`RetryCause::Garbage`].
+    pub garbage: u32,
+
+    /// See [`RetryCause::Rejected`].
+    pub rejected: u32,
+}
+
+impl RetryCounts {
+    /// The number of retries for any cause.
+    pub fn total(&self) -> u32 {
+        self.timeout + self.nak + self.checksum + self.sequence + self.garbage + self.rejected
+    }
+}
+
+/// Statistics about a transfer, successful or not, returned by
+/// [`Xmodem::send_with_report`] and [`Xmodem::recv_with_report`].
+///
+/// This is also a [`TransferObserver`], so it can be filled in by any
+/// driver taking one; only `elapsed` is left to the caller then.
+///
+/// [`Xmodem::send_with_report`]: crate::Xmodem::send_with_report
+/// [`Xmodem::recv_with_report`]: crate::Xmodem::recv_with_report
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct TransferReport {
+    /// The number of blocks acknowledged by the receiver.
+    pub blocks: u32,
+
+    /// The number of bytes of data sent or received. If the transfer
+    /// failed, this counts the data of the blocks acknowledged so far.
+    pub bytes: u64,
+
+    /// The retries that were needed along the way.
+    pub retries: RetryCounts,
+
+    /// The checksum mode agreed on in the handshake, if it completed.
+    pub checksum: Option<Checksum>,
+
+    /// The length of the blocks, if the handshake completed.
+    pub block_length: Option<BlockLength>,
+
+    /// How long the transfer took. This is zero if no clock was available.
+    pub elapsed: Duration,
+}
+
+impl TransferReport {
+    /// The effective throughput in bytes per second, or `None` if
+    /// `elapsed` is zero.
+    pub fn throughput(&self) -> Option<f64> {
+        let secs = self.elapsed.as_secs_f64();
+        if secs > 0.0 {
+            Some(self.bytes as f64 / secs)
+        } else {
+            None
+        }
+    }
+}
+
+impl TransferObserver for TransferReport {
+    fn handshake(&mut self, checksum: Checksum, block_length: BlockLength) {
+        self.checksum = Some(checksum);
+        self.block_length = Some(block_length);
+    }
+
+    fn block(&mut self, _blockno: u32, offset: u64, len: usize) {
+        self.blocks += 1;
+        self.bytes = offset + len as u64;
+    }
+
+    fn retry(&mut self, cause: RetryCause) {
+        let count = match cause {
+            RetryCause::Timeout => &mut self.retries.timeout,
+            RetryCause::Nak => &mut self.retries.nak,
+            RetryCause::Checksum => &mut self.retries.checksum,
+            RetryCause::Sequence => &mut self.retries.sequence,
+            RetryCause::Garbage => &mut self.retries.garbage,
+            RetryCause::Rejected => &mut self.retries.rejected,
+        };
+        *count += 1;
+    }
+
+    fn finished(&mut self, result: &Result<usize>) {
+        if let Ok(bytes) = result {
+            self.bytes = *bytes as u64;
+        }
+    }
+}
@@ -0,0 +1,41 @@
+//! Receiving block by block, e.g. straight into flash.
+
+/// What a [`BlockSink`] wants done with a block.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BlockAction {
+    /// Acknowledge the block and carry on with the next one.
+    Accept,
+
+    /// Reject the block with a NAK, so that the sender repeats it. This
+    /// counts against `max_errors`.
+    Retransmit,
+
+    /// Cancel the transfer, which then fails with
+    /// [`Error::Rejected`](crate::Error::Rejected).
+    Abort,
+}
+
+/// Takes the blocks received by
+/// [`Xmodem::recv_with_sink`](crate::Xmodem::recv_with_sink) or
+/// [`Transfer::recv_to_sink`](crate::Transfer::recv_to_sink), one at a
+/// time, and decides whether to acknowledge them.
+///
+/// Unlike a `Write` stream, a sink sees where each block belongs and can
+/// refuse it: a bootloader can erase and program a flash page per block,
+/// and check the image header in the first block before going any
+/// further.
+pub trait BlockSink {
+    /// Handles the block with number `blockno`, counting from 1 without
+    /// wrapping at 256. `offset` is the position of `data` in the stream.
+    ///
+    /// The last block is padded to its full length with `pad_byte`. If
+    /// this returns [`BlockAction::Retransmit`], the same block is offered
+    /// again once the sender has repeated it.
+    fn block(&mut self, blockno: u32, offset: u64, data: &[u8]) -> BlockAction;
+}
+
+impl<T: BlockSink + ?Sized> BlockSink for &mut T {
+    fn block(&mut self, blockno: u32, offset: u64, data: &[u8]) -> BlockAction {
+        (**self).block(blockno, offset, data)
+    }
+}
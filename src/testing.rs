@@ -0,0 +1,308 @@
+//! A simulated serial link, for testing code built on this crate without
+//! hardware.
+//!
+//! [`Link`] connects two [`Endpoint`]s, each of which is a device that can
+//! be passed to the drivers. The [`Faults`] of each direction damage the
+//! bytes on their way: they can be dropped, corrupted, duplicated, delayed
+//! or mixed with noise, and the link can be cut to make the other end time
+//! out. The random faults are drawn from a generator seeded with
+//! [`Link::seed`], so a failing test can be reproduced exactly.
+//!
+//! ```
+//! use std::thread;
+//! use xmodem::testing::Link;
+//! use xmodem::{Checksum, Xmodem};
+//!
+//! let mut link = Link::new();
+//! link.seed = 7;
+//! link.a_to_b.corrupt = 0.001;
+//! let (mut a, mut b) = link.open();
+//!
+//! let data = vec![0x42; 2000];
+//! let sender = thread::spawn(move || Xmodem::new().send(&mut a, &mut &data[..]));
+//! let mut received = Vec::new();
+//! Xmodem::new().recv(&mut b, &mut received, Checksum::CRC16).unwrap();
+//! assert_eq!(sender.join().unwrap().unwrap(), 2000);
+//! ```
+
+use std::io::{self, ErrorKind, Read, Write};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender, TryRecvError, channel};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The damage done to the bytes going one way over a [`Link`].
+///
+/// The probabilities apply to each byte written, independently. Offsets
+/// count the bytes written to the sending [`Endpoint`], from 0.
+#[derive(Clone, Debug, Default)]
+pub struct Faults {
+    /// The probability that a byte is lost.
+    pub drop: f64,
+
+    /// The probability that a random bit of a byte is flipped.
+    pub corrupt: f64,
+
+    /// The probability that a byte arrives twice.
+    pub duplicate: f64,
+
+    /// The probability that a random byte arrives before a byte.
+    pub noise: f64,
+
+    /// How long each byte takes to arrive.
+    pub delay: Duration,
+
+    /// A random extra delay of up to this long, for each byte. Bytes still
+    /// arrive in order.
+    pub jitter: Duration,
+
+    /// The offsets of the bytes to lose.
+    pub drop_at: Vec<u64>,
+
+    /// The offsets of the bytes to invert.
+    pub corrupt_at: Vec<u64>,
+
+    /// Loses everything from this offset on, as if the cable were pulled, so
+    /// that the other end times out.
+    pub cut_after: Option<u64>,
+}
+
+impl Faults {
+    pub fn new() -> Self {
+        Faults::default()
+    }
+}
+
+/// The configuration of a simulated link between two [`Endpoint`]s, `a`
+/// and `b`.
+#[derive(Clone, Debug, Default)]
+pub struct Link {
+    /// Seeds the random faults. The same seed and the same writes give the
+    /// same faults; only the delays depend on the timing of the test.
+    pub seed: u64,
+
+    /// The faults on the way from `a` to `b`.
+    pub a_to_b: Faults,
+
+    /// The faults on the way from `b` to `a`.
+    pub b_to_a: Faults,
+
+    /// Reads return `WouldBlock` instead of waiting for data.
+    pub nonblocking: bool,
+
+    /// Reads return `TimedOut` after waiting this long for data, like a
+    /// serial port with a read timeout. Ignored if `nonblocking` is set.
+    pub read_timeout: Option<Duration>,
+}
+
+impl Link {
+    /// A perfect link: reads wait for data, which arrives unchanged.
+    pub fn new() -> Self {
+        Link::default()
+    }
+
+    /// Connects two new endpoints, `a` and `b`.
+    pub fn open(&self) -> (Endpoint, Endpoint) {
+        let (a_tx, b_rx) = channel();
+        let (b_tx, a_rx) = channel();
+        let a = Endpoint {
+            input: a_rx,
+            held: None,
+            output: a_tx,
+            faults: self.a_to_b.clone(),
+            rng: Rng::new(self.seed),
+            written: 0,
+            last_arrival: Instant::now(),
+            nonblocking: self.nonblocking,
+            read_timeout: self.read_timeout,
+        };
+        let b = Endpoint {
+            input: b_rx,
+            held: None,
+            output: b_tx,
+            faults: self.b_to_a.clone(),
+            rng: Rng::new(!self.seed),
+            written: 0,
+            last_arrival: Instant::now(),
+            nonblocking: self.nonblocking,
+            read_timeout: self.read_timeout,
+        };
+        (a, b)
+    }
+}
+
+/// Connects two endpoints over a perfect link.
+pub fn loopback() -> (Endpoint, Endpoint) {
+    Link::new().open()
+}
+
+/// One end of a [`Link`]. Writing to it damages the bytes according to the
+/// [`Faults`] of its direction, and reading returns what arrived from the
+/// other end.
+///
+/// Once the other end is dropped, reads fail with `BrokenPipe` and writes
+/// are discarded.
+#[derive(Debug)]
+pub struct Endpoint {
+    input: Receiver<(Instant, u8)>,
+    held: Option<(Instant, u8)>,
+    output: Sender<(Instant, u8)>,
+    faults: Faults,
+    rng: Rng,
+    written: u64,
+    last_arrival: Instant,
+    nonblocking: bool,
+    read_timeout: Option<Duration>,
+}
+
+impl Endpoint {
+    /// The number of bytes written to this end so far.
+    pub fn written(&self) -> u64 {
+        self.written
+    }
+
+    /// Sends a byte, to arrive after the configured delay.
+    fn transmit(&mut self, byte: u8) {
+        let mut arrival = Instant::now() + self.faults.delay;
+        if !self.faults.jitter.is_zero() {
+            arrival += self.faults.jitter.mul_f64(self.rng.next_f64());
+        }
+        // Serial lines don't reorder
+        self.last_arrival = arrival.max(self.last_arrival);
+        // The other end may hang up while we're still canceling
+        let _ = self.output.send((self.last_arrival, byte));
+    }
+
+    /// Takes the next byte that has arrived. If `wait` is set, this waits
+    /// for one until `deadline` passes, if any.
+    fn next_byte(&mut self, wait: bool, deadline: Option<Instant>) -> io::Result<Option<u8>> {
+        let (arrival, byte) = match self.held.take() {
+            Some(held) => held,
+            None if !wait => match self.input.try_recv() {
+                Ok(v) => v,
+                Err(TryRecvError::Empty) => return Ok(None),
+                Err(TryRecvError::Disconnected) => return Err(ErrorKind::BrokenPipe.into()),
+            },
+            None => {
+                let res = match deadline {
+                    Some(deadline) => self
+                        .input
+                        .recv_timeout(deadline.saturating_duration_since(Instant::now())),
+                    None => self
+                        .input
+                        .recv()
+                        .map_err(|_| RecvTimeoutError::Disconnected),
+                };
+                match res {
+                    Ok(v) => v,
+                    Err(RecvTimeoutError::Timeout) => return Ok(None),
+                    Err(RecvTimeoutError::Disconnected) => {
+                        return Err(ErrorKind::BrokenPipe.into());
+                    }
+                }
+            }
+        };
+
+        let now = Instant::now();
+        if arrival > now {
+            if !wait {
+                self.held = Some((arrival, byte));
+                return Ok(None);
+            }
+            if let Some(deadline) = deadline
+                && deadline < arrival
+            {
+                thread::sleep(deadline.saturating_duration_since(now));
+                self.held = Some((arrival, byte));
+                return Ok(None);
+            }
+            thread::sleep(arrival - now);
+        }
+        Ok(Some(byte))
+    }
+}
+
+impl Read for Endpoint {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let deadline = self.read_timeout.map(|t| Instant::now() + t);
+        buf[0] = match self.next_byte(!self.nonblocking, deadline)? {
+            Some(b) => b,
+            None if self.nonblocking => return Err(ErrorKind::WouldBlock.into()),
+            None => return Err(ErrorKind::TimedOut.into()),
+        };
+        // Return whatever else has already arrived
+        let mut n = 1;
+        while n < buf.len() {
+            match self.next_byte(false, None) {
+                Ok(Some(b)) => buf[n] = b,
+                _ => break,
+            }
+            n += 1;
+        }
+        Ok(n)
+    }
+}
+
+impl Write for Endpoint {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for &byte in buf {
+            let offset = self.written;
+            self.written += 1;
+            if self.faults.cut_after.is_some_and(|cut| offset >= cut)
+                || self.faults.drop_at.contains(&offset)
+                || self.rng.chance(self.faults.drop)
+            {
+                continue;
+            }
+            if self.rng.chance(self.faults.noise) {
+                let noise = self.rng.next_u64() as u8;
+                self.transmit(noise);
+            }
+            let byte = if self.faults.corrupt_at.contains(&offset) {
+                !byte
+            } else if self.rng.chance(self.faults.corrupt) {
+                byte ^ (1 << (self.rng.next_u64() % 8))
+            } else {
+                byte
+            };
+            self.transmit(byte);
+            if self.rng.chance(self.faults.duplicate) {
+                self.transmit(byte);
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// SplitMix64, which is plenty for picking faults.
+#[derive(Debug)]
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        Rng(seed)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// A uniformly distributed number in `[0, 1)`.
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn chance(&mut self, probability: f64) -> bool {
+        probability > 0.0 && self.next_f64() < probability
+    }
+}
@@ -0,0 +1,258 @@
+//! Transfers combining the optional parts of the blocking drivers.
+
+use ::log::{debug, error};
+
+use crate::io::{Read, Write};
+use crate::{
+    BlockAction, BlockLength, BlockSink, Checksum, Clock, DefaultClock, Error, PadTrimmer, Phase,
+    ReceiverMachine, Result, SendBuffer, SenderMachine, TransferObserver, Xmodem, run_receiver,
+    run_sender, write_padding, write_truncated,
+};
+
+/// An XMODEM transfer with any combination of a caller-provided buffer, a
+/// clock and an observer, created by [`Xmodem::transfer`]. It receives
+/// into either a `Write` stream or a [`BlockSink`].
+///
+/// The parts that are left out are the same as with [`Xmodem::send`] and
+/// [`Xmodem::recv`]: the packets are stored on the stack, the timeouts are
+/// enforced with the system clock if there is one, and nobody is told about
+/// the progress.
+///
+/// ```
+/// # use std::io::{Read, Write};
+/// # use xmodem::{BlockLength, Checksum, Clock, Xmodem};
+/// # fn recv<D: Read + Write>(
+/// #     dev: &mut D,
+/// #     flash: &mut [u8],
+/// #     clock: &mut dyn Clock,
+/// # ) -> xmodem::Result<usize> {
+/// // 128-byte blocks only, without a 1K buffer on the stack
+/// let mut buffer = [0; BlockLength::Standard.packet_buffer_len()];
+/// Xmodem::new()
+///     .transfer()
+///     .buffer(&mut buffer)
+///     .clock(clock)
+///     .recv(dev, &mut &mut flash[..], Checksum::CRC16)
+/// # }
+/// ```
+pub struct Transfer<'a> {
+    config: Xmodem,
+    buffer: Option<&'a mut [u8]>,
+    clock: Option<&'a mut dyn Clock>,
+    observer: Option<&'a mut dyn TransferObserver>,
+    length: Option<u64>,
+}
+
+impl<'a> Transfer<'a> {
+    pub(crate) fn new(config: &Xmodem) -> Self {
+        Transfer {
+            config: *config,
+            buffer: None,
+            clock: None,
+            observer: None,
+            length: None,
+        }
+    }
+
+    /// Works in `buffer` instead of on the stack.
+    ///
+    /// When sending, `buffer` must hold at least
+    /// [`BlockLength::Standard.send_buffer_len()`](BlockLength::send_buffer_len)
+    /// bytes. If it is too small for
+    /// [`BlockLength::OneK.send_buffer_len()`](BlockLength::send_buffer_len),
+    /// 128-byte blocks are sent regardless of `block_length`.
+    ///
+    /// When receiving, `buffer` must hold at least
+    /// [`BlockLength::Standard.packet_buffer_len()`](BlockLength::packet_buffer_len)
+    /// bytes. If it is too small for
+    /// [`BlockLength::OneK.packet_buffer_len()`](BlockLength::packet_buffer_len),
+    /// 1024-byte blocks are rejected with a NAK, so the sender has to use
+    /// 128-byte blocks, or fall back to them with `downgrade_blocks`.
+    ///
+    /// The transfer panics if `buffer` is too small for 128-byte blocks.
+    ///
+    /// The buffers used otherwise, over 2 KiB when sending and 1 KiB when
+    /// receiving, are then not reserved on the stack at all: the frames of
+    /// the transfer itself stay below 1 KiB, besides whatever `dev`, the
+    /// stream and the observer use.
+    pub fn buffer(mut self, buffer: &'a mut [u8]) -> Self {
+        self.buffer = Some(buffer);
+        self
+    }
+
+    /// Enforces the timeouts configured in the [`Xmodem`] with `clock`,
+    /// instead of the system clock. Without `std`, there is no system clock,
+    /// so this is the only way to enforce them.
+    ///
+    /// `dev` may then return `TimedOut`, `WouldBlock` or `Interrupted`
+    /// errors from `read` until the timeout expires.
+    pub fn clock(mut self, clock: &'a mut dyn Clock) -> Self {
+        self.clock = Some(clock);
+        self
+    }
+
+    /// Reports the progress of the transfer to `observer`, which may also
+    /// cancel it.
+    pub fn observer(mut self, observer: &'a mut dyn TransferObserver) -> Self {
+        self.observer = Some(observer);
+        self
+    }
+
+    /// Receives exactly `length` bytes, for [`Xmodem::recv_exact`].
+    pub(crate) fn length(mut self, length: u64) -> Self {
+        self.length = Some(length);
+        self
+    }
+
+    /// Sends the contents of `stream`, like [`Xmodem::send`].
+    pub fn send<D: Read + Write, R: Read>(self, dev: &mut D, stream: &mut R) -> Result<usize> {
+        let Transfer {
+            config,
+            buffer,
+            clock,
+            observer,
+            ..
+        } = self;
+        let mut default_clock = DefaultClock::default();
+        let clock: Option<&mut dyn Clock> = match clock {
+            Some(clock) => Some(clock),
+            None => default_clock.get(),
+        };
+        let observer: &mut dyn TransferObserver = match observer {
+            Some(observer) => observer,
+            None => &mut (),
+        };
+
+        debug!("Starting XMODEM transfer");
+        let result = match buffer {
+            Some(buffer) => {
+                let block_length = BlockLength::fitting(buffer.len(), BlockLength::send_buffer_len);
+                let (packet, ahead) = buffer.split_at_mut(block_length.packet_buffer_len());
+                let mut machine = SenderMachine::with_buffer(&config, packet);
+                let mut buffer = SendBuffer::with_buffer(&mut ahead[..block_length as usize]);
+                run_sender(dev, clock, &mut machine, &mut buffer, stream, observer)
+            }
+            None => send_on_stack(&config, dev, clock, stream, observer),
+        };
+        observer.finished(&result);
+        result
+    }
+
+    /// Receives a transmission into `outstream`, like [`Xmodem::recv`].
+    pub fn recv<D: Read + Write, W: Write>(
+        self,
+        dev: &mut D,
+        outstream: &mut W,
+        checksum: Checksum,
+    ) -> Result<usize> {
+        let mut trimmer = PadTrimmer::new(self.config.pad_byte);
+        let trim_padding = self.config.trim_padding;
+        let mut length = self.length;
+        self.receive(dev, checksum, |_, _, data| {
+            Ok(Some(match length {
+                Some(ref mut remaining) => write_truncated(outstream, remaining, data)?,
+                None if trim_padding => {
+                    let (held, data) = trimmer.block(data);
+                    write_padding(outstream, trimmer.pad_byte, held)?;
+                    outstream.write_all(data)?;
+                    held + data.len()
+                }
+                None => {
+                    outstream.write_all(data)?;
+                    data.len()
+                }
+            }))
+        })
+    }
+
+    /// Receives a transmission block by block into `sink`, like
+    /// [`Xmodem::recv_with_sink`].
+    pub fn recv_to_sink<D: Read + Write, S: BlockSink>(
+        self,
+        dev: &mut D,
+        sink: &mut S,
+        checksum: Checksum,
+    ) -> Result<usize> {
+        self.receive(dev, checksum, |blockno, offset, data| {
+            match sink.block(blockno, offset, data) {
+                BlockAction::Accept => Ok(Some(data.len())),
+                BlockAction::Retransmit => Ok(None),
+                BlockAction::Abort => {
+                    error!("Block {} rejected by the sink", blockno);
+                    Err(Error::Rejected(Phase::Block(blockno)))
+                }
+            }
+        })
+    }
+
+    /// Drives a receiver, handing each block to `write` as `run_receiver`
+    /// does.
+    fn receive<D, F>(self, dev: &mut D, checksum: Checksum, write: F) -> Result<usize>
+    where
+        D: Read + Write,
+        F: FnMut(u32, u64, &[u8]) -> Result<Option<usize>>,
+    {
+        let Transfer {
+            config,
+            buffer,
+            clock,
+            observer,
+            ..
+        } = self;
+        let mut default_clock = DefaultClock::default();
+        let clock: Option<&mut dyn Clock> = match clock {
+            Some(clock) => Some(clock),
+            None => default_clock.get(),
+        };
+        let observer: &mut dyn TransferObserver = match observer {
+            Some(observer) => observer,
+            None => &mut (),
+        };
+
+        debug!("Starting XMODEM receive");
+        let result = match buffer {
+            Some(buffer) => {
+                let mut machine = ReceiverMachine::with_buffer(&config, checksum, buffer);
+                run_receiver(dev, clock, &mut machine, observer, write)
+            }
+            None => recv_on_stack(&config, checksum, dev, clock, observer, write),
+        };
+        observer.finished(&result);
+        result
+    }
+}
+
+/// Sends with the packet and the block read ahead on the stack. This is
+/// kept out of [`Transfer::send`], so that a transfer with a caller-provided
+/// buffer doesn't reserve room for them too.
+#[inline(never)]
+fn send_on_stack<D: Read + Write, R: Read>(
+    config: &Xmodem,
+    dev: &mut D,
+    clock: Option<&mut dyn Clock>,
+    stream: &mut R,
+    observer: &mut dyn TransferObserver,
+) -> Result<usize> {
+    let mut machine = SenderMachine::new(config);
+    let mut buffer = SendBuffer::new();
+    run_sender(dev, clock, &mut machine, &mut buffer, stream, observer)
+}
+
+/// Receives with the packet on the stack, kept out of `Transfer::receive`
+/// like [`send_on_stack`].
+#[inline(never)]
+fn recv_on_stack<D, F>(
+    config: &Xmodem,
+    checksum: Checksum,
+    dev: &mut D,
+    clock: Option<&mut dyn Clock>,
+    observer: &mut dyn TransferObserver,
+    write: F,
+) -> Result<usize>
+where
+    D: Read + Write,
+    F: FnMut(u32, u64, &[u8]) -> Result<Option<usize>>,
+{
+    let mut machine = ReceiverMachine::new(config, checksum);
+    run_receiver(dev, clock, &mut machine, observer, write)
+}
@@ -0,0 +1,439 @@
+//! YMODEM batch transfers.
+//!
+//! YMODEM is XMODEM with CRC16 and (usually) 1024-byte blocks, where each
+//! file is preceded by a header in block 0 carrying its name, exact length,
+//! modification time and mode. Several files can be sent in one session,
+//! which is terminated by an empty header.
+
+use core::fmt;
+use core::str;
+use core::time::Duration;
+
+use ::log::{debug, info};
+
+use crate::clock::{Clock, DefaultClock};
+use crate::io::{self, Read, Write};
+use crate::machine::{ReceiverEvent, ReceiverMachine, SenderEvent, SenderMachine};
+use crate::{
+    BlockLength, Checksum, Error, Result, SendBuffer, Xmodem, flush_output, run_receiver,
+    run_sender, step, write_truncated,
+};
+
+/// The metadata sent in a YMODEM header block.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct FileInfo<'a> {
+    /// The name of the file, without any directory components.
+    pub name: &'a str,
+
+    /// The exact length of the file in bytes. The receiver uses this to
+    /// strip the padding from the last block.
+    pub length: Option<u64>,
+
+    /// The modification time, in seconds since the Unix epoch.
+    pub mtime: Option<u64>,
+
+    /// The Unix file mode.
+    pub mode: Option<u32>,
+}
+
+impl<'a> FileInfo<'a> {
+    /// Creates the metadata for a file with the given name and length.
+    pub fn new(name: &'a str, length: u64) -> Self {
+        FileInfo {
+            name,
+            length: Some(length),
+            mtime: None,
+            mode: None,
+        }
+    }
+
+    /// Writes the header contents. The optional fields are positional, so
+    /// they are written up to the first one that is missing.
+    fn write_header<W: fmt::Write>(&self, w: &mut W) -> fmt::Result {
+        write!(w, "{}\0", self.name)?;
+        if let Some(length) = self.length {
+            write!(w, "{}", length)?;
+            if let Some(mtime) = self.mtime {
+                write!(w, " {:o}", mtime)?;
+                if let Some(mode) = self.mode {
+                    write!(w, " {:o}", mode)?;
+                }
+            }
+        }
+        w.write_char('\0')
+    }
+
+    /// Writes the header contents into `buf`, returning their length.
+    pub(crate) fn encode(&self, buf: &mut [u8]) -> Result<usize> {
+        let len = buf.len();
+        let mut cursor = Cursor(buf);
+        self.write_header(&mut cursor).map_err(|_| Error::Invalid)?;
+        Ok(len - cursor.0.len())
+    }
+
+    /// Parses a header block. Returns `None` for the empty header ending
+    /// the batch.
+    pub(crate) fn parse(block: &'a [u8]) -> Result<Option<Self>> {
+        let mut parts = block.splitn(3, |&b| b == 0);
+        let name = parts.next().unwrap_or_default();
+        if name.is_empty() {
+            return Ok(None);
+        }
+        let name = str::from_utf8(name).map_err(|_| Error::Invalid)?;
+        let fields =
+            str::from_utf8(parts.next().unwrap_or_default()).map_err(|_| Error::Invalid)?;
+
+        let mut fields = fields.split_ascii_whitespace();
+        let length = fields.next().and_then(|f| f.parse().ok());
+        let mtime = fields.next().and_then(|f| u64::from_str_radix(f, 8).ok());
+        let mode = fields.next().and_then(|f| u32::from_str_radix(f, 8).ok());
+
+        Ok(Some(FileInfo {
+            name,
+            length,
+            mtime,
+            mode,
+        }))
+    }
+}
+
+/// Counts the bytes written to it.
+struct Counter(usize);
+
+impl fmt::Write for Counter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0 += s.len();
+        Ok(())
+    }
+}
+
+/// Writes into a fixed buffer.
+struct Cursor<'a>(&'a mut [u8]);
+
+impl fmt::Write for Cursor<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let buf = core::mem::take(&mut self.0);
+        if s.len() > buf.len() {
+            return Err(fmt::Error);
+        }
+        let (a, b) = buf.split_at_mut(s.len());
+        a.copy_from_slice(s.as_bytes());
+        self.0 = b;
+        Ok(())
+    }
+}
+
+/// Configuration for a YMODEM batch transfer.
+#[derive(Copy, Clone, Debug)]
+pub struct Ymodem {
+    /// The number of errors that can occur before the communication is
+    /// considered a failure. This applies to each header and each file
+    /// separately.
+    pub max_errors: u32,
+
+    /// The byte used to pad the last block of each file. The receiver
+    /// strips it using the length from the header.
+    pub pad_byte: u8,
+
+    /// The length of the data blocks. Headers are sent in 128-byte blocks
+    /// unless they don't fit.
+    pub block_length: BlockLength,
+
+    /// When receiving, ask for YMODEM-G, where the headers and blocks are
+    /// streamed without ACKs. See [`Xmodem::streaming`].
+    pub streaming: bool,
+
+    /// How long to wait for the start of each header and file. See
+    /// [`Xmodem::handshake_timeout`].
+    pub handshake_timeout: Duration,
+
+    /// How long to wait between two bytes of the same packet.
+    pub byte_timeout: Duration,
+
+    /// How long to wait for the response to a block, or for the next
+    /// block. See [`Xmodem::ack_timeout`].
+    pub ack_timeout: Duration,
+
+    /// How long the sender waits for the ACK of its EOT.
+    pub eot_timeout: Duration,
+
+    /// The number of CAN bytes sent to tell the other end to abort the
+    /// transfer. See [`Xmodem::cancel_count`].
+    pub cancel_count: u32,
+
+    /// Follow the CAN bytes by as many backspaces. See
+    /// [`Xmodem::cancel_backspaces`].
+    pub cancel_backspaces: bool,
+}
+
+impl Ymodem {
+    /// Creates the YMODEM config with default parameters.
+    pub fn new() -> Self {
+        Ymodem {
+            max_errors: 16,
+            pad_byte: 0x1a,
+            block_length: BlockLength::OneK,
+            streaming: false,
+            handshake_timeout: Duration::from_secs(10),
+            byte_timeout: Duration::from_secs(1),
+            ack_timeout: Duration::from_secs(10),
+            eot_timeout: Duration::from_secs(10),
+            cancel_count: 8,
+            cancel_backspaces: false,
+        }
+    }
+
+    /// Sends a single file of the batch.
+    ///
+    /// `info` is sent in the header block, followed by the contents of
+    /// `stream`. The receiver relies on `info.length` to recover the exact
+    /// file size. Call [`finish`](Self::finish) after the last file.
+    /// Returns the number of bytes read from `stream`.
+    ///
+    /// Timeouts are handled as in [`Xmodem::send`]; see
+    /// [`send_file_with_clock`](Self::send_file_with_clock) to provide a
+    /// clock without `std`.
+    pub fn send_file<D: Read + Write, R: Read>(
+        &mut self,
+        dev: &mut D,
+        info: &FileInfo<'_>,
+        stream: &mut R,
+    ) -> Result<usize> {
+        self.send_file_inner(dev, info, stream, DefaultClock::default().get())
+    }
+
+    /// Like [`send_file`](Self::send_file), enforcing the timeouts with
+    /// `clock`.
+    pub fn send_file_with_clock<D: Read + Write, R: Read, C: Clock>(
+        &mut self,
+        dev: &mut D,
+        info: &FileInfo<'_>,
+        stream: &mut R,
+        clock: &mut C,
+    ) -> Result<usize> {
+        self.send_file_inner(dev, info, stream, Some(clock))
+    }
+
+    fn send_file_inner<D: Read + Write, R: Read>(
+        &self,
+        dev: &mut D,
+        info: &FileInfo<'_>,
+        stream: &mut R,
+        mut clock: Option<&mut dyn Clock>,
+    ) -> Result<usize> {
+        debug!("Sending YMODEM header for {}", info.name);
+        self.send_header(dev, clock.as_deref_mut(), Some(info))?;
+
+        debug!("Sending YMODEM data for {}", info.name);
+        let mut machine = SenderMachine::new(&self.xmodem(self.block_length, self.pad_byte));
+        run_sender(
+            dev,
+            clock,
+            &mut machine,
+            &mut SendBuffer::new(),
+            stream,
+            &mut (),
+        )
+    }
+
+    /// Ends the batch by sending an empty header.
+    pub fn finish<D: Read + Write>(&mut self, dev: &mut D) -> Result<()> {
+        self.finish_inner(dev, DefaultClock::default().get())
+    }
+
+    /// Like [`finish`](Self::finish), enforcing the timeouts with `clock`.
+    pub fn finish_with_clock<D: Read + Write, C: Clock>(
+        &mut self,
+        dev: &mut D,
+        clock: &mut C,
+    ) -> Result<()> {
+        self.finish_inner(dev, Some(clock))
+    }
+
+    fn finish_inner<D: Read + Write>(
+        &self,
+        dev: &mut D,
+        clock: Option<&mut dyn Clock>,
+    ) -> Result<()> {
+        debug!("Sending YMODEM end of batch");
+        self.send_header(dev, clock, None)?;
+        info!("YMODEM batch transmission successful");
+        Ok(())
+    }
+
+    /// Receives a single file of the batch.
+    ///
+    /// Once the header has been received, `open` is called with its
+    /// contents and returns the writer the file is stored into. If the
+    /// header has a length, the padding of the last block is stripped.
+    /// Returns the number of bytes written, or `None` once the sender has
+    /// ended the batch.
+    ///
+    /// Timeouts are handled as in [`Xmodem::recv`]; see
+    /// [`recv_file_with_clock`](Self::recv_file_with_clock) to provide a
+    /// clock without `std`.
+    pub fn recv_file<D, W, F>(&mut self, dev: &mut D, open: F) -> Result<Option<usize>>
+    where
+        D: Read + Write,
+        W: Write,
+        F: FnOnce(&FileInfo<'_>) -> io::Result<W>,
+    {
+        self.recv_file_inner(dev, open, DefaultClock::default().get())
+    }
+
+    /// Like [`recv_file`](Self::recv_file), enforcing the timeouts with
+    /// `clock`.
+    pub fn recv_file_with_clock<D, W, F, C>(
+        &mut self,
+        dev: &mut D,
+        open: F,
+        clock: &mut C,
+    ) -> Result<Option<usize>>
+    where
+        D: Read + Write,
+        W: Write,
+        F: FnOnce(&FileInfo<'_>) -> io::Result<W>,
+        C: Clock,
+    {
+        self.recv_file_inner(dev, open, Some(clock))
+    }
+
+    fn recv_file_inner<D, W, F>(
+        &self,
+        dev: &mut D,
+        open: F,
+        mut clock: Option<&mut dyn Clock>,
+    ) -> Result<Option<usize>>
+    where
+        D: Read + Write,
+        W: Write,
+        F: FnOnce(&FileInfo<'_>) -> io::Result<W>,
+    {
+        let config = self.xmodem(self.block_length, self.pad_byte);
+        let mut machine = ReceiverMachine::new(&config, Checksum::CRC16);
+        machine.set_next_blockno(0);
+
+        let (mut out, length) = loop {
+            flush_output(dev, &mut machine)?;
+
+            match step(dev, clock.as_deref_mut(), &mut machine, &mut ())? {
+                Some(ReceiverEvent::Block(_)) => {
+                    let info = match FileInfo::parse(machine.block()) {
+                        Ok(Some(info)) => info,
+                        Ok(None) => {
+                            machine.accept_block();
+                            flush_output(dev, &mut machine)?;
+                            info!("YMODEM batch reception successful");
+                            return Ok(None);
+                        }
+                        Err(e) => {
+                            machine.cancel();
+                            flush_output(dev, &mut machine).unwrap_or_default();
+                            return Err(e);
+                        }
+                    };
+                    debug!("Receiving YMODEM file {}", info.name);
+                    let length = info.length;
+                    match open(&info) {
+                        Ok(out) => {
+                            machine.accept_block();
+                            flush_output(dev, &mut machine)?;
+                            break (out, length);
+                        }
+                        Err(e) => {
+                            machine.cancel();
+                            flush_output(dev, &mut machine).unwrap_or_default();
+                            return Err(Error::Io(e));
+                        }
+                    }
+                }
+                Some(ReceiverEvent::Finished) => {
+                    // EOT instead of a header
+                    machine.cancel();
+                    flush_output(dev, &mut machine).unwrap_or_default();
+                    return Err(Error::Invalid);
+                }
+                Some(ReceiverEvent::Retry(_)) | None => {}
+            }
+        };
+
+        let mut remaining = length.unwrap_or(u64::MAX);
+        let mut machine = ReceiverMachine::new(&config, Checksum::CRC16);
+        let bytes = run_receiver(dev, clock, &mut machine, &mut (), |_, _, data| {
+            Ok(Some(write_truncated(&mut out, &mut remaining, data)?))
+        })?;
+        Ok(Some(bytes))
+    }
+
+    /// Sends a header block, or the empty header if `info` is `None`.
+    fn send_header<D: Read + Write>(
+        &self,
+        dev: &mut D,
+        mut clock: Option<&mut (dyn Clock + '_)>,
+        info: Option<&FileInfo<'_>>,
+    ) -> Result<()> {
+        let mut len = Counter(0);
+        if let Some(info) = info {
+            info.write_header(&mut len).map_err(|_| Error::Invalid)?;
+        }
+        let block_length = match len.0 {
+            0..=128 => BlockLength::Standard,
+            129..=1024 => BlockLength::OneK,
+            _ => return Err(Error::Invalid),
+        };
+
+        let mut machine = SenderMachine::new(&self.xmodem(block_length, 0));
+        machine.set_next_blockno(0);
+        loop {
+            let event = match machine.poll_event() {
+                Some(event) => Some(event),
+                None => step(dev, clock.as_deref_mut(), &mut machine, &mut ())?,
+            };
+            match event {
+                Some(SenderEvent::Started(_)) => {
+                    if let Some(info) = info {
+                        if info
+                            .write_header(&mut Cursor(machine.next_block()))
+                            .is_err()
+                        {
+                            machine.cancel();
+                            flush_output(dev, &mut machine).unwrap_or_default();
+                            return Err(Error::Invalid);
+                        }
+                    } else {
+                        machine.next_block();
+                    }
+                    machine.send_block();
+                    flush_output(dev, &mut machine)?;
+                }
+                Some(SenderEvent::BlockDone(_)) => return Ok(()),
+                // Headers are never downgraded
+                Some(SenderEvent::Retry(_))
+                | Some(SenderEvent::Downgraded(_))
+                | Some(SenderEvent::Finished)
+                | None => {}
+            }
+        }
+    }
+
+    fn xmodem(&self, block_length: BlockLength, pad_byte: u8) -> Xmodem {
+        let mut config = Xmodem::new();
+        config.max_errors = self.max_errors;
+        config.pad_byte = pad_byte;
+        config.block_length = block_length;
+        config.streaming = self.streaming;
+        config.handshake_timeout = self.handshake_timeout;
+        config.byte_timeout = self.byte_timeout;
+        config.ack_timeout = self.ack_timeout;
+        config.eot_timeout = self.eot_timeout;
+        config.cancel_count = self.cancel_count;
+        config.cancel_backspaces = self.cancel_backspaces;
+        config
+    }
+}
+
+impl Default for Ymodem {
+    fn default() -> Self {
+        Self::new()
+    }
+}
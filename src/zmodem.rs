@@ -0,0 +1,946 @@
+//! ZMODEM batch transfers.
+//!
+//! ZMODEM streams the data of each file in subpackets without waiting for
+//! acknowledgements. The receiver reports errors by asking the sender to
+//! resume from a given offset with ZRPOS, which also allows it to resume an
+//! interrupted transfer of a file it already has the beginning of. Headers
+//! and subpackets are protected by CRC-16 or CRC-32, and control characters
+//! are escaped with ZDLE so that they survive terminals and flow control.
+
+use core::time::Duration;
+
+use ::log::{debug, error, info, warn};
+
+use crate::check::{crc16 as calc_crc, crc32_update};
+use crate::clock::{Clock, DefaultClock};
+use crate::io::{self, Read, Write};
+use crate::{BS, CAN, Error, FileInfo, MAX_CANCEL_LEN, Phase, Result, get_byte_timeout};
+
+const ZPAD: u8 = b'*';
+const ZDLE: u8 = 0x18;
+const ZBIN: u8 = b'A';
+const ZHEX: u8 = b'B';
+const ZBIN32: u8 = b'C';
+const DLE: u8 = 0x10;
+const XON: u8 = 0x11;
+const XOFF: u8 = 0x13;
+
+// Header types
+const ZRQINIT: u8 = 0;
+const ZRINIT: u8 = 1;
+const ZSINIT: u8 = 2;
+const ZACK: u8 = 3;
+const ZFILE: u8 = 4;
+const ZSKIP: u8 = 5;
+const ZNAK: u8 = 6;
+const ZFIN: u8 = 8;
+const ZRPOS: u8 = 9;
+const ZDATA: u8 = 10;
+const ZEOF: u8 = 11;
+
+// Subpacket ends: whether another subpacket follows and an ACK is expected
+const ZCRCE: u8 = b'h';
+const ZCRCG: u8 = b'i';
+const ZCRCQ: u8 = b'j';
+const ZCRCW: u8 = b'k';
+const ZRUB0: u8 = b'l';
+const ZRUB1: u8 = b'm';
+
+// Receiver capabilities in ZRINIT
+const CANFDX: u8 = 0x01;
+const CANOVIO: u8 = 0x02;
+const CANFC32: u8 = 0x20;
+
+// Binary file conversion in ZFILE
+const ZCBIN: u8 = 1;
+
+/// The longest subpacket sent or accepted.
+const SUBPACKET_LEN: usize = 1024;
+
+/// The data sent before waiting for an ACK. The sender keeps it around to
+/// resend it when the receiver asks for it with ZRPOS.
+const WINDOW_LEN: usize = 8 * SUBPACKET_LEN;
+
+/// The number of bytes skipped while looking for a header before giving
+/// up. After asking for a retransmission, the receiver has to skip the
+/// rest of the window the sender is streaming.
+const GARBAGE_LIMIT: usize = 4 * WINDOW_LEN;
+
+/// Configuration for a ZMODEM batch transfer.
+#[derive(Copy, Clone, Debug)]
+pub struct Zmodem {
+    /// The number of errors in a row that can occur before the
+    /// communication is considered a failure.
+    pub max_errors: u32,
+
+    /// How long to wait for a header from the other end.
+    pub header_timeout: Duration,
+
+    /// How long to wait for each byte within a header or subpacket.
+    pub byte_timeout: Duration,
+
+    /// Use CRC-32 rather than CRC-16 if the other end supports it.
+    pub crc32: bool,
+
+    /// The number of CAN bytes sent to tell the other end to abort the
+    /// transfer, at most 16. Receivers need at least five in a row.
+    pub cancel_count: u32,
+
+    /// Follow the CAN bytes by as many backspaces, to erase them from the
+    /// other end's command line in case it already exited the transfer.
+    pub cancel_backspaces: bool,
+}
+
+impl Zmodem {
+    /// Creates the ZMODEM config with default parameters.
+    pub fn new() -> Self {
+        Zmodem {
+            max_errors: 16,
+            header_timeout: Duration::from_secs(10),
+            byte_timeout: Duration::from_secs(1),
+            crc32: true,
+            cancel_count: 8,
+            cancel_backspaces: true,
+        }
+    }
+
+    /// Sends a single file of the batch.
+    ///
+    /// `info` is sent in the ZFILE header, followed by the contents of
+    /// `stream`. If the receiver already has the beginning of the file, the
+    /// start of `stream` is skipped. Call [`finish`](Self::finish) after the
+    /// last file. Returns the number of bytes read from `stream`, which is
+    /// zero if the receiver skipped the file.
+    ///
+    /// Timeouts are handled as in [`Xmodem::send`](crate::Xmodem::send);
+    /// see [`send_file_with_clock`](Self::send_file_with_clock) to provide a
+    /// clock without `std`.
+    pub fn send_file<D: Read + Write, R: Read>(
+        &mut self,
+        dev: &mut D,
+        info: &FileInfo<'_>,
+        stream: &mut R,
+    ) -> Result<usize> {
+        self.send_file_inner(dev, info, stream, DefaultClock::default().get())
+    }
+
+    /// Like [`send_file`](Self::send_file), enforcing the timeouts with
+    /// `clock`.
+    pub fn send_file_with_clock<D: Read + Write, R: Read, C: Clock>(
+        &mut self,
+        dev: &mut D,
+        info: &FileInfo<'_>,
+        stream: &mut R,
+        clock: &mut C,
+    ) -> Result<usize> {
+        self.send_file_inner(dev, info, stream, Some(clock))
+    }
+
+    fn send_file_inner<'a, D: Read + Write, R: Read>(
+        &self,
+        dev: &'a mut D,
+        info: &FileInfo<'_>,
+        stream: &mut R,
+        clock: Option<&'a mut dyn Clock>,
+    ) -> Result<usize> {
+        let mut link = Link::new(dev, self, clock);
+        let result = link.send_file(info, stream);
+        link.abort_on(&result);
+        result
+    }
+
+    /// Ends the batch, telling the receiver that there are no more files.
+    pub fn finish<D: Read + Write>(&mut self, dev: &mut D) -> Result<()> {
+        self.finish_inner(dev, DefaultClock::default().get())
+    }
+
+    /// Like [`finish`](Self::finish), enforcing the timeouts with `clock`.
+    pub fn finish_with_clock<D: Read + Write, C: Clock>(
+        &mut self,
+        dev: &mut D,
+        clock: &mut C,
+    ) -> Result<()> {
+        self.finish_inner(dev, Some(clock))
+    }
+
+    fn finish_inner<'a, D: Read + Write>(
+        &self,
+        dev: &'a mut D,
+        clock: Option<&'a mut dyn Clock>,
+    ) -> Result<()> {
+        let mut link = Link::new(dev, self, clock);
+        let result = link.finish();
+        link.abort_on(&result);
+        result
+    }
+
+    /// Receives a single file of the batch.
+    ///
+    /// Once the ZFILE header has been received, `open` is called with its
+    /// contents and returns the writer the file is stored into. Returns the
+    /// number of bytes written, or `None` once the sender has ended the
+    /// batch.
+    ///
+    /// Timeouts are handled as in [`Xmodem::recv`](crate::Xmodem::recv);
+    /// see [`recv_file_with_clock`](Self::recv_file_with_clock) to provide a
+    /// clock without `std`.
+    pub fn recv_file<D, W, F>(&mut self, dev: &mut D, open: F) -> Result<Option<usize>>
+    where
+        D: Read + Write,
+        W: Write,
+        F: FnOnce(&FileInfo<'_>) -> io::Result<W>,
+    {
+        self.resume_file(dev, |info| Ok((open(info)?, 0)))
+    }
+
+    /// Like [`recv_file`](Self::recv_file), enforcing the timeouts with
+    /// `clock`.
+    pub fn recv_file_with_clock<D, W, F, C>(
+        &mut self,
+        dev: &mut D,
+        open: F,
+        clock: &mut C,
+    ) -> Result<Option<usize>>
+    where
+        D: Read + Write,
+        W: Write,
+        F: FnOnce(&FileInfo<'_>) -> io::Result<W>,
+        C: Clock,
+    {
+        self.resume_file_with_clock(dev, |info| Ok((open(info)?, 0)), clock)
+    }
+
+    /// Like [`recv_file`](Self::recv_file), but `open` also returns the
+    /// number of bytes of the file that were already received, e.g. by an
+    /// interrupted transfer. The sender resumes from there, and the writer
+    /// only gets the rest of the file.
+    pub fn resume_file<D, W, F>(&mut self, dev: &mut D, open: F) -> Result<Option<usize>>
+    where
+        D: Read + Write,
+        W: Write,
+        F: FnOnce(&FileInfo<'_>) -> io::Result<(W, u64)>,
+    {
+        self.resume_file_inner(dev, open, DefaultClock::default().get())
+    }
+
+    /// Like [`resume_file`](Self::resume_file), enforcing the timeouts with
+    /// `clock`.
+    pub fn resume_file_with_clock<D, W, F, C>(
+        &mut self,
+        dev: &mut D,
+        open: F,
+        clock: &mut C,
+    ) -> Result<Option<usize>>
+    where
+        D: Read + Write,
+        W: Write,
+        F: FnOnce(&FileInfo<'_>) -> io::Result<(W, u64)>,
+        C: Clock,
+    {
+        self.resume_file_inner(dev, open, Some(clock))
+    }
+
+    fn resume_file_inner<'a, D, W, F>(
+        &self,
+        dev: &'a mut D,
+        open: F,
+        clock: Option<&'a mut dyn Clock>,
+    ) -> Result<Option<usize>>
+    where
+        D: Read + Write,
+        W: Write,
+        F: FnOnce(&FileInfo<'_>) -> io::Result<(W, u64)>,
+    {
+        let mut link = Link::new(dev, self, clock);
+        let result = link.recv_file(open);
+        link.abort_on(&result);
+        result
+    }
+}
+
+impl Default for Zmodem {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+struct Header {
+    kind: u8,
+    data: [u8; 4],
+}
+
+impl Header {
+    fn new(kind: u8, data: [u8; 4]) -> Self {
+        Header { kind, data }
+    }
+
+    /// A header carrying a file offset.
+    fn at(kind: u8, position: u64) -> Self {
+        Header::new(kind, (position as u32).to_le_bytes())
+    }
+
+    fn position(&self) -> u64 {
+        u64::from(u32::from_le_bytes(self.data))
+    }
+
+    /// The flags in ZF0, which is the last byte of the header.
+    fn flags(&self) -> u8 {
+        self.data[3]
+    }
+}
+
+/// A byte received after undoing the ZDLE escaping.
+enum Escaped {
+    Byte(u8),
+    End(u8),
+}
+
+/// One end of a ZMODEM session, for the duration of one file.
+///
+/// The reading methods return `Ok(None)` for anything that the protocol
+/// recovers from: timeouts, garbage and CRC errors.
+struct Link<'a, D> {
+    dev: &'a mut D,
+    clock: Option<&'a mut dyn Clock>,
+    config: Zmodem,
+    phase: Phase,
+    errors: u32,
+    cans: u32,
+    /// Whether we send binary headers and subpackets with CRC-32.
+    crc32: bool,
+    /// Whether the last binary header received, and so the subpackets
+    /// following it, used CRC-32.
+    rx_crc32: bool,
+    out: [u8; 256],
+    out_len: usize,
+}
+
+impl<'a, D: Read + Write> Link<'a, D> {
+    fn new(dev: &'a mut D, config: &Zmodem, clock: Option<&'a mut dyn Clock>) -> Self {
+        Link {
+            dev,
+            clock,
+            config: *config,
+            phase: Phase::Handshake,
+            errors: 0,
+            cans: 0,
+            crc32: false,
+            rx_crc32: false,
+            out: [0; 256],
+            out_len: 0,
+        }
+    }
+
+    fn send_file<R: Read>(&mut self, info: &FileInfo<'_>, stream: &mut R) -> Result<usize> {
+        debug!("Sending ZMODEM file {}", info.name);
+        let buflen = self.send_init()?;
+        let Some(offset) = self.send_file_info(info)? else {
+            info!("Receiver skipped {}", info.name);
+            return Ok(0);
+        };
+        let bytes = self.send_data(stream, offset, buflen)?;
+        info!("ZMODEM transmission of {} successful", info.name);
+        Ok(bytes)
+    }
+
+    /// Asks the receiver for its capabilities, returning the size of its
+    /// buffer (zero for unlimited).
+    fn send_init(&mut self) -> Result<usize> {
+        self.send_hex_header(Header::new(ZRQINIT, [0; 4]))?;
+        loop {
+            match self.read_header()? {
+                Some(h) if h.kind == ZRINIT => {
+                    self.crc32 = self.config.crc32 && h.flags() & CANFC32 != 0;
+                    let buflen = usize::from(u16::from_le_bytes([h.data[0], h.data[1]]));
+                    debug!("Receiver ready, CRC-32: {}, buffer: {}", self.crc32, buflen);
+                    return Ok(buflen);
+                }
+                Some(h) => {
+                    warn!("Expected ZRINIT, got header {}", h.kind);
+                    self.error()?;
+                }
+                None => {
+                    self.error()?;
+                    self.send_hex_header(Header::new(ZRQINIT, [0; 4]))?;
+                }
+            }
+        }
+    }
+
+    /// Sends the ZFILE header, returning the offset to start at or `None`
+    /// if the receiver doesn't want the file.
+    fn send_file_info(&mut self, info: &FileInfo<'_>) -> Result<Option<u64>> {
+        let mut buf = [0; SUBPACKET_LEN];
+        let len = info.encode(&mut buf)?;
+
+        // The receiver answers our ZRQINIT with another ZRINIT if it had
+        // already sent one.
+        let mut stale_zrinit = true;
+        loop {
+            self.send_bin_header(Header::new(ZFILE, [0, 0, 0, ZCBIN]))?;
+            self.send_subpacket(&buf[..len], ZCRCW)?;
+            loop {
+                match self.read_header()? {
+                    Some(h) if h.kind == ZRPOS => return Ok(Some(h.position())),
+                    Some(h) if h.kind == ZSKIP => return Ok(None),
+                    Some(h) if h.kind == ZRINIT && stale_zrinit => {
+                        stale_zrinit = false;
+                        continue;
+                    }
+                    Some(h) => warn!("Expected ZRPOS, got header {}", h.kind),
+                    None => {}
+                }
+                self.error()?;
+                break;
+            }
+        }
+    }
+
+    /// Streams the data of the file starting at `offset`, followed by
+    /// ZEOF, until the receiver has all of it. Returns the number of bytes
+    /// read from `stream`.
+    fn send_data<R: Read>(&mut self, stream: &mut R, offset: u64, buflen: usize) -> Result<usize> {
+        let window_len = match buflen {
+            0 => WINDOW_LEN,
+            n => n.min(WINDOW_LEN),
+        };
+        let subpacket_len = window_len.min(SUBPACKET_LEN);
+        let mut window = [0; WINDOW_LEN];
+        let window = &mut window[..window_len];
+        // The stream offset of the start of the window and the amount of
+        // data in it
+        let mut start = 0;
+        let mut len = 0;
+        let mut eof = false;
+        let mut pos = offset;
+
+        loop {
+            while pos >= start + len as u64 && !eof {
+                start += len as u64;
+                len = read_full(stream, window)?;
+                eof = len < window_len;
+            }
+            if pos < start || pos > start + len as u64 {
+                error!("Receiver asked for offset {} outside of the window", pos);
+                return Err(Error::Invalid);
+            }
+
+            let end = start + len as u64;
+            if pos < end {
+                self.phase = block_phase(pos);
+                debug!("Sending data from offset {}", pos);
+                self.send_bin_header(Header::at(ZDATA, pos))?;
+                let mut chunks = window[(pos - start) as usize..len]
+                    .chunks(subpacket_len)
+                    .peekable();
+                while let Some(chunk) = chunks.next() {
+                    let kind = match chunks.peek() {
+                        Some(_) => ZCRCG,
+                        None if eof => ZCRCE,
+                        None => ZCRCW,
+                    };
+                    self.send_subpacket(chunk, kind)?;
+                    pos += chunk.len() as u64;
+                    self.phase = block_phase(pos);
+                }
+                if !eof {
+                    pos = self.wait_ack(start, end)?;
+                    continue;
+                }
+            }
+
+            self.phase = Phase::Eot;
+            self.send_bin_header(Header::at(ZEOF, end))?;
+            match self.wait_eof_ack()? {
+                None => return Ok(end as usize),
+                Some(p) => pos = p,
+            }
+        }
+    }
+
+    /// Waits for the receiver to acknowledge the window ending at `end`,
+    /// returning the offset to continue from.
+    fn wait_ack(&mut self, start: u64, end: u64) -> Result<u64> {
+        loop {
+            match self.read_header()? {
+                Some(h) if h.kind == ZACK && h.position() == end => {
+                    self.errors = 0;
+                    return Ok(end);
+                }
+                Some(h) if h.kind == ZACK => continue,
+                Some(h) if h.kind == ZRPOS => {
+                    warn!("Receiver asked to resend from {}", h.position());
+                    self.error()?;
+                    return Ok(h.position());
+                }
+                Some(h) => {
+                    warn!("Expected ZACK, got header {}", h.kind);
+                    self.error()?;
+                }
+                None => {
+                    warn!("Timeout waiting for ZACK at {}", end);
+                    self.error()?;
+                    return Ok(start);
+                }
+            }
+        }
+    }
+
+    /// Waits for the receiver to confirm the end of the file with ZRINIT.
+    /// Returns the offset to resend from if it asks for more data instead.
+    fn wait_eof_ack(&mut self) -> Result<Option<u64>> {
+        loop {
+            match self.read_header()? {
+                Some(h) if h.kind == ZRINIT => return Ok(None),
+                Some(h) if h.kind == ZRPOS => {
+                    warn!("Receiver asked to resend from {}", h.position());
+                    self.error()?;
+                    return Ok(Some(h.position()));
+                }
+                Some(h) if h.kind == ZACK => continue,
+                Some(h) => warn!("Expected ZRINIT, got header {}", h.kind),
+                None => warn!("Timeout waiting for ZRINIT"),
+            }
+            self.error()?;
+        }
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        debug!("Sending ZMODEM end of batch");
+        self.phase = Phase::Eot;
+        loop {
+            self.send_hex_header(Header::new(ZFIN, [0; 4]))?;
+            match self.read_header()? {
+                Some(h) if h.kind == ZFIN => {
+                    self.dev.write_all(b"OO")?;
+                    info!("ZMODEM batch transmission successful");
+                    return Ok(());
+                }
+                Some(h) => warn!("Expected ZFIN, got header {}", h.kind),
+                None => {}
+            }
+            self.error()?;
+        }
+    }
+
+    fn recv_file<W, F>(&mut self, open: F) -> Result<Option<usize>>
+    where
+        W: Write,
+        F: FnOnce(&FileInfo<'_>) -> io::Result<(W, u64)>,
+    {
+        let mut buf = [0; SUBPACKET_LEN];
+        self.send_zrinit()?;
+        let (mut out, start) = loop {
+            match self.read_header()? {
+                Some(h) if h.kind == ZFILE => match self.read_subpacket(&mut buf)? {
+                    Some((len, _)) => {
+                        let info = FileInfo::parse(&buf[..len])?.ok_or(Error::Invalid)?;
+                        debug!("Receiving ZMODEM file {}", info.name);
+                        break open(&info)?;
+                    }
+                    None => {
+                        self.error()?;
+                        self.send_hex_header(Header::new(ZNAK, [0; 4]))?;
+                    }
+                },
+                Some(h) if h.kind == ZSINIT => match self.read_subpacket(&mut buf)? {
+                    Some(_) => self.send_hex_header(Header::new(ZACK, [0; 4]))?,
+                    None => {
+                        self.error()?;
+                        self.send_hex_header(Header::new(ZNAK, [0; 4]))?;
+                    }
+                },
+                Some(h) if h.kind == ZFIN => {
+                    self.phase = Phase::Eot;
+                    // The sender may hang up as soon as it has our ZFIN, and
+                    // its final "OO" is just a courtesy
+                    self.send_hex_header(Header::new(ZFIN, [0; 4]))
+                        .unwrap_or_default();
+                    for _ in 0..2 {
+                        if !matches!(self.read_byte(self.config.byte_timeout), Ok(Some(b'O'))) {
+                            break;
+                        }
+                    }
+                    info!("ZMODEM batch reception successful");
+                    return Ok(None);
+                }
+                // The sender is looking for us, or missed the ZRINIT
+                // confirming the end of the previous file
+                Some(h) if h.kind == ZRQINIT || h.kind == ZEOF => self.send_zrinit()?,
+                Some(h) => {
+                    warn!("Expected ZFILE, got header {}", h.kind);
+                    self.error()?;
+                    self.send_zrinit()?;
+                }
+                None => {
+                    self.error()?;
+                    self.send_zrinit()?;
+                }
+            }
+        };
+
+        let mut offset = start;
+        self.phase = block_phase(offset);
+        self.send_hex_header(Header::at(ZRPOS, offset))?;
+        loop {
+            match self.read_header()? {
+                Some(h) if h.kind == ZDATA && h.position() == offset => loop {
+                    let Some((len, end)) = self.read_subpacket(&mut buf)? else {
+                        warn!("Bad subpacket at offset {}", offset);
+                        self.error()?;
+                        self.send_hex_header(Header::at(ZRPOS, offset))?;
+                        break;
+                    };
+                    out.write_all(&buf[..len])?;
+                    offset += len as u64;
+                    self.errors = 0;
+                    self.phase = block_phase(offset);
+                    match end {
+                        ZCRCG => {}
+                        ZCRCQ => self.send_hex_header(Header::at(ZACK, offset))?,
+                        ZCRCW => {
+                            self.send_hex_header(Header::at(ZACK, offset))?;
+                            break;
+                        }
+                        _ => break,
+                    }
+                },
+                Some(h) if h.kind == ZEOF && h.position() == offset => {
+                    self.send_zrinit()?;
+                    info!("ZMODEM reception successful");
+                    return Ok(Some((offset - start) as usize));
+                }
+                // Data we already asked to be resent
+                Some(h) if h.kind == ZEOF => {
+                    debug!("Ignoring ZEOF at {} while at {}", h.position(), offset)
+                }
+                // The sender missed our ZRPOS
+                Some(h) if h.kind == ZFILE => {
+                    self.read_subpacket(&mut buf)?;
+                    self.send_hex_header(Header::at(ZRPOS, offset))?;
+                }
+                Some(h) => {
+                    warn!("Unexpected header {} at offset {}", h.kind, offset);
+                    self.error()?;
+                    self.send_hex_header(Header::at(ZRPOS, offset))?;
+                }
+                None => {
+                    self.error()?;
+                    self.send_hex_header(Header::at(ZRPOS, offset))?;
+                }
+            }
+        }
+    }
+
+    fn send_zrinit(&mut self) -> Result<()> {
+        let mut flags = CANFDX | CANOVIO;
+        if self.config.crc32 {
+            flags |= CANFC32;
+        }
+        // A zero buffer size lets the sender stream the whole file
+        self.send_hex_header(Header::new(ZRINIT, [0, 0, 0, flags]))
+    }
+
+    /// Counts an error, giving up once there were too many.
+    fn error(&mut self) -> Result<()> {
+        self.errors += 1;
+        if self.errors >= self.config.max_errors {
+            error!(
+                "Exhausted max retries ({}) {}",
+                self.config.max_errors, self.phase
+            );
+            return Err(Error::ExhaustedRetries(self.phase));
+        }
+        Ok(())
+    }
+
+    /// Tells the other end to stop if `result` is an error that it doesn't
+    /// know about.
+    fn abort_on<T>(&mut self, result: &Result<T>) {
+        if let Err(e) = result
+            && !matches!(e, Error::Canceled(_))
+        {
+            let count = (self.config.cancel_count as usize).min(MAX_CANCEL_LEN);
+            let mut cancel = [CAN; 2 * MAX_CANCEL_LEN];
+            let len = if self.config.cancel_backspaces {
+                cancel[count..2 * count].fill(BS);
+                2 * count
+            } else {
+                count
+            };
+            self.dev.write_all(&cancel[..len]).unwrap_or_default();
+        }
+    }
+
+    fn read_byte(&mut self, timeout: Duration) -> Result<Option<u8>> {
+        let byte = get_byte_timeout(self.dev, self.clock.as_deref_mut(), timeout, &mut ())?;
+        // ZDLE is CAN, but escaped data never has two of them in a row
+        if byte == Some(CAN) {
+            self.cans += 1;
+            if self.cans >= 5 {
+                error!("Transmission canceled: received five cancel (CAN) bytes");
+                return Err(Error::Canceled(self.phase));
+            }
+        } else {
+            self.cans = 0;
+        }
+        Ok(byte)
+    }
+
+    /// Skips to the next header and reads it.
+    fn read_header(&mut self) -> Result<Option<Header>> {
+        let mut pad = false;
+        for _ in 0..GARBAGE_LIMIT {
+            let Some(byte) = self.read_byte(self.config.header_timeout)? else {
+                return Ok(None);
+            };
+            match byte {
+                ZPAD => pad = true,
+                ZDLE if pad => {
+                    let header = match self.read_byte(self.config.byte_timeout)? {
+                        Some(ZHEX) => self.read_hex_header()?,
+                        Some(ZBIN) => self.read_bin_header(false)?,
+                        Some(ZBIN32) => self.read_bin_header(true)?,
+                        _ => None,
+                    };
+                    if header.is_none() {
+                        warn!("Bad header");
+                    }
+                    return Ok(header);
+                }
+                _ => pad = false,
+            }
+        }
+        warn!("No header found");
+        Ok(None)
+    }
+
+    fn read_hex_header(&mut self) -> Result<Option<Header>> {
+        let mut raw = [0; 7];
+        for byte in raw.iter_mut() {
+            let mut value = 0;
+            for _ in 0..2 {
+                let Some(c) = self.read_byte(self.config.byte_timeout)? else {
+                    return Ok(None);
+                };
+                let Some(digit) = char::from(c).to_digit(16) else {
+                    return Ok(None);
+                };
+                value = value << 4 | digit as u8;
+            }
+            *byte = value;
+        }
+        let crc = u16::from_be_bytes([raw[5], raw[6]]);
+        if calc_crc(&raw[..5]) != crc {
+            return Ok(None);
+        }
+        Ok(Some(Header::new(raw[0], [raw[1], raw[2], raw[3], raw[4]])))
+    }
+
+    fn read_bin_header(&mut self, crc32: bool) -> Result<Option<Header>> {
+        let Some(raw) = self.read_escaped_bytes::<5>()? else {
+            return Ok(None);
+        };
+        let valid = if crc32 {
+            let Some(crc) = self.read_escaped_bytes::<4>()? else {
+                return Ok(None);
+            };
+            crc32_of(&[&raw]) == u32::from_le_bytes(crc)
+        } else {
+            let Some(crc) = self.read_escaped_bytes::<2>()? else {
+                return Ok(None);
+            };
+            calc_crc(&raw) == u16::from_be_bytes(crc)
+        };
+        if !valid {
+            return Ok(None);
+        }
+        self.rx_crc32 = crc32;
+        Ok(Some(Header::new(raw[0], [raw[1], raw[2], raw[3], raw[4]])))
+    }
+
+    /// Reads a data subpacket into `buf`, returning its length and the
+    /// kind of subpacket end.
+    fn read_subpacket(&mut self, buf: &mut [u8]) -> Result<Option<(usize, u8)>> {
+        let mut len = 0;
+        loop {
+            match self.read_escaped()? {
+                Some(Escaped::Byte(byte)) if len < buf.len() => {
+                    buf[len] = byte;
+                    len += 1;
+                }
+                Some(Escaped::End(end)) => {
+                    let data = &buf[..len];
+                    let valid = if self.rx_crc32 {
+                        let Some(crc) = self.read_escaped_bytes::<4>()? else {
+                            return Ok(None);
+                        };
+                        crc32_of(&[data, &[end]]) == u32::from_le_bytes(crc)
+                    } else {
+                        let Some(crc) = self.read_escaped_bytes::<2>()? else {
+                            return Ok(None);
+                        };
+                        let mut state = crc16::State::<crc16::XMODEM>::new();
+                        state.update(data);
+                        state.update(&[end]);
+                        state.get() == u16::from_be_bytes(crc)
+                    };
+                    return Ok(valid.then_some((len, end)));
+                }
+                Some(Escaped::Byte(_)) => {
+                    warn!("Subpacket longer than {} bytes", buf.len());
+                    return Ok(None);
+                }
+                None => return Ok(None),
+            }
+        }
+    }
+
+    fn read_escaped_bytes<const N: usize>(&mut self) -> Result<Option<[u8; N]>> {
+        let mut bytes = [0; N];
+        for byte in bytes.iter_mut() {
+            match self.read_escaped()? {
+                Some(Escaped::Byte(b)) => *byte = b,
+                _ => return Ok(None),
+            }
+        }
+        Ok(Some(bytes))
+    }
+
+    fn read_escaped(&mut self) -> Result<Option<Escaped>> {
+        let timeout = self.config.byte_timeout;
+        let mut escaped = false;
+        loop {
+            let Some(byte) = self.read_byte(timeout)? else {
+                return Ok(None);
+            };
+            // Flow control characters are always escaped when meant as data
+            if let XON | XOFF = byte & 0x7f {
+                continue;
+            }
+            if !escaped {
+                if byte == ZDLE {
+                    escaped = true;
+                    continue;
+                }
+                return Ok(Some(Escaped::Byte(byte)));
+            }
+            return Ok(match byte {
+                ZCRCE | ZCRCG | ZCRCQ | ZCRCW => Some(Escaped::End(byte)),
+                ZRUB0 => Some(Escaped::Byte(0x7f)),
+                ZRUB1 => Some(Escaped::Byte(0xff)),
+                b if b & 0x60 == 0x40 => Some(Escaped::Byte(b ^ 0x40)),
+                _ => None,
+            });
+        }
+    }
+
+    fn send_hex_header(&mut self, header: Header) -> Result<()> {
+        const DIGITS: &[u8; 16] = b"0123456789abcdef";
+        let mut raw = [header.kind, 0, 0, 0, 0, 0, 0];
+        raw[1..5].copy_from_slice(&header.data);
+        let crc = calc_crc(&raw[..5]).to_be_bytes();
+        raw[5..].copy_from_slice(&crc);
+
+        self.put_all(&[ZPAD, ZPAD, ZDLE, ZHEX])?;
+        for byte in raw {
+            self.put(DIGITS[usize::from(byte >> 4)])?;
+            self.put(DIGITS[usize::from(byte & 0xf)])?;
+        }
+        self.put_all(&[b'\r', b'\n' | 0x80])?;
+        if header.kind != ZFIN && header.kind != ZACK {
+            self.put(XON)?;
+        }
+        self.flush_out()
+    }
+
+    fn send_bin_header(&mut self, header: Header) -> Result<()> {
+        let mut raw = [header.kind, 0, 0, 0, 0];
+        raw[1..].copy_from_slice(&header.data);
+        if self.crc32 {
+            self.put_all(&[ZPAD, ZDLE, ZBIN32])?;
+            self.put_escaped(&raw)?;
+            self.put_escaped(&crc32_of(&[&raw]).to_le_bytes())?;
+        } else {
+            self.put_all(&[ZPAD, ZDLE, ZBIN])?;
+            self.put_escaped(&raw)?;
+            self.put_escaped(&calc_crc(&raw).to_be_bytes())?;
+        }
+        self.flush_out()
+    }
+
+    fn send_subpacket(&mut self, data: &[u8], end: u8) -> Result<()> {
+        self.put_escaped(data)?;
+        self.put_all(&[ZDLE, end])?;
+        if self.crc32 {
+            self.put_escaped(&crc32_of(&[data, &[end]]).to_le_bytes())?;
+        } else {
+            let mut state = crc16::State::<crc16::XMODEM>::new();
+            state.update(data);
+            state.update(&[end]);
+            self.put_escaped(&state.get().to_be_bytes())?;
+        }
+        if end == ZCRCW {
+            self.put(XON)?;
+        }
+        self.flush_out()
+    }
+
+    fn put_escaped(&mut self, data: &[u8]) -> Result<()> {
+        for &byte in data {
+            match byte & 0x7f {
+                ZDLE | DLE | XON | XOFF => self.put_all(&[ZDLE, byte ^ 0x40])?,
+                _ => self.put(byte)?,
+            }
+        }
+        Ok(())
+    }
+
+    fn put_all(&mut self, data: &[u8]) -> Result<()> {
+        for &byte in data {
+            self.put(byte)?;
+        }
+        Ok(())
+    }
+
+    fn put(&mut self, byte: u8) -> Result<()> {
+        if self.out_len == self.out.len() {
+            self.flush_out()?;
+        }
+        self.out[self.out_len] = byte;
+        self.out_len += 1;
+        Ok(())
+    }
+
+    fn flush_out(&mut self) -> Result<()> {
+        let len = core::mem::take(&mut self.out_len);
+        self.dev.write_all(&self.out[..len])?;
+        Ok(())
+    }
+}
+
+/// The phase reported in errors while transferring the data at `offset`,
+/// counting 1K subpackets from 1.
+fn block_phase(offset: u64) -> Phase {
+    Phase::Block((offset / SUBPACKET_LEN as u64) as u32 + 1)
+}
+
+/// Reads from `stream` until `buf` is full or the stream ends.
+fn read_full<R: Read>(stream: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match stream.read(&mut buf[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(len)
+}
+
+/// The CRC-32 of the concatenated `parts`.
+fn crc32_of(parts: &[&[u8]]) -> u32 {
+    !parts.iter().fold(!0, |crc, part| crc32_update(crc, part))
+}
@@ -0,0 +1,113 @@
+//! Test the async drivers against each other
+#![cfg(feature = "async")]
+extern crate tokio;
+extern crate xmodem;
+
+use std::io::Cursor;
+use std::time::Duration;
+use tokio::io::{AsyncWriteExt, duplex};
+use xmodem::{BlockLength, Checksum, Error, Phase, Xmodem};
+
+async fn xmodem_async_loopback(
+    checksum_mode: Checksum,
+    block_length: BlockLength,
+    data_len: usize,
+) {
+    let data_out: Vec<u8> = (0..data_len).map(|idx| ((idx + 7) * 13) as u8).collect();
+    let (mut p1, mut p2) = duplex(64);
+
+    let mut sender = Xmodem::new();
+    sender.block_length = block_length;
+    let mut receiver = Xmodem::new();
+    let mut data_in = Vec::new();
+    let mut stream = &data_out[..];
+
+    let (bytes_out, bytes_in) = tokio::join!(
+        sender.send_async(&mut p1, &mut stream),
+        receiver.recv_async(&mut p2, &mut data_in, checksum_mode),
+    );
+    assert_eq!(bytes_out.unwrap(), data_len);
+    assert_eq!(bytes_in.unwrap(), data_in.len());
+
+    let bl = block_length as usize;
+    assert_eq!(data_in.len(), data_len.div_ceil(bl) * bl);
+    assert_eq!(&data_in[..data_len], &data_out[..]);
+    assert!(data_in[data_len..].iter().all(|&b| b == 0x1a));
+}
+
+#[tokio::test]
+async fn xmodem_async_loopback_standard() {
+    xmodem_async_loopback(Checksum::Standard, BlockLength::Standard, 2000).await;
+}
+
+#[tokio::test]
+async fn xmodem_async_loopback_onek_crc() {
+    xmodem_async_loopback(Checksum::CRC16, BlockLength::OneK, 5000).await;
+}
+
+#[tokio::test]
+async fn xmodem_async_recv_times_out() {
+    // Nobody answers on the other end, and the stream never reports a
+    // timeout by itself.
+    let (mut p1, _p2) = duplex(64);
+    let mut xmodem = Xmodem::new();
+    xmodem.max_errors = 3;
+    xmodem.handshake_timeout = Duration::from_millis(10);
+
+    let mut data_in = Vec::new();
+    let res = xmodem
+        .recv_async(&mut p1, &mut data_in, Checksum::CRC16)
+        .await;
+    assert!(matches!(res, Err(Error::ExhaustedRetries(_))));
+}
+
+#[tokio::test]
+async fn xmodem_async_trim_padding() {
+    let data_out: Vec<u8> = (0..1000).map(|idx| (idx % 7) as u8 + 1).collect();
+    let (mut p1, mut p2) = duplex(64);
+
+    let mut sender = Xmodem::new();
+    let mut receiver = Xmodem::new();
+    receiver.trim_padding = true;
+    let mut data_in = Vec::new();
+    let mut stream = &data_out[..];
+
+    let (bytes_out, bytes_in) = tokio::join!(
+        sender.send_async(&mut p1, &mut stream),
+        receiver.recv_async(&mut p2, &mut data_in, Checksum::CRC16),
+    );
+    assert_eq!(bytes_out.unwrap(), 1000);
+    assert_eq!(bytes_in.unwrap(), 1000);
+    assert_eq!(data_in, data_out);
+}
+
+#[tokio::test]
+async fn xmodem_async_streaming_canceled_by_receiver() {
+    // Large enough for the whole transfer, so that the sender never waits
+    // for the receiver to read
+    let (mut p1, mut p2) = duplex(8192);
+    // The sender gets the data after the first block only once the
+    // receiver has given up
+    let (mut source_in, mut source) = duplex(8192);
+    source_in.write_all(&[0x42; 1024]).await.unwrap();
+
+    let mut sender = Xmodem::new();
+    sender.block_length = BlockLength::OneK;
+    let mut receiver = Xmodem::new();
+    receiver.streaming = true;
+    let mut space = [0; 100];
+    let mut disk = Cursor::new(&mut space[..]);
+
+    let receiving = async {
+        let res = receiver
+            .recv_async(&mut p2, &mut disk, Checksum::CRC16)
+            .await;
+        source_in.write_all(&[0x42; 4000]).await.unwrap();
+        drop(source_in);
+        res
+    };
+    let (sent, received) = tokio::join!(sender.send_async(&mut p1, &mut source), receiving);
+    assert!(matches!(received, Err(Error::Io(_))));
+    // The sender notices the cancel between blocks, not at the EOT
+    assert!(matches!(sent, Err(Error::Canceled(Phase::Block(_)))));
+}
@@ -0,0 +1,81 @@
+//! Test the block checks and plugging in custom ones
+extern crate xmodem;
+
+use xmodem::{
+    BlockCheck, BlockLength, Checksum, Crc16, Crc32, ReceiverEvent, ReceiverMachine, SenderEvent,
+    SenderMachine, Sum8, Xmodem,
+};
+
+fn check_value(check: &dyn BlockCheck, data: &[u8]) -> Vec<u8> {
+    let mut out = vec![0; check.size()];
+    check.compute(data, &mut out);
+    assert!(check.verify(data, &out));
+    out
+}
+
+#[test]
+fn check_values() {
+    let data = b"123456789";
+    assert_eq!(check_value(&Sum8, data), [0xdd]);
+    assert_eq!(check_value(&Crc16, data), [0x31, 0xc3]);
+    assert_eq!(check_value(&Crc32, data), 0xcbf4_3926u32.to_le_bytes());
+    assert_eq!(check_value(&Checksum::Standard, data), [0xdd]);
+    assert_eq!(check_value(&Checksum::CRC16, data), [0x31, 0xc3]);
+    assert!(!Crc32.verify(b"123456780", &0xcbf4_3926u32.to_le_bytes()));
+}
+
+/// A bootloader's proprietary trailer: the XOR of all bytes, twice.
+struct Xor;
+
+impl BlockCheck for Xor {
+    fn size(&self) -> usize {
+        2
+    }
+
+    fn compute(&self, data: &[u8], out: &mut [u8]) {
+        let x = data.iter().fold(0, |x, &y| x ^ y);
+        out.copy_from_slice(&[x, x]);
+    }
+}
+
+fn custom_check_block(check: &'static dyn BlockCheck) -> Vec<u8> {
+    let mut config = Xmodem::new();
+    config.block_check = Some(check);
+    let mut sender = SenderMachine::new(&config);
+    let mut receiver = ReceiverMachine::new(&config, Checksum::CRC16);
+    assert_eq!(receiver.poll_output(), Some(&b"C"[..]));
+    assert_eq!(
+        sender.handle_byte(b'C').unwrap(),
+        Some(SenderEvent::Started(Checksum::CRC16))
+    );
+
+    sender.next_block()[..5].copy_from_slice(b"hello");
+    sender.send_block();
+    let frame = sender.poll_output().unwrap().to_vec();
+    assert_eq!(
+        frame.len(),
+        3 + BlockLength::Standard as usize + check.size()
+    );
+
+    let (last, rest) = frame.split_last().unwrap();
+    for &b in rest {
+        assert_eq!(receiver.handle_byte(b).unwrap(), None);
+    }
+    assert_eq!(
+        receiver.handle_byte(*last).unwrap(),
+        Some(ReceiverEvent::Block(1))
+    );
+    assert_eq!(&receiver.block()[..5], b"hello");
+    frame
+}
+
+#[test]
+fn custom_checks_replace_the_negotiated_one() {
+    let frame = custom_check_block(&Crc32);
+    let mut expected = [0; 4];
+    Crc32.compute(&frame[3..131], &mut expected);
+    assert_eq!(&frame[131..], expected);
+
+    let frame = custom_check_block(&Xor);
+    assert_eq!(frame[131], frame[132]);
+}
@@ -0,0 +1,79 @@
+//! Test the `xmodem` binary against itself over stdin and stdout
+#![cfg(feature = "cli")]
+extern crate tempfile;
+
+use std::fs;
+use std::io;
+use std::process::{Command, Stdio};
+use std::thread;
+
+fn xmodem() -> Command {
+    Command::new(env!("CARGO_BIN_EXE_xmodem"))
+}
+
+#[test]
+fn cli_send_recv() {
+    let dir = tempfile::tempdir().unwrap();
+    let data: Vec<u8> = (0..5000).map(|idx| ((idx + 7) * 13) as u8).collect();
+    let src = dir.path().join("src");
+    let dst = dir.path().join("dst");
+    fs::write(&src, &data).unwrap();
+
+    let mut recv = xmodem()
+        .args(["recv", "--trim", "--quiet"])
+        .arg(&dst)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut send = xmodem()
+        .args(["send", "--1k", "--quiet"])
+        .arg(&src)
+        .stdin(recv.stdout.take().unwrap())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    // Close the loop from the sender back to the receiver
+    let mut send_out = send.stdout.take().unwrap();
+    let mut recv_in = recv.stdin.take().unwrap();
+    let pump = thread::spawn(move || io::copy(&mut send_out, &mut recv_in));
+
+    assert!(send.wait().unwrap().success());
+    assert!(recv.wait().unwrap().success());
+    pump.join().unwrap().unwrap();
+    assert_eq!(fs::read(&dst).unwrap(), data);
+}
+
+#[test]
+fn cli_missing_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let status = xmodem()
+        .arg("send")
+        .arg(dir.path().join("missing"))
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .unwrap();
+    assert_eq!(status.code(), Some(3));
+}
+
+#[test]
+fn cli_recv_times_out() {
+    // Nothing ever answers, so the handshake gives up and the partial file
+    // is removed.
+    let dir = tempfile::tempdir().unwrap();
+    let dst = dir.path().join("dst");
+    let mut recv = xmodem()
+        .args(["recv", "--quiet", "--max-errors", "2", "--timeout", "1"])
+        .arg(&dst)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let _stdin = recv.stdin.take();
+    assert_eq!(recv.wait().unwrap().code(), Some(1));
+    assert!(!dst.exists());
+}
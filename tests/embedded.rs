@@ -0,0 +1,167 @@
+//! Test the adapters for the embedded serial traits
+#![cfg(all(feature = "embedded-io", feature = "embedded-hal-nb"))]
+extern crate embedded_hal_nb;
+extern crate embedded_io;
+extern crate xmodem;
+
+use embedded_hal_nb::nb;
+use std::convert::Infallible;
+use std::sync::mpsc::{Receiver, Sender, TryRecvError, channel};
+use xmodem::{BlockLength, Checksum, EmbeddedIo, NbSerial, StdClock, Xmodem};
+
+/// One end of a byte pipe, implementing both sets of serial traits.
+struct Port {
+    pin: Receiver<u8>,
+    pout: Sender<u8>,
+    peeked: Option<u8>,
+}
+
+fn ports() -> (Port, Port) {
+    let (s1, r1) = channel();
+    let (s2, r2) = channel();
+    let p1 = Port {
+        pin: r1,
+        pout: s2,
+        peeked: None,
+    };
+    let p2 = Port {
+        pin: r2,
+        pout: s1,
+        peeked: None,
+    };
+    (p1, p2)
+}
+
+impl Port {
+    fn try_read(&mut self) -> Option<u8> {
+        match self.peeked.take() {
+            Some(b) => Some(b),
+            None => match self.pin.try_recv() {
+                Ok(b) => Some(b),
+                Err(TryRecvError::Empty) => None,
+                Err(TryRecvError::Disconnected) => panic!("other end hung up"),
+            },
+        }
+    }
+}
+
+impl embedded_io::ErrorType for Port {
+    type Error = Infallible;
+}
+
+impl embedded_io::Read for Port {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Infallible> {
+        let mut n = 0;
+        while n < buf.len() {
+            match self.try_read() {
+                Some(b) => buf[n] = b,
+                None if n == 0 => buf[n] = self.pin.recv().unwrap(),
+                None => break,
+            }
+            n += 1;
+        }
+        Ok(n)
+    }
+}
+
+impl embedded_io::ReadReady for Port {
+    fn read_ready(&mut self) -> Result<bool, Infallible> {
+        self.peeked = self.try_read();
+        Ok(self.peeked.is_some())
+    }
+}
+
+impl embedded_io::Write for Port {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Infallible> {
+        for &b in buf {
+            // The other end may hang up while we're still canceling
+            let _ = self.pout.send(b);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<(), Infallible> {
+        Ok(())
+    }
+}
+
+impl embedded_hal_nb::serial::ErrorType for Port {
+    type Error = Infallible;
+}
+
+impl embedded_hal_nb::serial::Read for Port {
+    fn read(&mut self) -> nb::Result<u8, Infallible> {
+        self.try_read().ok_or(nb::Error::WouldBlock)
+    }
+}
+
+impl embedded_hal_nb::serial::Write for Port {
+    fn write(&mut self, word: u8) -> nb::Result<(), Infallible> {
+        let _ = self.pout.send(word);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> nb::Result<(), Infallible> {
+        Ok(())
+    }
+}
+
+fn data(len: usize) -> Vec<u8> {
+    (0..len).map(|idx| ((idx + 7) * 13) as u8).collect()
+}
+
+#[test]
+fn embedded_io_loopback() {
+    let data_out = data(3000);
+    let expected = data_out.clone();
+    let (p1, p2) = ports();
+    let handle = std::thread::spawn(move || {
+        let mut xmodem = Xmodem::new();
+        xmodem.block_length = BlockLength::OneK;
+        xmodem
+            .send_with_clock(
+                &mut EmbeddedIo(p1),
+                &mut &data_out[..],
+                &mut StdClock::new(),
+            )
+            .unwrap()
+    });
+
+    let mut data_in = Vec::new();
+    Xmodem::new()
+        .recv_with_clock(
+            &mut EmbeddedIo(p2),
+            &mut data_in,
+            Checksum::CRC16,
+            &mut StdClock::new(),
+        )
+        .unwrap();
+
+    assert_eq!(handle.join().unwrap(), expected.len());
+    assert_eq!(&data_in[..expected.len()], &expected[..]);
+}
+
+#[test]
+fn embedded_hal_nb_loopback() {
+    let data_out = data(1000);
+    let expected = data_out.clone();
+    let (p1, p2) = ports();
+    let handle = std::thread::spawn(move || {
+        Xmodem::new()
+            .send_with_clock(&mut NbSerial(p1), &mut &data_out[..], &mut StdClock::new())
+            .unwrap()
+    });
+
+    let mut data_in = Vec::new();
+    Xmodem::new()
+        .recv_with_clock(
+            &mut NbSerial(p2),
+            &mut data_in,
+            Checksum::Standard,
+            &mut StdClock::new(),
+        )
+        .unwrap();
+
+    assert_eq!(handle.join().unwrap(), expected.len());
+    assert_eq!(&data_in[..expected.len()], &expected[..]);
+}
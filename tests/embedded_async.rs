@@ -0,0 +1,93 @@
+//! Test the embedded-io-async drivers against each other
+#![cfg(feature = "embedded-io-async")]
+extern crate embedded_io_async;
+extern crate tokio;
+extern crate xmodem;
+
+use embedded_io_async::{ErrorKind, ErrorType, Read, Write};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream, duplex};
+use xmodem::{AsyncTimer, BlockLength, Checksum, Error, Xmodem};
+
+/// Exposes a tokio stream through the `embedded-io-async` traits.
+struct Port(DuplexStream);
+
+impl ErrorType for Port {
+    type Error = ErrorKind;
+}
+
+impl Read for Port {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, ErrorKind> {
+        self.0.read(buf).await.map_err(|_| ErrorKind::Other)
+    }
+}
+
+impl Write for Port {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, ErrorKind> {
+        self.0.write(buf).await.map_err(|_| ErrorKind::Other)
+    }
+
+    async fn flush(&mut self) -> Result<(), ErrorKind> {
+        self.0.flush().await.map_err(|_| ErrorKind::Other)
+    }
+}
+
+struct Sink(Vec<u8>);
+
+impl ErrorType for Sink {
+    type Error = ErrorKind;
+}
+
+impl Write for Sink {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, ErrorKind> {
+        self.0.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+}
+
+struct TokioTimer;
+
+impl AsyncTimer for TokioTimer {
+    async fn sleep(&mut self, duration: Duration) {
+        tokio::time::sleep(duration).await
+    }
+}
+
+#[tokio::test]
+async fn embedded_async_loopback() {
+    let data_out: Vec<u8> = (0..5000).map(|idx| ((idx + 7) * 13) as u8).collect();
+    let (p1, p2) = duplex(64);
+    let (mut p1, mut p2) = (Port(p1), Port(p2));
+
+    let mut sender = Xmodem::new();
+    sender.block_length = BlockLength::OneK;
+    let mut receiver = Xmodem::new();
+    receiver.trim_padding = true;
+    let mut data_in = Sink(Vec::new());
+    let mut stream = &data_out[..];
+    let (mut t1, mut t2) = (TokioTimer, TokioTimer);
+
+    let (bytes_out, bytes_in) = tokio::join!(
+        sender.send_embedded_async(&mut p1, &mut stream, &mut t1),
+        receiver.recv_embedded_async(&mut p2, &mut data_in, Checksum::CRC16, &mut t2),
+    );
+    assert_eq!(bytes_out.unwrap(), 5000);
+    assert_eq!(bytes_in.unwrap(), 5000);
+    assert_eq!(data_in.0, data_out);
+}
+
+#[tokio::test]
+async fn embedded_async_recv_times_out() {
+    // Nobody answers on the other end; only the timer ends the wait.
+    let (p1, _p2) = duplex(64);
+    let mut p1 = Port(p1);
+    let mut xmodem = Xmodem::new();
+    xmodem.max_errors = 3;
+    xmodem.handshake_timeout = Duration::from_millis(10);
+
+    let mut data_in = Sink(Vec::new());
+    let res = xmodem
+        .recv_embedded_async(&mut p1, &mut data_in, Checksum::CRC16, &mut TokioTimer)
+        .await;
+    assert!(matches!(res, Err(Error::ExhaustedRetries(_))));
+}
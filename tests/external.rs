@@ -7,7 +7,7 @@ use rand::{RngCore, rng};
 use std::io::{self, Read, Seek, Write};
 use std::process::{ChildStdin, ChildStdout, Command, Stdio};
 use tempfile::NamedTempFile;
-use xmodem::{BlockLength, Checksum, Xmodem};
+use xmodem::{BlockLength, Checksum, FileInfo, Xmodem, Ymodem, Zmodem};
 
 struct ChildStdInOut {
     stdin: ChildStdin,
@@ -40,13 +40,10 @@ fn xmodem_recv(checksum_mode: Checksum, block_length: BlockLength, data_len: usi
 
     let mut send_builder = Command::new("sb");
     send_builder.arg("--xmodem");
-    match block_length {
-        BlockLength::OneK => {
-            send_builder.arg("--1k");
-        }
-        _ => {}
+    if let BlockLength::OneK = block_length {
+        send_builder.arg("--1k");
     }
-    let send = send_builder
+    let mut send = send_builder
         .arg(send_file.path())
         .stdin(Stdio::piped())
         .stdout(Stdio::piped())
@@ -54,8 +51,8 @@ fn xmodem_recv(checksum_mode: Checksum, block_length: BlockLength, data_len: usi
         .spawn()
         .unwrap();
 
-    let tx_stream = send.stdin.unwrap();
-    let rx_stream = send.stdout.unwrap();
+    let tx_stream = send.stdin.take().unwrap();
+    let rx_stream = send.stdout.take().unwrap();
     let mut serial_dev = ChildStdInOut {
         stdin: tx_stream,
         stdout: rx_stream,
@@ -67,14 +64,13 @@ fn xmodem_recv(checksum_mode: Checksum, block_length: BlockLength, data_len: usi
         .recv(&mut serial_dev, &mut recv_data, checksum_mode)
         .unwrap();
     assert_eq!(bytes, (data_len + 127) & !127);
+    send.wait().unwrap();
 
     let mut sent_data = Vec::new();
     send_file.seek(std::io::SeekFrom::Start(0)).unwrap();
     send_file.read_to_end(&mut sent_data).unwrap();
     let mut padded_data = sent_data.clone();
-    for _ in 0..(128 - sent_data.len() % 128) {
-        padded_data.push(0x1a);
-    }
+    padded_data.extend(std::iter::repeat_n(0x1a, 128 - sent_data.len() % 128));
     assert_eq!(padded_data, recv_data);
 }
 
@@ -105,7 +101,7 @@ fn xmodem_send_standard() {
     rng().fill_bytes(&mut data);
 
     let mut recv_file = NamedTempFile::new().unwrap();
-    let recv = Command::new("rb")
+    let mut recv = Command::new("rb")
         .arg("--xmodem")
         .arg(recv_file.path())
         .stdin(Stdio::piped())
@@ -114,8 +110,8 @@ fn xmodem_send_standard() {
         .spawn()
         .unwrap();
 
-    let tx_stream = recv.stdin.unwrap();
-    let rx_stream = recv.stdout.unwrap();
+    let tx_stream = recv.stdin.take().unwrap();
+    let rx_stream = recv.stdout.take().unwrap();
     let mut serial_dev = ChildStdInOut {
         stdin: tx_stream,
         stdout: rx_stream,
@@ -124,13 +120,12 @@ fn xmodem_send_standard() {
     let mut xmodem = Xmodem::new();
     let bytes = xmodem.send(&mut serial_dev, &mut &data[..]).unwrap();
     assert_eq!(bytes, data_len);
+    recv.wait().unwrap();
 
     let mut received_data = Vec::new();
     recv_file.read_to_end(&mut received_data).unwrap();
     let mut padded_data = data.clone();
-    for _ in 0..(128 - data.len() % 128) {
-        padded_data.push(0x1a);
-    }
+    padded_data.extend(std::iter::repeat_n(0x1a, 128 - data.len() % 128));
     assert_eq!(received_data, padded_data);
 }
 
@@ -141,7 +136,7 @@ fn xmodem_send_crc() {
     rng().fill_bytes(&mut data);
 
     let mut recv_file = NamedTempFile::new().unwrap();
-    let recv = Command::new("rb")
+    let mut recv = Command::new("rb")
         .arg("--xmodem")
         .arg("--with-crc")
         .arg(recv_file.path())
@@ -151,8 +146,8 @@ fn xmodem_send_crc() {
         .spawn()
         .unwrap();
 
-    let tx_stream = recv.stdin.unwrap();
-    let rx_stream = recv.stdout.unwrap();
+    let tx_stream = recv.stdin.take().unwrap();
+    let rx_stream = recv.stdout.take().unwrap();
     let mut serial_dev = ChildStdInOut {
         stdin: tx_stream,
         stdout: rx_stream,
@@ -160,13 +155,151 @@ fn xmodem_send_crc() {
 
     let mut xmodem = Xmodem::new();
     xmodem.send(&mut serial_dev, &mut &data[..]).unwrap();
+    recv.wait().unwrap();
 
     let mut received_data = Vec::new();
     recv_file.read_to_end(&mut received_data).unwrap();
     let mut padded_data = data.clone();
 
-    for _ in 0..(128 - data.len() % 128) {
-        padded_data.push(0x1a);
-    }
+    padded_data.extend(std::iter::repeat_n(0x1a, 128 - data.len() % 128));
     assert_eq!(received_data, padded_data);
 }
+
+#[test]
+fn ymodem_recv_batch() {
+    let mut files = Vec::new();
+    for data_len in [2000, 5000] {
+        let mut data = vec![0; data_len];
+        rng().fill_bytes(&mut data);
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(&data).unwrap();
+        files.push((file, data));
+    }
+
+    let mut send = Command::new("sb")
+        .args(files.iter().map(|(file, _)| file.path()))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+
+    let tx_stream = send.stdin.take().unwrap();
+    let rx_stream = send.stdout.take().unwrap();
+    let mut serial_dev = ChildStdInOut {
+        stdin: tx_stream,
+        stdout: rx_stream,
+    };
+
+    let mut ymodem = Ymodem::new();
+    for (file, data) in &files {
+        let expected_name = file.path().file_name().unwrap().to_str().unwrap();
+        let mut recv_data = Vec::new();
+        let bytes = ymodem
+            .recv_file(&mut serial_dev, |info| {
+                assert_eq!(info.name, expected_name);
+                assert_eq!(info.length, Some(data.len() as u64));
+                Ok(&mut recv_data)
+            })
+            .unwrap();
+        assert_eq!(bytes, Some(data.len()));
+        assert_eq!(&recv_data, data);
+    }
+    assert_eq!(
+        ymodem
+            .recv_file(&mut serial_dev, |_| Ok(io::sink()))
+            .unwrap(),
+        None
+    );
+    send.wait().unwrap();
+}
+
+#[test]
+fn zmodem_recv_batch() {
+    let mut files = Vec::new();
+    for data_len in [2000, 50000] {
+        let mut data = vec![0; data_len];
+        rng().fill_bytes(&mut data);
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(&data).unwrap();
+        files.push((file, data));
+    }
+
+    let mut send = Command::new("sz")
+        .args(files.iter().map(|(file, _)| file.path()))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+
+    let tx_stream = send.stdin.take().unwrap();
+    let rx_stream = send.stdout.take().unwrap();
+    let mut serial_dev = ChildStdInOut {
+        stdin: tx_stream,
+        stdout: rx_stream,
+    };
+
+    let mut zmodem = Zmodem::new();
+    for (file, data) in &files {
+        let expected_name = file.path().file_name().unwrap().to_str().unwrap();
+        let mut recv_data = Vec::new();
+        let bytes = zmodem
+            .recv_file(&mut serial_dev, |info| {
+                assert_eq!(info.name, expected_name);
+                assert_eq!(info.length, Some(data.len() as u64));
+                Ok(&mut recv_data)
+            })
+            .unwrap();
+        assert_eq!(bytes, Some(data.len()));
+        assert_eq!(&recv_data, data);
+    }
+    assert_eq!(
+        zmodem
+            .recv_file(&mut serial_dev, |_| Ok(io::sink()))
+            .unwrap(),
+        None
+    );
+    send.wait().unwrap();
+}
+
+#[test]
+fn zmodem_send_batch() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut files = Vec::new();
+    for (name, data_len) in [("first.bin", 3000), ("second.bin", 40000)] {
+        let mut data = vec![0; data_len];
+        rng().fill_bytes(&mut data);
+        files.push((name, data));
+    }
+
+    let mut recv = Command::new("rz")
+        .current_dir(dir.path())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+
+    let tx_stream = recv.stdin.take().unwrap();
+    let rx_stream = recv.stdout.take().unwrap();
+    let mut serial_dev = ChildStdInOut {
+        stdin: tx_stream,
+        stdout: rx_stream,
+    };
+
+    let mut zmodem = Zmodem::new();
+    for (name, data) in &files {
+        let info = FileInfo::new(name, data.len() as u64);
+        let bytes = zmodem
+            .send_file(&mut serial_dev, &info, &mut &data[..])
+            .unwrap();
+        assert_eq!(bytes, data.len());
+    }
+    zmodem.finish(&mut serial_dev).unwrap();
+    recv.wait().unwrap();
+
+    for (name, data) in &files {
+        assert_eq!(&std::fs::read(dir.path().join(name)).unwrap(), data);
+    }
+}
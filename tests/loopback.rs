@@ -4,55 +4,56 @@ extern crate tempfile;
 extern crate xmodem;
 
 use std::io::{self, ErrorKind, Read, Write};
-use std::sync::mpsc::{Receiver, Sender, channel};
-use xmodem::{BlockLength, Checksum, Xmodem};
+use std::time::{Duration, Instant};
+use xmodem::testing::{Endpoint, Link, loopback};
+use xmodem::{
+    BlockLength, CancelToken, Checksum, Clock, Crc32, Error, FileInfo, Phase, RetryCause,
+    RetryCounts, TransferObserver, TransferReport, Xmodem, Ymodem, Zmodem,
+};
 
-struct BidirectionalPipe {
-    pin: Receiver<u8>,
-    pout: Sender<u8>,
+/// Like `loopback`, but reads return `WouldBlock` instead of waiting.
+fn nonblocking_loopback() -> (Endpoint, Endpoint) {
+    let mut link = Link::new();
+    link.nonblocking = true;
+    link.open()
 }
 
-impl Read for BidirectionalPipe {
+/// Wraps a pipe and drops every 'C' written to it, like a sender that only
+/// knows the standard checksum would ignore them.
+struct NoCrcPipe(Endpoint);
+
+impl Read for NoCrcPipe {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        for idx in 0..buf.len() {
-            buf[idx] = match self.pin.recv() {
-                Ok(v) => v,
-                Err(e) => return Err(std::io::Error::new(ErrorKind::BrokenPipe, e)),
-            }
-        }
-        Ok(buf.len())
+        self.0.read(buf)
     }
 }
 
-impl Write for BidirectionalPipe {
+impl Write for NoCrcPipe {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        for v in buf {
-            self.pout.send(*v).unwrap();
-        }
+        let data: Vec<u8> = buf.iter().copied().filter(|&b| b != b'C').collect();
+        self.0.write(&data)?;
         Ok(buf.len())
     }
 
     fn flush(&mut self) -> io::Result<()> {
-        Ok(())
+        self.0.flush()
     }
 }
 
-fn loopback() -> (BidirectionalPipe, BidirectionalPipe) {
-    let (s1, r1) = channel();
-    let (s2, r2) = channel();
-    (
-        BidirectionalPipe { pin: r1, pout: s2 },
-        BidirectionalPipe { pin: r2, pout: s1 },
-    )
+/// A clock that advances by 100 milliseconds every time it is read.
+struct FakeClock(Duration);
+
+impl Clock for FakeClock {
+    fn now(&mut self) -> Duration {
+        self.0 += Duration::from_millis(100);
+        self.0
+    }
 }
 
 #[cfg(test)]
 fn xmodem_loopback(checksum_mode: Checksum, block_length: BlockLength, data_len: usize) {
-    let mut data_out = vec![0; data_len];
     // We don't really need the rng here
-    for idx in 0..data_len {
-        data_out[idx] = ((idx + 7) * 13) as u8;
-    }
+    let data_out: Vec<u8> = (0..data_len).map(|idx| ((idx + 7) * 13) as u8).collect();
     let (mut p1, mut p2) = loopback();
     let handle = std::thread::spawn(move || {
         let mut xmodem = Xmodem::new();
@@ -72,9 +73,7 @@ fn xmodem_loopback(checksum_mode: Checksum, block_length: BlockLength, data_len:
 
     // Pad output data to multiple of block length for comparison
     let bl = block_length as usize;
-    for _ in 0..(bl - data_len % bl) {
-        dato.push(0x1a);
-    }
+    dato.extend(std::iter::repeat_n(0x1a, bl - data_len % bl));
     let (dati, bytes_in) = handle2.join().unwrap();
     assert_eq!(dato.len(), dati.len());
     assert_eq!(dato, dati);
@@ -101,3 +100,1226 @@ fn xmodem_loopback_long_crc() {
     // make sure we wrap block counter
     xmodem_loopback(Checksum::CRC16, BlockLength::Standard, 50000);
 }
+
+#[test]
+fn xmodem_loopback_crc32_trailer() {
+    let data_out: Vec<u8> = (0..5000).map(|idx| (idx * 17) as u8).collect();
+    let (mut p1, mut p2) = loopback();
+    let mut xmodem = Xmodem::new();
+    xmodem.block_length = BlockLength::OneK;
+    xmodem.block_check = Some(&Crc32);
+    xmodem.trim_padding = true;
+
+    let expected = data_out.clone();
+    let handle = std::thread::spawn(move || xmodem.send(&mut p1, &mut &data_out[..]).unwrap());
+    let mut data_in = Vec::new();
+    xmodem.recv(&mut p2, &mut data_in, Checksum::CRC16).unwrap();
+
+    assert_eq!(handle.join().unwrap(), expected.len());
+    assert_eq!(data_in, expected);
+}
+
+#[test]
+fn xmodem_loopback_retransmit_on_nak() {
+    let data_len = 2000;
+    let data_out: Vec<u8> = (0..data_len).map(|idx| ((idx + 7) * 13) as u8).collect();
+
+    // Each CRC16 block is 3 header bytes, 128 data bytes and 2 CRC bytes.
+    // Corrupt the data of the 4th block, its first retransmission, and
+    // one later block.
+    let block = 3 + 128 + 2;
+    let mut link = Link::new();
+    link.a_to_b.corrupt_at = vec![3 * block + 10, 4 * block + 20, 10 * block + 100];
+    let (mut p1, mut p2) = link.open();
+
+    let expected = data_out.clone();
+    let handle = std::thread::spawn(move || {
+        let mut xmodem = Xmodem::new();
+        xmodem.send(&mut p1, &mut &data_out[..]).unwrap()
+    });
+    let handle2 = std::thread::spawn(move || {
+        let mut xmodem = Xmodem::new();
+        let mut data_in = Vec::new();
+        xmodem.recv(&mut p2, &mut data_in, Checksum::CRC16).unwrap();
+        data_in
+    });
+
+    assert_eq!(handle.join().unwrap(), data_len);
+    let data_in = handle2.join().unwrap();
+    assert_eq!(&data_in[..data_len], &expected[..]);
+    assert!(data_in[data_len..].iter().all(|&b| b == 0x1a));
+    assert_eq!(data_in.len(), (data_len + 127) & !127);
+}
+
+#[test]
+fn ymodem_loopback_batch() {
+    let files: Vec<(String, Vec<u8>)> = vec![
+        (
+            "first.bin".into(),
+            (0..3000).map(|idx| (idx * 13) as u8).collect(),
+        ),
+        ("empty.bin".into(), Vec::new()),
+        ("second.bin".into(), (0..130).map(|idx| idx as u8).collect()),
+    ];
+    let (mut p1, mut p2) = loopback();
+
+    let sent = files.clone();
+    let handle = std::thread::spawn(move || {
+        let mut ymodem = Ymodem::new();
+        for (name, data) in &sent {
+            let mut info = FileInfo::new(name, data.len() as u64);
+            info.mtime = Some(1_600_000_000);
+            info.mode = Some(0o644);
+            let bytes = ymodem.send_file(&mut p1, &info, &mut &data[..]).unwrap();
+            assert_eq!(bytes, data.len());
+        }
+        ymodem.finish(&mut p1).unwrap();
+    });
+    let handle2 = std::thread::spawn(move || {
+        let mut ymodem = Ymodem::new();
+        let mut received = Vec::new();
+        loop {
+            let mut name = String::new();
+            let mut data = Vec::new();
+            let bytes = ymodem.recv_file(&mut p2, |info| {
+                assert_eq!(info.mtime, Some(1_600_000_000));
+                assert_eq!(info.mode, Some(0o644));
+                name = info.name.to_string();
+                Ok(&mut data)
+            });
+            match bytes.unwrap() {
+                Some(bytes) => {
+                    assert_eq!(bytes, data.len());
+                    received.push((name, data));
+                }
+                None => return received,
+            }
+        }
+    });
+
+    handle.join().unwrap();
+    assert_eq!(handle2.join().unwrap(), files);
+}
+
+#[test]
+fn xmodem_loopback_streaming() {
+    let data_out: Vec<u8> = (0..5000).map(|idx| (idx * 11) as u8).collect();
+    let (mut p1, mut p2) = loopback();
+    let mut xmodem = Xmodem::new();
+    xmodem.block_length = BlockLength::OneK;
+    xmodem.streaming = true;
+    xmodem.trim_padding = true;
+
+    let expected = data_out.clone();
+    let handle = std::thread::spawn(move || {
+        let mut report = TransferReport::default();
+        xmodem
+            .send_with_observer(&mut p1, &mut &data_out[..], &mut report)
+            .unwrap();
+        report
+    });
+    let mut data_in = Vec::new();
+    xmodem
+        .recv(&mut p2, &mut data_in, Checksum::Standard)
+        .unwrap();
+
+    let report = handle.join().unwrap();
+    assert_eq!(report.checksum, Some(Checksum::CRC16));
+    assert_eq!(report.blocks, 5);
+    assert_eq!(data_in, expected);
+}
+
+#[test]
+fn ymodem_loopback_streaming() {
+    let files: Vec<(String, Vec<u8>)> = vec![
+        (
+            "a.bin".into(),
+            (0..2500).map(|idx| (idx * 3) as u8).collect(),
+        ),
+        ("b.bin".into(), (0..10).collect()),
+    ];
+    let (mut p1, mut p2) = loopback();
+
+    let sent = files.clone();
+    let handle = std::thread::spawn(move || {
+        let mut ymodem = Ymodem::new();
+        for (name, data) in &sent {
+            let info = FileInfo::new(name, data.len() as u64);
+            ymodem.send_file(&mut p1, &info, &mut &data[..]).unwrap();
+        }
+        ymodem.finish(&mut p1).unwrap();
+    });
+
+    let mut ymodem = Ymodem::new();
+    ymodem.streaming = true;
+    let mut received = Vec::new();
+    loop {
+        let mut name = String::new();
+        let mut data = Vec::new();
+        let bytes = ymodem.recv_file(&mut p2, |info| {
+            name = info.name.to_string();
+            Ok(&mut data)
+        });
+        if bytes.unwrap().is_none() {
+            break;
+        }
+        received.push((name, data));
+    }
+
+    handle.join().unwrap();
+    assert_eq!(received, files);
+}
+
+/// Sends `files` with ZMODEM over `dev`, returning the bytes read from each.
+fn spawn_zmodem_sender<D: Read + Write + Send + 'static>(
+    mut dev: D,
+    files: Vec<(String, Vec<u8>)>,
+) -> std::thread::JoinHandle<Vec<usize>> {
+    std::thread::spawn(move || {
+        let mut zmodem = Zmodem::new();
+        let sent = files
+            .iter()
+            .map(|(name, data)| {
+                let info = FileInfo::new(name, data.len() as u64);
+                zmodem.send_file(&mut dev, &info, &mut &data[..]).unwrap()
+            })
+            .collect();
+        zmodem.finish(&mut dev).unwrap();
+        sent
+    })
+}
+
+/// Receives ZMODEM files until the end of the batch.
+fn zmodem_recv_all(zmodem: &mut Zmodem, dev: &mut Endpoint) -> Vec<(String, Vec<u8>)> {
+    let mut received = Vec::new();
+    loop {
+        let mut name = String::new();
+        let mut data = Vec::new();
+        let bytes = zmodem.recv_file(dev, |info| {
+            name = info.name.to_string();
+            Ok(&mut data)
+        });
+        match bytes.unwrap() {
+            Some(bytes) => {
+                assert_eq!(bytes, data.len());
+                received.push((name, data));
+            }
+            None => return received,
+        }
+    }
+}
+
+#[test]
+fn zmodem_loopback_batch() {
+    let files: Vec<(String, Vec<u8>)> = vec![
+        // Every byte value, so that all of the escaping is exercised
+        (
+            "first.bin".into(),
+            (0..30000).map(|idx| (idx * 7) as u8).collect(),
+        ),
+        ("empty.bin".into(), Vec::new()),
+        (
+            "second.bin".into(),
+            (0..8192).map(|idx| idx as u8).collect(),
+        ),
+    ];
+    let (p1, mut p2) = loopback();
+
+    let handle = spawn_zmodem_sender(p1, files.clone());
+    let received = zmodem_recv_all(&mut Zmodem::new(), &mut p2);
+
+    assert_eq!(handle.join().unwrap(), vec![30000, 0, 8192]);
+    assert_eq!(received, files);
+}
+
+#[test]
+fn zmodem_loopback_recovers_from_errors() {
+    let data: Vec<u8> = (0..40000).map(|idx| (idx * 13) as u8).collect();
+    let mut link = Link::new();
+    link.a_to_b.corrupt_at = vec![3000, 3001, 9500, 25000];
+    let (p1, mut p2) = link.open();
+
+    let files = vec![("noisy.bin".to_string(), data)];
+    let handle = spawn_zmodem_sender(p1, files.clone());
+    // Only CRC-16 on the way back
+    let mut zmodem = Zmodem::new();
+    zmodem.crc32 = false;
+    let received = zmodem_recv_all(&mut zmodem, &mut p2);
+
+    assert_eq!(handle.join().unwrap(), vec![40000]);
+    assert_eq!(received, files);
+}
+
+#[test]
+fn zmodem_loopback_resume() {
+    let data: Vec<u8> = (0..20000).map(|idx| (idx * 3) as u8).collect();
+    let (p1, mut p2) = loopback();
+
+    let handle = spawn_zmodem_sender(p1, vec![("resumed.bin".into(), data.clone())]);
+    let mut zmodem = Zmodem::new();
+    let mut rest = Vec::new();
+    let bytes = zmodem
+        .resume_file(&mut p2, |info| {
+            assert_eq!(info.length, Some(20000));
+            Ok((&mut rest, 12345))
+        })
+        .unwrap();
+    assert_eq!(bytes, Some(20000 - 12345));
+    assert_eq!(rest, &data[12345..]);
+    assert_eq!(zmodem.recv_file(&mut p2, |_| Ok(io::sink())).unwrap(), None);
+
+    // The sender skips what the receiver already has
+    assert_eq!(handle.join().unwrap(), vec![20000]);
+}
+
+#[test]
+fn xmodem_loopback_nonblocking() {
+    let data_out: Vec<u8> = (0..3000).map(|idx| (idx * 13) as u8).collect();
+    let (mut p1, mut p2) = nonblocking_loopback();
+
+    let expected = data_out.clone();
+    let handle = std::thread::spawn(move || {
+        let mut xmodem = Xmodem::new();
+        xmodem.block_length = BlockLength::OneK;
+        xmodem.send(&mut p1, &mut &data_out[..]).unwrap()
+    });
+    let mut data_in = Vec::new();
+    Xmodem::new()
+        .recv(&mut p2, &mut data_in, Checksum::CRC16)
+        .unwrap();
+
+    assert_eq!(handle.join().unwrap(), 3000);
+    assert_eq!(&data_in[..3000], &expected[..]);
+}
+
+#[test]
+fn xmodem_send_handshake_timeout() {
+    // Nobody answers, and the device never reports a timeout itself.
+    let (mut p1, _p2) = nonblocking_loopback();
+    let mut xmodem = Xmodem::new();
+    xmodem.max_errors = 3;
+    xmodem.handshake_timeout = Duration::from_millis(20);
+
+    let start = Instant::now();
+    let res = xmodem.send(&mut p1, &mut &[0u8; 10][..]);
+    assert!(matches!(res, Err(Error::ExhaustedRetries(_))));
+    assert!(start.elapsed() >= Duration::from_millis(60));
+}
+
+#[test]
+fn xmodem_recv_with_clock() {
+    let (mut p1, _p2) = nonblocking_loopback();
+    let mut xmodem = Xmodem::new();
+    xmodem.max_errors = 2;
+    xmodem.handshake_timeout = Duration::from_secs(5);
+
+    let mut clock = FakeClock(Duration::ZERO);
+    let res = xmodem.recv_with_clock(&mut p1, &mut Vec::new(), Checksum::CRC16, &mut clock);
+    assert!(matches!(res, Err(Error::ExhaustedRetries(_))));
+    assert!(clock.0 >= Duration::from_secs(10));
+}
+
+/// A writer that fails once it has accepted `limit` bytes.
+struct FailingWriter {
+    limit: usize,
+}
+
+impl Write for FailingWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.len() > self.limit {
+            return Err(io::Error::other("disk full"));
+        }
+        self.limit -= buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn xmodem_send_canceled_by_receiver() {
+    let data_out: Vec<u8> = (0..5000).map(|idx| idx as u8).collect();
+    let (mut p1, mut p2) = loopback();
+
+    let handle = std::thread::spawn(move || {
+        let mut xmodem = Xmodem::new();
+        xmodem.send(&mut p1, &mut &data_out[..])
+    });
+    let mut xmodem = Xmodem::new();
+    let res = xmodem.recv(&mut p2, &mut FailingWriter { limit: 1000 }, Checksum::CRC16);
+    assert!(matches!(res, Err(Error::Io(_))));
+
+    let res = handle.join().unwrap();
+    assert!(matches!(res, Err(Error::Canceled(_))));
+}
+
+#[test]
+fn xmodem_streaming_canceled_by_receiver() {
+    let (mut p1, mut p2) = loopback();
+
+    let handle = std::thread::spawn(move || {
+        let mut xmodem = Xmodem::new();
+        xmodem.block_length = BlockLength::OneK;
+        xmodem.send_with_report(&mut p1, &mut &[0x42; 5000][..])
+    });
+    let mut xmodem = Xmodem::new();
+    xmodem.streaming = true;
+    let res = xmodem.recv(&mut p2, &mut FailingWriter { limit: 100 }, Checksum::CRC16);
+    assert!(matches!(res, Err(Error::Io(_))));
+
+    // The sender can't check for the cancel without waiting, so it finds
+    // it in place of the ACK of the EOT
+    let (report, res) = handle.join().unwrap();
+    assert!(matches!(res, Err(Error::Canceled(Phase::Eot))));
+    assert_eq!(report.blocks, 5);
+}
+
+#[test]
+fn xmodem_streaming_with_read_timeout() {
+    let mut link = Link::new();
+    link.read_timeout = Some(Duration::from_millis(200));
+    let (mut p1, mut p2) = link.open();
+    let data_out: Vec<u8> = (0..20 * 1024).map(|idx| (idx * 7) as u8).collect();
+
+    let expected = data_out.clone();
+    let handle = std::thread::spawn(move || {
+        let mut xmodem = Xmodem::new();
+        xmodem.block_length = BlockLength::OneK;
+        xmodem.send(&mut p1, &mut &data_out[..])
+    });
+    let mut xmodem = Xmodem::new();
+    xmodem.streaming = true;
+    let start = Instant::now();
+    let mut data_in = Vec::new();
+    xmodem.recv(&mut p2, &mut data_in, Checksum::CRC16).unwrap();
+
+    // Nothing waits for the read timeout between the blocks
+    assert_eq!(handle.join().unwrap().unwrap(), 20 * 1024);
+    assert!(start.elapsed() < Duration::from_secs(1));
+    assert_eq!(data_in, expected);
+}
+
+#[test]
+fn xmodem_loopback_lost_acks() {
+    // 300 blocks, so the block numbers wrap around
+    let data_len = 300 * 128;
+    let data_out: Vec<u8> = (0..data_len).map(|idx| (idx / 128) as u8).collect();
+
+    // The receiver writes 'C' and then one byte per block. Turn the ACKs of
+    // a few blocks (including 255 and 256) into garbage, so the sender
+    // repeats them.
+    let mut link = Link::new();
+    link.b_to_a.corrupt_at = vec![3, 255, 257, 290];
+    let (mut p1, mut p2) = link.open();
+
+    let expected = data_out.clone();
+    let handle = std::thread::spawn(move || {
+        let mut xmodem = Xmodem::new();
+        xmodem.send(&mut p1, &mut &data_out[..]).unwrap()
+    });
+    let mut data_in = Vec::new();
+    let bytes = Xmodem::new()
+        .recv(&mut p2, &mut data_in, Checksum::CRC16)
+        .unwrap();
+
+    assert_eq!(handle.join().unwrap(), data_len);
+    assert_eq!(bytes, data_len);
+    assert_eq!(data_in, expected);
+}
+
+#[test]
+fn xmodem_loopback_corrupted_header() {
+    let data_out: Vec<u8> = (0..1000).map(|idx| (idx * 3) as u8).collect();
+
+    // Damage the sequence number complement of block 3, which is the third
+    // byte of the third 133-byte packet
+    let mut link = Link::new();
+    link.a_to_b.corrupt_at = vec![2 * 133 + 2];
+    let (mut p1, mut p2) = link.open();
+
+    let expected = data_out.clone();
+    let handle = std::thread::spawn(move || {
+        let (report, res) = Xmodem::new().send_with_report(&mut p1, &mut &data_out[..]);
+        res.unwrap();
+        report
+    });
+    let mut data_in = Vec::new();
+    Xmodem::new()
+        .recv(&mut p2, &mut data_in, Checksum::CRC16)
+        .unwrap();
+
+    let report = handle.join().unwrap();
+    assert_eq!(report.bytes, 1000);
+    assert_eq!(report.retries.nak, 1);
+    assert_eq!(&data_in[..1000], &expected[..]);
+}
+
+#[test]
+fn xmodem_loopback_auto_checksum_fallback() {
+    let data_out: Vec<u8> = (0..1000).map(|idx| (idx * 3) as u8).collect();
+    let (mut p1, p2) = nonblocking_loopback();
+    let mut p2 = NoCrcPipe(p2);
+
+    let expected = data_out.clone();
+    let handle = std::thread::spawn(move || {
+        let mut xmodem = Xmodem::new();
+        xmodem.send(&mut p1, &mut &data_out[..]).unwrap()
+    });
+    let mut xmodem = Xmodem::new();
+    xmodem.handshake_timeout = Duration::from_millis(20);
+    let mut data_in = Vec::new();
+    xmodem.recv(&mut p2, &mut data_in, Checksum::Auto).unwrap();
+
+    assert_eq!(handle.join().unwrap(), 1000);
+    assert_eq!(&data_in[..1000], &expected[..]);
+}
+
+/// Sends `data` from another thread, returning the handle to join it.
+fn spawn_sender(mut dev: Endpoint, data: Vec<u8>) -> std::thread::JoinHandle<usize> {
+    std::thread::spawn(move || Xmodem::new().send(&mut dev, &mut &data[..]).unwrap())
+}
+
+#[test]
+fn xmodem_loopback_trim_padding() {
+    // The first block ends in what looks like padding, which must be kept
+    let mut data_out = vec![0x55; 300];
+    data_out[120..128].fill(0x1a);
+    let (p1, mut p2) = loopback();
+    let handle = spawn_sender(p1, data_out.clone());
+
+    let mut xmodem = Xmodem::new();
+    xmodem.trim_padding = true;
+    let mut data_in = Vec::new();
+    let bytes_in = xmodem.recv(&mut p2, &mut data_in, Checksum::CRC16).unwrap();
+
+    assert_eq!(handle.join().unwrap(), 300);
+    assert_eq!(bytes_in, 300);
+    assert_eq!(data_in, data_out);
+}
+
+#[test]
+fn xmodem_loopback_recv_exact() {
+    // The message itself ends in the pad byte
+    let mut data_out: Vec<u8> = (0..1000).map(|idx| (idx * 7) as u8).collect();
+    data_out[990..].fill(0x1a);
+    let (p1, mut p2) = loopback();
+    let handle = spawn_sender(p1, data_out.clone());
+
+    let mut data_in = Vec::new();
+    let bytes_in = Xmodem::new()
+        .recv_exact(&mut p2, &mut data_in, Checksum::CRC16, 1000)
+        .unwrap();
+    assert_eq!(handle.join().unwrap(), 1000);
+    assert_eq!(bytes_in, 1000);
+    assert_eq!(data_in, data_out);
+
+    // A transmission shorter than expected returns the true length
+    let (p1, mut p2) = loopback();
+    let handle = spawn_sender(p1, data_out[..200].to_vec());
+    let mut data_in = Vec::new();
+    let bytes_in = Xmodem::new()
+        .recv_exact(&mut p2, &mut data_in, Checksum::CRC16, 1000)
+        .unwrap();
+    assert_eq!(handle.join().unwrap(), 200);
+    assert_eq!(bytes_in, 256);
+    assert_eq!(data_in.len(), 256);
+}
+
+#[derive(Debug, PartialEq)]
+enum Progress {
+    Handshake(Checksum, BlockLength),
+    Block(u32, u64, usize),
+    Retry(RetryCause),
+    Finished(usize),
+}
+
+/// Records everything it is notified of.
+#[derive(Default)]
+struct Recorder(Vec<Progress>);
+
+impl TransferObserver for Recorder {
+    fn handshake(&mut self, checksum: Checksum, block_length: BlockLength) {
+        self.0.push(Progress::Handshake(checksum, block_length));
+    }
+
+    fn block(&mut self, blockno: u32, offset: u64, len: usize) {
+        self.0.push(Progress::Block(blockno, offset, len));
+    }
+
+    fn retry(&mut self, cause: RetryCause) {
+        self.0.push(Progress::Retry(cause));
+    }
+
+    fn finished(&mut self, result: &Result<usize, Error>) {
+        self.0.push(Progress::Finished(*result.as_ref().unwrap()));
+    }
+}
+
+#[test]
+fn xmodem_loopback_observer() {
+    let data_out: Vec<u8> = (0..300).map(|idx| (idx * 5) as u8).collect();
+    // Damage the second block on its way to the receiver
+    let mut link = Link::new();
+    link.a_to_b.corrupt_at = vec![133 + 10];
+    let (mut p1, mut p2) = link.open();
+
+    let handle = std::thread::spawn(move || {
+        let mut observer = Recorder::default();
+        Xmodem::new()
+            .send_with_observer(&mut p1, &mut &data_out[..], &mut observer)
+            .unwrap();
+        observer.0
+    });
+    let mut observer = Recorder::default();
+    let mut data_in = Vec::new();
+    Xmodem::new()
+        .recv_with_observer(&mut p2, &mut data_in, Checksum::CRC16, &mut observer)
+        .unwrap();
+
+    assert_eq!(
+        handle.join().unwrap(),
+        [
+            Progress::Handshake(Checksum::CRC16, BlockLength::Standard),
+            Progress::Block(1, 0, 128),
+            Progress::Retry(RetryCause::Nak),
+            Progress::Block(2, 128, 128),
+            Progress::Block(3, 256, 44),
+            Progress::Finished(300),
+        ]
+    );
+    assert_eq!(
+        observer.0,
+        [
+            Progress::Handshake(Checksum::CRC16, BlockLength::Standard),
+            Progress::Block(1, 0, 128),
+            Progress::Retry(RetryCause::Checksum),
+            Progress::Block(2, 128, 128),
+            Progress::Block(3, 256, 128),
+            Progress::Finished(384),
+        ]
+    );
+}
+
+#[test]
+fn xmodem_loopback_report() {
+    let data_out: Vec<u8> = (0..3000).map(|idx| (idx * 11) as u8).collect();
+    // Damage a block on its way to the receiver, and an ACK on its way back
+    let mut link = Link::new();
+    link.a_to_b.corrupt_at = vec![1029 + 100];
+    link.b_to_a.corrupt_at = vec![3];
+    let (mut p1, mut p2) = link.open();
+
+    let handle = std::thread::spawn(move || {
+        let mut xmodem = Xmodem::new();
+        xmodem.block_length = BlockLength::OneK;
+        let (report, res) = xmodem.send_with_report(&mut p1, &mut &data_out[..]);
+        res.unwrap();
+        report
+    });
+    let mut data_in = Vec::new();
+    let (received, res) = Xmodem::new().recv_with_report(&mut p2, &mut data_in, Checksum::CRC16);
+    res.unwrap();
+    let sent = handle.join().unwrap();
+
+    assert_eq!(
+        sent,
+        TransferReport {
+            blocks: 3,
+            bytes: 3000,
+            retries: RetryCounts {
+                nak: 1,
+                garbage: 1,
+                ..RetryCounts::default()
+            },
+            checksum: Some(Checksum::CRC16),
+            block_length: Some(BlockLength::OneK),
+            elapsed: sent.elapsed,
+        }
+    );
+    assert_eq!(
+        received,
+        TransferReport {
+            blocks: 3,
+            bytes: 3072,
+            retries: RetryCounts {
+                checksum: 1,
+                sequence: 1,
+                ..RetryCounts::default()
+            },
+            checksum: Some(Checksum::CRC16),
+            block_length: Some(BlockLength::OneK),
+            elapsed: received.elapsed,
+        }
+    );
+    assert_eq!(received.retries.total(), 2);
+    assert!(sent.elapsed > Duration::ZERO);
+    assert!(sent.throughput().unwrap() > 0.0);
+}
+
+#[test]
+fn xmodem_report_on_failure() {
+    let data_out: Vec<u8> = (0..5000).map(|idx| idx as u8).collect();
+    let (mut p1, mut p2) = loopback();
+
+    let handle = std::thread::spawn(move || {
+        let mut xmodem = Xmodem::new();
+        xmodem.send(&mut p1, &mut &data_out[..])
+    });
+    let (report, res) = Xmodem::new().recv_with_report(
+        &mut p2,
+        &mut FailingWriter { limit: 1000 },
+        Checksum::CRC16,
+    );
+    assert!(matches!(res, Err(Error::Io(_))));
+    assert!(matches!(handle.join().unwrap(), Err(Error::Canceled(_))));
+
+    // Seven blocks fit in the writer, the eighth doesn't
+    assert_eq!(report.blocks, 7);
+    assert_eq!(report.bytes, 7 * 128);
+    assert_eq!(report.checksum, Some(Checksum::CRC16));
+    assert!(report.elapsed > Duration::ZERO);
+}
+
+/// Cancels a token once the given block has been transferred.
+#[test]
+fn xmodem_recv_aborted_while_waiting() {
+    static STOP: CancelToken = CancelToken::new();
+    let mut link = Link::new();
+    link.read_timeout = Some(Duration::from_millis(100));
+    // Nobody answers on the other end
+    let (mut p1, mut p2) = link.open();
+
+    let start = Instant::now();
+    let stopper = std::thread::spawn(|| {
+        std::thread::sleep(Duration::from_millis(200));
+        STOP.cancel();
+    });
+    let res =
+        Xmodem::new().recv_with_observer(&mut p2, &mut Vec::new(), Checksum::CRC16, &mut &STOP);
+    stopper.join().unwrap();
+
+    // Long before the 10 second handshake timeout
+    assert!(matches!(res, Err(Error::Aborted)));
+    assert!(start.elapsed() < Duration::from_secs(1));
+    let mut sent = Vec::new();
+    p1.read_to_end(&mut sent).unwrap_or_default();
+    assert!(sent.ends_with(&[0x18; 8]));
+}
+
+struct CancelAfter<'a>(u32, &'a CancelToken);
+
+impl TransferObserver for CancelAfter<'_> {
+    fn block(&mut self, blockno: u32, _offset: u64, _len: usize) {
+        if blockno == self.0 {
+            self.1.cancel();
+        }
+    }
+}
+
+#[test]
+fn xmodem_send_aborted_by_token() {
+    static STOP: CancelToken = CancelToken::new();
+    let data_out: Vec<u8> = (0..3000).map(|idx| idx as u8).collect();
+    let (mut p1, mut p2) = loopback();
+
+    let handle = std::thread::spawn(move || {
+        Xmodem::new().send_with_observer(&mut p1, &mut &data_out[..], &mut &STOP)
+    });
+    // The receiver stops the sender from its own thread
+    let mut data_in = Vec::new();
+    let res = Xmodem::new().recv_with_observer(
+        &mut p2,
+        &mut data_in,
+        Checksum::CRC16,
+        &mut CancelAfter(3, &STOP),
+    );
+
+    assert!(matches!(handle.join().unwrap(), Err(Error::Aborted)));
+    assert!(matches!(res, Err(Error::Canceled(_))));
+    assert!(data_in.len() >= 3 * 128);
+}
+
+#[test]
+fn xmodem_recv_aborted_by_token() {
+    let data_out: Vec<u8> = (0..3000).map(|idx| idx as u8).collect();
+    let (mut p1, mut p2) = loopback();
+
+    let handle = std::thread::spawn(move || Xmodem::new().send(&mut p1, &mut &data_out[..]));
+    let token = CancelToken::new();
+    let mut report = TransferReport::default();
+    let mut data_in = Vec::new();
+    let res = Xmodem::new().recv_with_observer(
+        &mut p2,
+        &mut data_in,
+        Checksum::CRC16,
+        &mut (CancelAfter(5, &token), (&token, &mut report)),
+    );
+
+    assert!(matches!(res, Err(Error::Aborted)));
+    assert!(matches!(handle.join().unwrap(), Err(Error::Canceled(_))));
+    assert_eq!(report.blocks, 5);
+    assert_eq!(data_in.len(), 5 * 128);
+}
+
+/// A peer that answers with scripted bytes, times out once the script is
+/// exhausted, and records everything written to it.
+struct ScriptedPeer {
+    script: std::collections::VecDeque<u8>,
+    wire: Vec<u8>,
+}
+
+impl ScriptedPeer {
+    fn new(script: &[u8]) -> Self {
+        ScriptedPeer {
+            script: script.iter().copied().collect(),
+            wire: Vec::new(),
+        }
+    }
+}
+
+impl Read for ScriptedPeer {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.script.pop_front() {
+            Some(b) if !buf.is_empty() => {
+                buf[0] = b;
+                Ok(1)
+            }
+            _ => Err(io::Error::new(ErrorKind::TimedOut, "script exhausted")),
+        }
+    }
+}
+
+impl Write for ScriptedPeer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.wire.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+struct FailingReader;
+
+impl Read for FailingReader {
+    fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+        Err(io::Error::other("disk on fire"))
+    }
+}
+
+fn quick_xmodem() -> Xmodem {
+    let mut xmodem = Xmodem::new();
+    xmodem.max_errors = 3;
+    xmodem.handshake_timeout = Duration::from_millis(1);
+    xmodem.ack_timeout = Duration::from_millis(1);
+    xmodem.eot_timeout = Duration::from_millis(1);
+    xmodem
+}
+
+#[test]
+fn xmodem_send_cancels_on_wire_after_max_errors() {
+    let mut peer = ScriptedPeer::new(b"C\x15\x15\x15");
+    let mut xmodem = quick_xmodem();
+    xmodem.cancel_backspaces = true;
+    let res = xmodem.send(&mut peer, &mut &[1u8; 100][..]);
+
+    assert!(matches!(res, Err(Error::ExhaustedRetries(_))));
+    // Three copies of the block, then the cancel sequence
+    let frame_len = 3 + 128 + 2;
+    assert_eq!(peer.wire.len(), 3 * frame_len + 16);
+    assert_eq!(&peer.wire[3 * frame_len..][..8], &[0x18; 8]);
+    assert_eq!(&peer.wire[3 * frame_len + 8..], &[0x08; 8]);
+}
+
+#[test]
+fn xmodem_send_cancels_on_wire_when_stream_fails() {
+    let mut peer = ScriptedPeer::new(b"C");
+    let res = quick_xmodem().send(&mut peer, &mut FailingReader);
+
+    assert!(matches!(res, Err(Error::Io(_))));
+    assert_eq!(peer.wire, [0x18; 8]);
+}
+
+#[test]
+fn xmodem_recv_cancels_on_wire_after_max_errors() {
+    let mut peer = ScriptedPeer::new(b"");
+    let mut data_in = Vec::new();
+    let res = quick_xmodem().recv(&mut peer, &mut data_in, Checksum::CRC16);
+
+    assert!(matches!(res, Err(Error::ExhaustedRetries(_))));
+    let mut expected = b"CCC".to_vec();
+    expected.extend_from_slice(&[0x18; 8]);
+    assert_eq!(peer.wire, expected);
+}
+
+#[test]
+fn xmodem_loopback_downgrade_short_tail() {
+    let data_out: Vec<u8> = (0..2300).map(|idx| (idx * 7) as u8).collect();
+    let (mut p1, mut p2) = loopback();
+
+    let expected = data_out.clone();
+    let handle = std::thread::spawn(move || {
+        let mut xmodem = Xmodem::new();
+        xmodem.block_length = BlockLength::OneK;
+        xmodem.downgrade_blocks = true;
+        let (report, res) = xmodem.send_with_report(&mut p1, &mut &data_out[..]);
+        res.unwrap();
+        report
+    });
+    let mut data_in = Vec::new();
+    Xmodem::new()
+        .recv(&mut p2, &mut data_in, Checksum::CRC16)
+        .unwrap();
+
+    let report = handle.join().unwrap();
+    assert_eq!(report.bytes, 2300);
+    // Two 1K blocks, then two 128-byte blocks for the last 252 bytes
+    assert_eq!(report.blocks, 4);
+    assert_eq!(data_in.len(), 2 * 1024 + 2 * 128);
+    assert_eq!(&data_in[..2300], &expected[..]);
+}
+
+/// Wraps a pipe that damages every 1K packet, like a link whose buffers
+/// are too small for them.
+struct NoOneKPipe(Endpoint);
+
+impl Read for NoOneKPipe {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl Write for NoOneKPipe {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.len() > 1024 {
+            let mut damaged = buf.to_vec();
+            damaged[500] ^= 0xff;
+            return self.0.write(&damaged);
+        }
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+#[test]
+fn xmodem_loopback_downgrade_after_errors() {
+    let data_out: Vec<u8> = (0..3000).map(|idx| (idx * 7) as u8).collect();
+    let (p1, mut p2) = loopback();
+    let mut p1 = NoOneKPipe(p1);
+
+    let expected = data_out.clone();
+    let handle = std::thread::spawn(move || {
+        let mut xmodem = Xmodem::new();
+        xmodem.block_length = BlockLength::OneK;
+        xmodem.downgrade_blocks = true;
+        let (report, res) = xmodem.send_with_report(&mut p1, &mut &data_out[..]);
+        res.unwrap();
+        report
+    });
+    let mut data_in = Vec::new();
+    Xmodem::new()
+        .recv(&mut p2, &mut data_in, Checksum::CRC16)
+        .unwrap();
+
+    let report = handle.join().unwrap();
+    assert_eq!(report.bytes, 3000);
+    assert_eq!(report.blocks, 24);
+    assert_eq!(report.retries.nak, 3);
+    assert_eq!(data_in.len(), 24 * 128);
+    assert_eq!(&data_in[..3000], &expected[..]);
+}
+
+/// A reader that returns between 1 and 7 bytes per call, and is
+/// occasionally interrupted, like a pipe or a decompressor.
+struct TricklingReader<'a> {
+    data: &'a [u8],
+    calls: usize,
+}
+
+impl Read for TricklingReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.calls += 1;
+        if self.calls.is_multiple_of(11) {
+            return Err(ErrorKind::Interrupted.into());
+        }
+        let n = (self.calls % 7 + 1).min(buf.len()).min(self.data.len());
+        buf[..n].copy_from_slice(&self.data[..n]);
+        self.data = &self.data[n..];
+        Ok(n)
+    }
+}
+
+fn xmodem_loopback_short_reads(block_length: BlockLength) {
+    let data_len = 3000;
+    let data_out: Vec<u8> = (0..data_len).map(|idx| (idx * 13) as u8).collect();
+    let (mut p1, mut p2) = loopback();
+
+    let expected = data_out.clone();
+    let handle = std::thread::spawn(move || {
+        let mut xmodem = Xmodem::new();
+        xmodem.block_length = block_length;
+        let mut reader = TricklingReader {
+            data: &data_out,
+            calls: 0,
+        };
+        xmodem.send(&mut p1, &mut reader).unwrap()
+    });
+    let mut data_in = Vec::new();
+    Xmodem::new()
+        .recv(&mut p2, &mut data_in, Checksum::CRC16)
+        .unwrap();
+
+    assert_eq!(handle.join().unwrap(), data_len);
+    let bl = block_length as usize;
+    assert_eq!(data_in.len(), data_len.div_ceil(bl) * bl);
+    assert_eq!(&data_in[..data_len], &expected[..]);
+    assert!(data_in[data_len..].iter().all(|&b| b == 0x1a));
+}
+
+#[test]
+fn xmodem_loopback_short_reads_standard() {
+    xmodem_loopback_short_reads(BlockLength::Standard);
+}
+
+#[test]
+fn xmodem_loopback_short_reads_onek() {
+    xmodem_loopback_short_reads(BlockLength::OneK);
+}
+
+#[test]
+fn xmodem_loopback_caller_buffers() {
+    static mut SEND_BUFFER: [u8; BlockLength::OneK.send_buffer_len()] =
+        [0; BlockLength::OneK.send_buffer_len()];
+    let data_out: Vec<u8> = (0..3000).map(|idx| (idx * 11) as u8).collect();
+    let (mut p1, mut p2) = loopback();
+
+    let expected = data_out.clone();
+    let handle = std::thread::spawn(move || {
+        let mut xmodem = Xmodem::new();
+        xmodem.block_length = BlockLength::OneK;
+        #[allow(static_mut_refs)]
+        let buffer = unsafe { &mut SEND_BUFFER };
+        xmodem
+            .send_with_buffer(&mut p1, &mut &data_out[..], buffer)
+            .unwrap()
+    });
+    let mut buffer = [0; BlockLength::OneK.packet_buffer_len()];
+    let mut data_in = Vec::new();
+    Xmodem::new()
+        .recv_with_buffer(&mut p2, &mut data_in, Checksum::CRC16, &mut buffer)
+        .unwrap();
+
+    assert_eq!(handle.join().unwrap(), 3000);
+    assert_eq!(data_in.len(), 3 * 1024);
+    assert_eq!(&data_in[..3000], &expected[..]);
+}
+
+#[test]
+fn xmodem_loopback_small_send_buffer() {
+    let data_out: Vec<u8> = (0..300).map(|idx| (idx * 3) as u8).collect();
+    let (mut p1, mut p2) = loopback();
+
+    let expected = data_out.clone();
+    let handle = std::thread::spawn(move || {
+        let mut xmodem = Xmodem::new();
+        // Ignored, since the buffer only has room for 128-byte blocks
+        xmodem.block_length = BlockLength::OneK;
+        let mut buffer = [0; BlockLength::Standard.send_buffer_len()];
+        xmodem
+            .send_with_buffer(&mut p1, &mut &data_out[..], &mut buffer)
+            .unwrap()
+    });
+    let mut data_in = Vec::new();
+    Xmodem::new()
+        .recv(&mut p2, &mut data_in, Checksum::CRC16)
+        .unwrap();
+
+    assert_eq!(handle.join().unwrap(), 300);
+    assert_eq!(data_in.len(), 3 * 128);
+    assert_eq!(&data_in[..300], &expected[..]);
+}
+
+#[test]
+fn xmodem_loopback_small_recv_buffer() {
+    let data_out: Vec<u8> = (0..300).map(|idx| (idx * 5) as u8).collect();
+    let (p1, mut p2) = loopback();
+
+    let handle = spawn_sender(p1, data_out.clone());
+    let mut buffer = [0; BlockLength::Standard.packet_buffer_len()];
+    let mut data_in = Vec::new();
+    Xmodem::new()
+        .recv_with_buffer(&mut p2, &mut data_in, Checksum::CRC16, &mut buffer)
+        .unwrap();
+
+    assert_eq!(handle.join().unwrap(), 300);
+    assert_eq!(data_in.len(), 3 * 128);
+    assert_eq!(&data_in[..300], &data_out[..]);
+}
+
+#[test]
+fn xmodem_small_recv_buffer_rejects_1k_blocks() {
+    let (mut p1, mut p2) = loopback();
+
+    let handle = std::thread::spawn(move || {
+        let mut xmodem = Xmodem::new();
+        xmodem.block_length = BlockLength::OneK;
+        xmodem.max_errors = 3;
+        xmodem.send(&mut p1, &mut &[0x42; 2000][..])
+    });
+    let mut xmodem = Xmodem::new();
+    xmodem.max_errors = 3;
+    let mut buffer = [0; BlockLength::Standard.packet_buffer_len()];
+    let mut data_in = Vec::new();
+    let res = xmodem.recv_with_buffer(&mut p2, &mut data_in, Checksum::CRC16, &mut buffer);
+
+    assert!(matches!(res, Err(Error::ExhaustedRetries(_))));
+    assert!(data_in.is_empty());
+    // The receiver gives up on the 1K block and cancels
+    assert!(matches!(
+        handle.join().unwrap(),
+        Err(Error::Canceled(_)) | Err(Error::ExhaustedRetries(_))
+    ));
+}
+
+#[test]
+#[should_panic(expected = "too small")]
+fn xmodem_recv_buffer_too_small() {
+    let (_p1, mut p2) = loopback();
+    let mut buffer = [0; 100];
+    let _ = Xmodem::new().recv_with_buffer(&mut p2, &mut Vec::new(), Checksum::CRC16, &mut buffer);
+}
+
+#[test]
+fn xmodem_transfer_combines_parts() {
+    let data_out: Vec<u8> = (0..300).map(|idx| (idx * 7) as u8).collect();
+    let (mut p1, mut p2) = loopback();
+
+    let expected = data_out.clone();
+    let handle = std::thread::spawn(move || {
+        let mut buffer = [0; BlockLength::Standard.send_buffer_len()];
+        let mut report = TransferReport::default();
+        let res = Xmodem::new()
+            .transfer()
+            .buffer(&mut buffer)
+            .observer(&mut report)
+            .send(&mut p1, &mut &data_out[..]);
+        (report, res)
+    });
+    let mut buffer = [0; BlockLength::Standard.packet_buffer_len()];
+    let mut clock = FakeClock(Duration::ZERO);
+    let mut report = TransferReport::default();
+    let mut data_in = Vec::new();
+    Xmodem::new()
+        .transfer()
+        .buffer(&mut buffer)
+        .clock(&mut clock)
+        .observer(&mut report)
+        .recv(&mut p2, &mut data_in, Checksum::CRC16)
+        .unwrap();
+
+    let (sent, res) = handle.join().unwrap();
+    assert_eq!(res.unwrap(), 300);
+    assert_eq!(sent.blocks, 3);
+    assert_eq!(report.blocks, 3);
+    assert!(clock.0 > Duration::ZERO);
+    assert_eq!(&data_in[..300], &expected[..]);
+}
+
+#[test]
+fn ymodem_recv_cancels_on_eot_instead_of_header() {
+    let mut peer = ScriptedPeer::new(b"\x04");
+    let res = Ymodem::new().recv_file(&mut peer, |_| Ok(Vec::new()));
+
+    assert!(matches!(res, Err(Error::Invalid)));
+    // The EOT is acknowledged before the receiver notices the problem
+    let mut expected = b"C\x06".to_vec();
+    expected.extend_from_slice(&[0x18; 8]);
+    assert_eq!(peer.wire, expected);
+}
+
+#[test]
+fn ymodem_cancel_sequence_is_configurable() {
+    let mut ymodem = Ymodem::new();
+    ymodem.cancel_count = 3;
+    ymodem.cancel_backspaces = true;
+    let mut peer = ScriptedPeer::new(b"\x04");
+    let res = ymodem.recv_file(&mut peer, |_| Ok(Vec::new()));
+
+    assert!(matches!(res, Err(Error::Invalid)));
+    assert_eq!(peer.wire, b"C\x06\x18\x18\x18\x08\x08\x08");
+}
+
+#[test]
+fn zmodem_cancel_sequence_is_configurable() {
+    let mut zmodem = Zmodem::new();
+    zmodem.max_errors = 2;
+    zmodem.header_timeout = Duration::from_millis(1);
+    zmodem.cancel_count = 5;
+    zmodem.cancel_backspaces = false;
+    let mut peer = ScriptedPeer::new(b"");
+    let res = zmodem.recv_file(&mut peer, |_| Ok(Vec::new()));
+
+    assert!(matches!(res, Err(Error::ExhaustedRetries(_))));
+    assert!(peer.wire.ends_with(&[0x18; 5]));
+    assert!(!peer.wire.ends_with(&[0x18; 6]));
+}
+
+#[test]
+fn ymodem_recv_with_clock() {
+    let (mut p1, _p2) = nonblocking_loopback();
+    let mut ymodem = Ymodem::new();
+    ymodem.max_errors = 2;
+    ymodem.handshake_timeout = Duration::from_secs(5);
+
+    let mut clock = FakeClock(Duration::ZERO);
+    let res = ymodem.recv_file_with_clock(&mut p1, |_| Ok(Vec::new()), &mut clock);
+    assert!(matches!(res, Err(Error::ExhaustedRetries(_))));
+    assert!(clock.0 >= Duration::from_secs(10));
+}
+
+#[test]
+fn zmodem_recv_with_clock() {
+    let (mut p1, _p2) = nonblocking_loopback();
+    let mut zmodem = Zmodem::new();
+    zmodem.max_errors = 2;
+    zmodem.header_timeout = Duration::from_secs(5);
+
+    let mut clock = FakeClock(Duration::ZERO);
+    let res = zmodem.recv_file_with_clock(&mut p1, |_| Ok(Vec::new()), &mut clock);
+    assert!(matches!(res, Err(Error::ExhaustedRetries(_))));
+    assert!(clock.0 >= Duration::from_secs(10));
+}
+
+#[test]
+fn zmodem_send_with_clock() {
+    let (mut p1, _p2) = nonblocking_loopback();
+    let mut zmodem = Zmodem::new();
+    zmodem.max_errors = 2;
+    zmodem.header_timeout = Duration::from_secs(5);
+
+    let mut clock = FakeClock(Duration::ZERO);
+    let info = FileInfo::new("a.txt", 10);
+    let res = zmodem.send_file_with_clock(&mut p1, &info, &mut &[0u8; 10][..], &mut clock);
+    assert!(matches!(res, Err(Error::ExhaustedRetries(_))));
+    assert!(clock.0 >= Duration::from_secs(10));
+}
+
+#[test]
+fn ymodem_send_handshake_timeout() {
+    let (mut p1, _p2) = nonblocking_loopback();
+    let mut ymodem = Ymodem::new();
+    ymodem.max_errors = 3;
+    ymodem.handshake_timeout = Duration::from_millis(20);
+
+    let start = Instant::now();
+    let info = FileInfo::new("a.txt", 10);
+    let res = ymodem.send_file(&mut p1, &info, &mut &[0u8; 10][..]);
+    assert!(matches!(res, Err(Error::ExhaustedRetries(_))));
+    assert!(start.elapsed() < Duration::from_secs(5));
+}
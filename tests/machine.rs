@@ -0,0 +1,740 @@
+//! Test the sans-IO state machines without any IO or threads
+extern crate xmodem;
+
+use xmodem::{
+    BlockLength, Checksum, Error, Phase, ReceiverEvent, ReceiverMachine, RetryCause, SenderEvent,
+    SenderMachine, Xmodem,
+};
+
+fn drain_sender(machine: &mut SenderMachine) -> Vec<u8> {
+    let mut out = Vec::new();
+    while let Some(bytes) = machine.poll_output() {
+        out.extend_from_slice(bytes);
+    }
+    out
+}
+
+fn drain_receiver(machine: &mut ReceiverMachine) -> Vec<u8> {
+    let mut out = Vec::new();
+    while let Some(bytes) = machine.poll_output() {
+        out.extend_from_slice(bytes);
+    }
+    out
+}
+
+/// Runs a whole transfer by shuttling bytes between the two machines.
+fn machine_loopback(checksum: Checksum, block_length: BlockLength, data: &[u8]) -> Vec<u8> {
+    let mut config = Xmodem::new();
+    config.block_length = block_length;
+    let mut sender = SenderMachine::new(&config);
+    let mut receiver = ReceiverMachine::new(&config, checksum);
+    let mut chunks = data.chunks(block_length as usize);
+    let mut received = Vec::new();
+
+    let mut to_sender = drain_receiver(&mut receiver);
+    while !(sender.is_finished() && receiver.is_finished()) {
+        let mut to_receiver = Vec::new();
+        for b in to_sender.drain(..) {
+            match sender.handle_byte(b).unwrap() {
+                Some(SenderEvent::Started(_)) | Some(SenderEvent::BlockDone(_)) => {
+                    match chunks.next() {
+                        Some(chunk) => {
+                            sender.next_block()[..chunk.len()].copy_from_slice(chunk);
+                            sender.send_block();
+                        }
+                        None => sender.finish(),
+                    }
+                }
+                Some(SenderEvent::Retry(_))
+                | Some(SenderEvent::Downgraded(_))
+                | Some(SenderEvent::Finished)
+                | None => {}
+            }
+            to_receiver.extend(drain_sender(&mut sender));
+        }
+
+        for b in to_receiver {
+            if let Some(ReceiverEvent::Block(_)) = receiver.handle_byte(b).unwrap() {
+                received.extend_from_slice(receiver.block());
+                receiver.accept_block();
+            }
+            to_sender.extend(drain_receiver(&mut receiver));
+        }
+    }
+    received
+}
+
+#[test]
+fn machine_loopback_standard() {
+    let data: Vec<u8> = (0..1000).map(|idx| (idx * 7) as u8).collect();
+    let received = machine_loopback(Checksum::Standard, BlockLength::Standard, &data);
+    assert_eq!(received.len(), 1024);
+    assert_eq!(&received[..1000], &data[..]);
+    assert!(received[1000..].iter().all(|&b| b == 0x1a));
+}
+
+#[test]
+fn machine_loopback_onek_crc() {
+    let data: Vec<u8> = (0..3000).map(|idx| (idx * 13) as u8).collect();
+    let received = machine_loopback(Checksum::CRC16, BlockLength::OneK, &data);
+    assert_eq!(received.len(), 3072);
+    assert_eq!(&received[..3000], &data[..]);
+}
+
+#[test]
+fn sender_retransmits_on_nak() {
+    let mut sender = SenderMachine::new(&Xmodem::new());
+    assert_eq!(
+        sender.handle_byte(b'C').unwrap(),
+        Some(SenderEvent::Started(Checksum::CRC16))
+    );
+    sender.next_block()[0] = 42;
+    sender.send_block();
+    let first = drain_sender(&mut sender);
+    assert_eq!(first.len(), 3 + 128 + 2);
+    assert_eq!(&first[..4], &[0x01, 1, 0xFE, 42]);
+
+    assert_eq!(
+        sender.handle_byte(0x15).unwrap(),
+        Some(SenderEvent::Retry(RetryCause::Nak))
+    );
+    assert_eq!(drain_sender(&mut sender), first);
+
+    assert_eq!(
+        sender.handle_timeout().unwrap(),
+        Some(SenderEvent::Retry(RetryCause::Timeout))
+    );
+    assert_eq!(drain_sender(&mut sender), first);
+
+    assert_eq!(
+        sender.handle_byte(b'x').unwrap(),
+        Some(SenderEvent::Retry(RetryCause::Garbage))
+    );
+    assert_eq!(drain_sender(&mut sender), first);
+
+    assert_eq!(
+        sender.handle_byte(0x06).unwrap(),
+        Some(SenderEvent::BlockDone(1))
+    );
+}
+
+#[test]
+fn receiver_gives_up_after_max_errors() {
+    let mut config = Xmodem::new();
+    config.max_errors = 3;
+    let mut receiver = ReceiverMachine::new(&config, Checksum::Standard);
+    assert_eq!(drain_receiver(&mut receiver), [0x15]);
+
+    for _ in 0..2 {
+        assert_eq!(
+            receiver.handle_timeout().unwrap(),
+            Some(ReceiverEvent::Retry(RetryCause::Timeout))
+        );
+    }
+    assert!(matches!(
+        receiver.handle_timeout(),
+        Err(Error::ExhaustedRetries(Phase::Handshake))
+    ));
+    assert_eq!(drain_receiver(&mut receiver), [0x18; 8]);
+}
+
+/// Returns a sender that has sent its first block and waits for the ACK.
+fn sender_waiting_for_ack() -> SenderMachine {
+    let mut sender = SenderMachine::new(&Xmodem::new());
+    sender.handle_byte(b'C').unwrap();
+    sender.next_block();
+    sender.send_block();
+    drain_sender(&mut sender);
+    sender
+}
+
+#[test]
+fn sender_canceled_during_data() {
+    let mut sender = sender_waiting_for_ack();
+    assert_eq!(sender.handle_byte(0x18).unwrap(), None);
+    assert!(matches!(
+        sender.handle_byte(0x18),
+        Err(Error::Canceled(Phase::Block(1)))
+    ));
+    assert_eq!(drain_sender(&mut sender), []);
+}
+
+#[test]
+fn sender_canceled_during_eot() {
+    let mut sender = sender_waiting_for_ack();
+    sender.handle_byte(0x06).unwrap();
+    sender.finish();
+    assert_eq!(drain_sender(&mut sender), [0x04]);
+    assert_eq!(sender.handle_byte(0x18).unwrap(), None);
+    assert!(matches!(
+        sender.handle_byte(0x18),
+        Err(Error::Canceled(Phase::Eot))
+    ));
+}
+
+#[test]
+fn sender_ignores_single_can() {
+    let mut sender = sender_waiting_for_ack();
+    // A lone CAN is not retransmitted for, and doesn't cancel
+    assert_eq!(sender.handle_byte(0x18).unwrap(), None);
+    assert_eq!(drain_sender(&mut sender), []);
+    assert_eq!(
+        sender.handle_byte(0x06).unwrap(),
+        Some(SenderEvent::BlockDone(1))
+    );
+
+    // CANs separated by other bytes don't cancel either
+    sender.next_block();
+    sender.send_block();
+    drain_sender(&mut sender);
+    assert_eq!(sender.handle_byte(0x18).unwrap(), None);
+    assert_eq!(
+        sender.handle_byte(0x15).unwrap(),
+        Some(SenderEvent::Retry(RetryCause::Nak))
+    );
+    assert_eq!(sender.handle_byte(0x18).unwrap(), None);
+    assert_eq!(
+        sender.handle_byte(0x06).unwrap(),
+        Some(SenderEvent::BlockDone(2))
+    );
+}
+
+/// Builds a 128-byte block with the standard checksum.
+fn standard_packet(seqno: u8, fill: u8) -> Vec<u8> {
+    let mut packet = vec![0x01, seqno, 0xFF - seqno];
+    packet.extend_from_slice(&[fill; 128]);
+    packet.push(fill.wrapping_mul(128));
+    packet
+}
+
+fn feed_receiver(receiver: &mut ReceiverMachine, bytes: &[u8]) -> Option<ReceiverEvent> {
+    let mut event = None;
+    for &b in bytes {
+        if let Some(e) = receiver.handle_byte(b).unwrap() {
+            assert!(event.is_none());
+            event = Some(e);
+        }
+    }
+    event
+}
+
+#[test]
+fn receiver_acks_duplicate_blocks() {
+    let mut receiver = ReceiverMachine::new(&Xmodem::new(), Checksum::Standard);
+    drain_receiver(&mut receiver);
+
+    for blockno in 1..=300u32 {
+        let seqno = blockno as u8;
+        assert_eq!(
+            feed_receiver(&mut receiver, &standard_packet(seqno, seqno)),
+            Some(ReceiverEvent::Block(blockno))
+        );
+        assert_eq!(receiver.block(), &[seqno; 128][..]);
+        receiver.accept_block();
+        assert_eq!(drain_receiver(&mut receiver), [0x06]);
+
+        // Repeat every block, as if the ACK got lost. This covers the
+        // wraparound from 255 to 0.
+        assert_eq!(
+            feed_receiver(&mut receiver, &standard_packet(seqno, seqno)),
+            Some(ReceiverEvent::Retry(RetryCause::Sequence))
+        );
+        assert_eq!(drain_receiver(&mut receiver), [0x06]);
+    }
+
+    assert_eq!(
+        feed_receiver(&mut receiver, &[0x04]),
+        Some(ReceiverEvent::Finished)
+    );
+}
+
+#[test]
+fn receiver_cancels_out_of_sequence_block() {
+    let mut receiver = ReceiverMachine::new(&Xmodem::new(), Checksum::Standard);
+    drain_receiver(&mut receiver);
+
+    feed_receiver(&mut receiver, &standard_packet(1, 0));
+    receiver.accept_block();
+    drain_receiver(&mut receiver);
+
+    let packet = standard_packet(3, 0);
+    let (last, rest) = packet.split_last().unwrap();
+    assert_eq!(feed_receiver(&mut receiver, rest), None);
+    assert!(matches!(
+        receiver.handle_byte(*last),
+        Err(Error::SequenceMismatch {
+            expected: 2,
+            received: 3
+        })
+    ));
+    assert_eq!(drain_receiver(&mut receiver), [0x18; 8]);
+}
+
+#[test]
+fn receiver_repeats_handshake_on_timeout() {
+    let mut receiver = ReceiverMachine::new(&Xmodem::new(), Checksum::CRC16);
+    assert_eq!(drain_receiver(&mut receiver), b"C");
+    for _ in 0..5 {
+        assert_eq!(
+            receiver.handle_timeout().unwrap(),
+            Some(ReceiverEvent::Retry(RetryCause::Timeout))
+        );
+        assert_eq!(drain_receiver(&mut receiver), b"C");
+    }
+    assert_eq!(receiver.checksum(), Checksum::CRC16);
+}
+
+#[test]
+fn receiver_auto_checksum_falls_back() {
+    let mut config = Xmodem::new();
+    config.crc_attempts = 2;
+    let mut receiver = ReceiverMachine::new(&config, Checksum::Auto);
+    assert_eq!(receiver.checksum(), Checksum::CRC16);
+    assert_eq!(drain_receiver(&mut receiver), b"C");
+    receiver.handle_timeout().unwrap();
+    assert_eq!(drain_receiver(&mut receiver), b"C");
+    receiver.handle_timeout().unwrap();
+    assert_eq!(drain_receiver(&mut receiver), [0x15]);
+    assert_eq!(receiver.checksum(), Checksum::Standard);
+    receiver.handle_timeout().unwrap();
+    assert_eq!(drain_receiver(&mut receiver), [0x15]);
+
+    assert_eq!(
+        feed_receiver(&mut receiver, &standard_packet(1, 9)),
+        Some(ReceiverEvent::Block(1))
+    );
+}
+
+#[test]
+fn receiver_auto_checksum_uses_crc() {
+    let mut receiver = ReceiverMachine::new(&Xmodem::new(), Checksum::Auto);
+    let received = {
+        let mut sender = SenderMachine::new(&Xmodem::new());
+        let start = drain_receiver(&mut receiver);
+        assert_eq!(
+            sender.handle_byte(start[0]).unwrap(),
+            Some(SenderEvent::Started(Checksum::CRC16))
+        );
+        sender.next_block().fill(7);
+        sender.send_block();
+        feed_receiver(&mut receiver, &drain_sender(&mut sender))
+    };
+    assert_eq!(received, Some(ReceiverEvent::Block(1)));
+    assert_eq!(receiver.block(), &[7; 128][..]);
+}
+
+#[test]
+fn receiver_naks_bad_checksum() {
+    let mut receiver = ReceiverMachine::new(&Xmodem::new(), Checksum::Standard);
+    drain_receiver(&mut receiver);
+
+    let mut packet = standard_packet(1, 5);
+    packet[10] ^= 0xff;
+    assert_eq!(
+        feed_receiver(&mut receiver, &packet),
+        Some(ReceiverEvent::Retry(RetryCause::Checksum))
+    );
+    assert_eq!(drain_receiver(&mut receiver), [0x15]);
+    assert_eq!(
+        feed_receiver(&mut receiver, &standard_packet(1, 5)),
+        Some(ReceiverEvent::Block(1))
+    );
+}
+
+#[test]
+fn receiver_canceled_by_sender() {
+    let mut receiver = ReceiverMachine::new(&Xmodem::new(), Checksum::Standard);
+    drain_receiver(&mut receiver);
+
+    // A lone CAN is ignored
+    assert_eq!(receiver.handle_byte(0x18).unwrap(), None);
+    assert_eq!(
+        feed_receiver(&mut receiver, &standard_packet(1, 0)),
+        Some(ReceiverEvent::Block(1))
+    );
+    receiver.accept_block();
+    drain_receiver(&mut receiver);
+
+    assert_eq!(receiver.handle_byte(0x18).unwrap(), None);
+    assert!(matches!(
+        receiver.handle_byte(0x18),
+        Err(Error::Canceled(Phase::Block(2)))
+    ));
+    assert_eq!(drain_receiver(&mut receiver), []);
+}
+
+#[test]
+fn sender_cancel() {
+    let mut sender = sender_waiting_for_ack();
+    sender.cancel();
+    assert_eq!(drain_sender(&mut sender), [0x18; 8]);
+    assert_eq!(sender.handle_byte(0x06).unwrap(), None);
+}
+
+#[test]
+fn sender_cancels_after_max_errors() {
+    let mut config = Xmodem::new();
+    config.max_errors = 2;
+
+    // During the handshake
+    let mut sender = SenderMachine::new(&config);
+    sender.handle_timeout().unwrap();
+    assert!(matches!(
+        sender.handle_byte(b'x'),
+        Err(Error::ExhaustedRetries(Phase::Handshake))
+    ));
+    assert_eq!(drain_sender(&mut sender), [0x18; 8]);
+
+    // While sending a block
+    let mut sender = SenderMachine::new(&config);
+    sender.handle_byte(b'C').unwrap();
+    sender.next_block();
+    sender.send_block();
+    drain_sender(&mut sender);
+    sender.handle_byte(0x15).unwrap();
+    drain_sender(&mut sender);
+    assert!(matches!(
+        sender.handle_byte(0x15),
+        Err(Error::ExhaustedRetries(Phase::Block(1)))
+    ));
+    assert_eq!(drain_sender(&mut sender), [0x18; 8]);
+
+    // While waiting for the EOT to be acknowledged
+    sender = SenderMachine::new(&config);
+    sender.handle_byte(0x15).unwrap();
+    sender.finish();
+    drain_sender(&mut sender);
+    sender.handle_timeout().unwrap();
+    assert!(matches!(
+        sender.handle_timeout(),
+        Err(Error::ExhaustedRetries(Phase::Eot))
+    ));
+    assert_eq!(drain_sender(&mut sender), [0x18; 8]);
+}
+
+#[test]
+fn cancel_sequence_is_configurable() {
+    let mut config = Xmodem::new();
+    config.cancel_count = 3;
+    config.cancel_backspaces = true;
+
+    let mut sender = SenderMachine::new(&config);
+    sender.cancel();
+    assert_eq!(
+        drain_sender(&mut sender),
+        [0x18, 0x18, 0x18, 0x08, 0x08, 0x08]
+    );
+
+    config.cancel_count = 100;
+    let mut receiver = ReceiverMachine::new(&config, Checksum::CRC16);
+    drain_receiver(&mut receiver);
+    receiver.cancel();
+    let mut expected = vec![0x18; 16];
+    expected.extend_from_slice(&[0x08; 16]);
+    assert_eq!(drain_receiver(&mut receiver), expected);
+}
+
+#[test]
+fn receiver_naks_bad_sequence_complement() {
+    let mut receiver = ReceiverMachine::new(&Xmodem::new(), Checksum::Standard);
+    drain_receiver(&mut receiver);
+
+    let mut packet = standard_packet(1, 0);
+    packet[2] = 0;
+    assert_eq!(
+        feed_receiver(&mut receiver, &packet),
+        Some(ReceiverEvent::Retry(RetryCause::Checksum))
+    );
+    assert_eq!(drain_receiver(&mut receiver), [0x15]);
+    assert_eq!(
+        feed_receiver(&mut receiver, &standard_packet(1, 0)),
+        Some(ReceiverEvent::Block(1))
+    );
+}
+
+#[test]
+fn error_display() {
+    assert_eq!(
+        Error::ExhaustedRetries(Phase::Block(42)).to_string(),
+        "too many errors at block 42"
+    );
+    assert_eq!(
+        Error::Canceled(Phase::Handshake).to_string(),
+        "canceled by the other end during the handshake"
+    );
+    assert_eq!(
+        Error::Rejected(Phase::Block(1)).to_string(),
+        "rejected by the sink at block 1"
+    );
+    assert_eq!(
+        Error::SequenceMismatch {
+            expected: 3,
+            received: 5
+        }
+        .to_string(),
+        "received block with sequence number 5 while expecting 3"
+    );
+
+    let err = Error::from(std::io::Error::other("unplugged"));
+    assert_eq!(err.to_string(), "IO error: unplugged");
+    let source = std::error::Error::source(&err).unwrap();
+    assert_eq!(source.to_string(), "unplugged");
+    assert!(std::error::Error::source(&Error::Aborted).is_none());
+}
+
+#[test]
+fn sender_downgrades_short_tail() {
+    let mut config = Xmodem::new();
+    config.block_length = BlockLength::OneK;
+    let mut sender = SenderMachine::new(&config);
+    sender.handle_byte(b'C').unwrap();
+    assert_eq!(sender.next_block_for(300).len(), 1024);
+
+    config.downgrade_blocks = true;
+    let mut sender = SenderMachine::new(&config);
+    sender.handle_byte(b'C').unwrap();
+    assert_eq!(sender.next_block_for(1024).len(), 1024);
+    assert_eq!(sender.next_block_for(897).len(), 1024);
+    assert_eq!(sender.next_block_for(896).len(), 128);
+    sender.send_block();
+    assert_eq!(drain_sender(&mut sender)[0], 0x01);
+}
+
+#[test]
+fn sender_downgrades_after_errors() {
+    let mut config = Xmodem::new();
+    config.block_length = BlockLength::OneK;
+    config.downgrade_blocks = true;
+    config.downgrade_errors = 2;
+    let mut sender = SenderMachine::new(&config);
+    sender.handle_byte(b'C').unwrap();
+    sender.next_block();
+    sender.send_block();
+    let first = drain_sender(&mut sender);
+    assert_eq!(first.len(), 3 + 1024 + 2);
+
+    assert_eq!(
+        sender.handle_byte(0x15).unwrap(),
+        Some(SenderEvent::Retry(RetryCause::Nak))
+    );
+    assert_eq!(drain_sender(&mut sender), first);
+
+    // Instead of repeating the block in flight, the sender asks for its
+    // data again, to send it as 128-byte blocks starting from block 1
+    assert_eq!(
+        sender.handle_byte(0x15).unwrap(),
+        Some(SenderEvent::Downgraded(RetryCause::Nak))
+    );
+    assert_eq!(drain_sender(&mut sender), []);
+    assert_eq!(sender.block_length(), BlockLength::Standard);
+    assert_eq!(sender.next_block().len(), 128);
+    sender.send_block();
+    let packet = drain_sender(&mut sender);
+    assert_eq!(packet.len(), 3 + 128 + 2);
+    assert_eq!(&packet[..3], &[0x01, 1, 0xfe]);
+    assert_eq!(
+        sender.handle_byte(0x06).unwrap(),
+        Some(SenderEvent::BlockDone(1))
+    );
+}
+
+#[test]
+fn streaming_without_acks() {
+    let mut config = Xmodem::new();
+    config.streaming = true;
+    let mut sender = SenderMachine::new(&config);
+    let mut receiver = ReceiverMachine::new(&config, Checksum::Standard);
+    assert_eq!(drain_receiver(&mut receiver), b"G");
+    assert_eq!(
+        sender.handle_byte(b'G').unwrap(),
+        Some(SenderEvent::Started(Checksum::CRC16))
+    );
+    assert!(sender.is_streaming());
+
+    for blockno in 1..=3 {
+        sender.next_block()[0] = blockno as u8;
+        sender.send_block();
+        assert_eq!(sender.poll_event(), None);
+        for b in drain_sender(&mut sender) {
+            if let Some(event) = receiver.handle_byte(b).unwrap() {
+                assert_eq!(event, ReceiverEvent::Block(blockno));
+                assert_eq!(receiver.block()[0], blockno as u8);
+                receiver.accept_block();
+            }
+        }
+        assert_eq!(drain_receiver(&mut receiver), b"");
+        assert_eq!(sender.poll_event(), Some(SenderEvent::BlockDone(blockno)));
+        assert_eq!(sender.poll_event(), None);
+    }
+
+    sender.finish();
+    for b in drain_sender(&mut sender) {
+        receiver.handle_byte(b).unwrap();
+    }
+    assert!(receiver.is_finished());
+    assert_eq!(drain_receiver(&mut receiver), [0x06]);
+    assert_eq!(
+        sender.handle_byte(0x06).unwrap(),
+        Some(SenderEvent::Finished)
+    );
+}
+
+#[test]
+fn streaming_aborts_on_errors() {
+    let mut config = Xmodem::new();
+    config.streaming = true;
+    let mut sender = SenderMachine::new(&config);
+    sender.handle_byte(b'G').unwrap();
+    sender.next_block();
+    sender.send_block();
+    let mut frame = drain_sender(&mut sender);
+    frame[10] ^= 0xFF;
+
+    let mut receiver = ReceiverMachine::new(&config, Checksum::CRC16);
+    drain_receiver(&mut receiver);
+    let (last, rest) = frame.split_last().unwrap();
+    for &b in rest {
+        assert_eq!(receiver.handle_byte(b).unwrap(), None);
+    }
+    assert!(matches!(receiver.handle_byte(*last), Err(Error::Checksum)));
+    assert_eq!(drain_receiver(&mut receiver), [0x18; 8]);
+
+    // The sender doesn't repeat anything, so a timeout is fatal as well
+    let mut receiver = ReceiverMachine::new(&config, Checksum::CRC16);
+    drain_receiver(&mut receiver);
+    assert_eq!(receiver.handle_byte(0x01).unwrap(), None);
+    assert!(matches!(
+        receiver.handle_timeout(),
+        Err(Error::ExhaustedRetries(Phase::Block(1)))
+    ));
+
+    // The receiver can still cancel while blocks are streaming
+    assert_eq!(sender.handle_byte(0x18).unwrap(), None);
+    assert!(matches!(
+        sender.handle_byte(0x18),
+        Err(Error::Canceled(Phase::Block(1)))
+    ));
+}
+
+#[test]
+fn sender_with_small_buffer() {
+    let mut config = Xmodem::new();
+    config.block_length = BlockLength::OneK;
+    let mut buffer = [0; BlockLength::Standard.packet_buffer_len()];
+    let mut sender = SenderMachine::with_buffer(&config, &mut buffer[..]);
+    assert_eq!(sender.block_length(), BlockLength::Standard);
+    sender.handle_byte(b'C').unwrap();
+    sender.next_block().fill(0x42);
+    sender.send_block();
+
+    let mut out = Vec::new();
+    while let Some(bytes) = sender.poll_output() {
+        out.extend_from_slice(bytes);
+    }
+    assert_eq!(out.len(), 3 + 128 + 2);
+    assert_eq!(out[0], 0x01);
+}
+
+#[test]
+fn receiver_with_small_buffer_skips_1k_blocks() {
+    let mut buffer = [0; BlockLength::Standard.packet_buffer_len()];
+    let mut receiver =
+        ReceiverMachine::with_buffer(&Xmodem::new(), Checksum::Standard, &mut buffer[..]);
+    assert_eq!(receiver.poll_output(), Some(&[0x15][..]));
+
+    // A whole 1K packet, which can't be stored
+    let mut events = Vec::new();
+    let mut packet = vec![0x02, 1, 0xFE];
+    packet.extend_from_slice(&[0x18; 1024]);
+    packet.push(0);
+    for b in packet {
+        events.extend(receiver.handle_byte(b).unwrap());
+    }
+    assert_eq!(events, [ReceiverEvent::Retry(RetryCause::Garbage)]);
+    assert_eq!(receiver.poll_output(), Some(&[0x15][..]));
+
+    let mut events = Vec::new();
+    for b in standard_packet(1, 7) {
+        events.extend(receiver.handle_byte(b).unwrap());
+    }
+    assert_eq!(events, [ReceiverEvent::Block(1)]);
+    assert_eq!(receiver.block(), &[7; 128][..]);
+}
+
+#[test]
+#[should_panic(expected = "too small")]
+fn sender_buffer_too_small() {
+    SenderMachine::with_buffer(&Xmodem::new(), [0; 64]);
+}
+
+#[test]
+fn receiver_rejects_block() {
+    let mut config = Xmodem::new();
+    config.max_errors = 2;
+    let mut receiver = ReceiverMachine::new(&config, Checksum::Standard);
+    drain_receiver(&mut receiver);
+
+    let packet = standard_packet(1, 9);
+    assert_eq!(
+        feed_receiver(&mut receiver, &packet),
+        Some(ReceiverEvent::Block(1))
+    );
+    receiver.reject_block().unwrap();
+    assert_eq!(drain_receiver(&mut receiver), [0x15]);
+
+    // The same block is expected again, and the second rejection is fatal
+    assert_eq!(
+        feed_receiver(&mut receiver, &packet),
+        Some(ReceiverEvent::Block(1))
+    );
+    assert!(matches!(
+        receiver.reject_block(),
+        Err(Error::ExhaustedRetries(Phase::Block(1)))
+    ));
+    assert_eq!(drain_receiver(&mut receiver), [0x18; 8]);
+}
+
+#[test]
+fn receiver_naks_packet_after_stray_soh() {
+    let mut receiver = ReceiverMachine::new(&Xmodem::new(), Checksum::Standard);
+    drain_receiver(&mut receiver);
+
+    // Line noise that looks like SOH shifts the packet by a byte, so its
+    // header doesn't check out
+    let mut bytes = vec![0x01];
+    bytes.extend(standard_packet(1, 0x20));
+    let mut events = Vec::new();
+    for b in bytes {
+        events.extend(receiver.handle_byte(b).unwrap());
+    }
+    assert_eq!(events, [ReceiverEvent::Retry(RetryCause::Checksum)]);
+    assert_eq!(drain_receiver(&mut receiver), [0x15]);
+
+    assert_eq!(
+        feed_receiver(&mut receiver, &standard_packet(1, 0x20)),
+        Some(ReceiverEvent::Block(1))
+    );
+}
+
+#[test]
+fn receiver_corrupted_headers_exhaust_retries() {
+    let mut config = Xmodem::new();
+    config.max_errors = 3;
+    let mut receiver = ReceiverMachine::new(&config, Checksum::Standard);
+    drain_receiver(&mut receiver);
+
+    let mut packet = standard_packet(3, 0);
+    packet[2] = 3;
+    for _ in 0..2 {
+        assert_eq!(
+            feed_receiver(&mut receiver, &packet),
+            Some(ReceiverEvent::Retry(RetryCause::Checksum))
+        );
+    }
+    let (last, rest) = packet.split_last().unwrap();
+    assert_eq!(feed_receiver(&mut receiver, rest), None);
+    assert!(matches!(
+        receiver.handle_byte(*last),
+        Err(Error::ExhaustedRetries(Phase::Block(1)))
+    ));
+}
@@ -0,0 +1,165 @@
+//! Test the io module used without std. Run with
+//! `cargo test --no-default-features --test no_std_io`.
+#![cfg(not(feature = "std"))]
+extern crate xmodem;
+
+use xmodem::io::{self, ErrorKind, Read, Write};
+use xmodem::{Checksum, Xmodem};
+
+/// Plays back `input` one byte per read, then times out, and records what
+/// is written.
+struct ScriptedPeer<'a> {
+    input: &'a [u8],
+    wire: Vec<u8>,
+}
+
+impl Read for ScriptedPeer<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.input.is_empty() {
+            return Err(ErrorKind::TimedOut.into());
+        }
+        self.input.read(&mut buf[..1])
+    }
+}
+
+impl Write for ScriptedPeer<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.wire.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Returns at most 3 bytes per read, and is interrupted every other call.
+struct Trickle<'a> {
+    data: &'a [u8],
+    calls: usize,
+}
+
+impl Read for Trickle<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.calls += 1;
+        if self.calls.is_multiple_of(2) {
+            return Err(ErrorKind::Interrupted.into());
+        }
+        let n = buf.len().min(3);
+        self.data.read(&mut buf[..n])
+    }
+}
+
+/// Accepts nothing.
+struct Full;
+
+impl Write for Full {
+    fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+        Ok(0)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn send_and_recv_with_slices() {
+    let data_out: Vec<u8> = (0..200).map(|idx| (idx * 7) as u8).collect();
+
+    // The receiver asks for CRC16, then ACKs both blocks and the EOT
+    let mut sender_dev = ScriptedPeer {
+        input: b"C\x06\x06\x06",
+        wire: Vec::new(),
+    };
+    let bytes = Xmodem::new()
+        .send(&mut sender_dev, &mut &data_out[..])
+        .unwrap();
+    assert_eq!(bytes, 200);
+    assert_eq!(sender_dev.wire.len(), 2 * (3 + 128 + 2) + 1);
+
+    let mut receiver_dev = ScriptedPeer {
+        input: &sender_dev.wire,
+        wire: Vec::new(),
+    };
+    let mut data_in = [0; 256];
+    let bytes = Xmodem::new()
+        .recv(&mut receiver_dev, &mut &mut data_in[..], Checksum::CRC16)
+        .unwrap();
+    assert_eq!(bytes, 256);
+    assert_eq!(receiver_dev.wire, b"C\x06\x06\x06");
+    assert_eq!(&data_in[..200], &data_out[..]);
+    assert!(data_in[200..].iter().all(|&b| b == 0x1a));
+}
+
+#[test]
+fn recv_fails_when_the_output_is_full() {
+    let mut sender_dev = ScriptedPeer {
+        input: b"C\x06\x06\x06",
+        wire: Vec::new(),
+    };
+    Xmodem::new()
+        .send(&mut sender_dev, &mut &[0x42; 200][..])
+        .unwrap();
+
+    let mut receiver_dev = ScriptedPeer {
+        input: &sender_dev.wire,
+        wire: Vec::new(),
+    };
+    let mut data_in = [0; 200];
+    let res = Xmodem::new().recv(&mut receiver_dev, &mut &mut data_in[..], Checksum::CRC16);
+    assert!(matches!(res, Err(xmodem::Error::Io(e)) if e.kind() == ErrorKind::Other));
+    // The first block fit, the second one didn't
+    assert!(receiver_dev.wire.starts_with(b"C\x06\x18"));
+}
+
+#[test]
+fn slice_read_exact() {
+    let mut data = &b"abcdef"[..];
+    let mut buf = [0; 4];
+    data.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"abcd");
+    assert_eq!(data, b"ef");
+
+    let err = data.read_exact(&mut buf).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+    assert!(data.is_empty());
+}
+
+#[test]
+fn read_exact_retries_short_reads() {
+    let mut reader = Trickle {
+        data: b"0123456789",
+        calls: 0,
+    };
+    let mut buf = [0; 8];
+    reader.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"01234567");
+
+    let err = reader.read_exact(&mut buf).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+}
+
+#[test]
+fn write_all_stops_when_nothing_is_written() {
+    let err = Full.write_all(b"abc").unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::Other);
+
+    let mut buf = [0; 2];
+    let err = (&mut buf[..]).write_all(b"abc").unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::Other);
+}
+
+#[test]
+fn error_display() {
+    let err = io::Error::from(ErrorKind::WouldBlock);
+    assert_eq!(err.kind(), ErrorKind::WouldBlock);
+    assert_eq!(
+        err.to_string(),
+        "IO error WouldBlock: operation would block"
+    );
+    assert_eq!(
+        io::Error::other("unplugged").to_string(),
+        "IO error Other: unplugged"
+    );
+}
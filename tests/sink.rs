@@ -0,0 +1,164 @@
+//! Test receiving into a BlockSink
+extern crate xmodem;
+
+use std::time::Duration;
+use xmodem::testing::loopback;
+use xmodem::{
+    BlockAction, BlockLength, BlockSink, Checksum, Clock, Error, Phase, TransferObserver,
+    TransferReport, Xmodem,
+};
+
+/// Pretends to program 128-byte flash pages, failing the first attempt at
+/// some of them.
+#[derive(Default)]
+struct Flash {
+    image: Vec<u8>,
+    blocks: Vec<(u32, u64)>,
+    flaky: Vec<u32>,
+}
+
+impl BlockSink for Flash {
+    fn block(&mut self, blockno: u32, offset: u64, data: &[u8]) -> BlockAction {
+        self.blocks.push((blockno, offset));
+        if let Some(i) = self.flaky.iter().position(|&b| b == blockno) {
+            self.flaky.remove(i);
+            return BlockAction::Retransmit;
+        }
+        assert_eq!(offset, self.image.len() as u64);
+        self.image.extend_from_slice(data);
+        BlockAction::Accept
+    }
+}
+
+#[test]
+fn sink_gets_blocks_with_offsets() {
+    let data_out: Vec<u8> = (0..300).map(|idx| (idx * 7) as u8).collect();
+    let (mut p1, mut p2) = loopback();
+    let expected = data_out.clone();
+    let handle = std::thread::spawn(move || Xmodem::new().send(&mut p1, &mut &data_out[..]));
+
+    let mut flash = Flash {
+        flaky: vec![2],
+        ..Flash::default()
+    };
+    let bytes = Xmodem::new()
+        .recv_with_sink(&mut p2, &mut flash, Checksum::CRC16)
+        .unwrap();
+
+    assert_eq!(handle.join().unwrap().unwrap(), 300);
+    assert_eq!(bytes, 3 * 128);
+    assert_eq!(flash.blocks, [(1, 0), (2, 128), (2, 128), (3, 256)]);
+    assert_eq!(&flash.image[..300], &expected[..]);
+    assert!(flash.image[300..].iter().all(|&b| b == 0x1a));
+}
+
+#[test]
+fn sink_gives_up_after_max_errors() {
+    let (mut p1, mut p2) = loopback();
+    let handle = std::thread::spawn(move || Xmodem::new().send(&mut p1, &mut &[0u8; 200][..]));
+
+    let mut xmodem = Xmodem::new();
+    xmodem.max_errors = 3;
+    let mut flash = Flash {
+        flaky: vec![1; 3],
+        ..Flash::default()
+    };
+    let res = xmodem.recv_with_sink(&mut p2, &mut flash, Checksum::CRC16);
+
+    assert!(matches!(res, Err(Error::ExhaustedRetries(_))));
+    assert!(matches!(handle.join().unwrap(), Err(Error::Canceled(_))));
+    assert_eq!(flash.blocks, [(1, 0); 3]);
+}
+
+/// Checks the magic number at the start of the image before taking
+/// anything else.
+struct HeaderCheck(usize);
+
+impl BlockSink for HeaderCheck {
+    fn block(&mut self, blockno: u32, _offset: u64, data: &[u8]) -> BlockAction {
+        if blockno == 1 && !data.starts_with(b"IMG1") {
+            return BlockAction::Abort;
+        }
+        self.0 += 1;
+        BlockAction::Accept
+    }
+}
+
+#[test]
+fn sink_aborts_on_bad_header() {
+    let (mut p1, mut p2) = loopback();
+    let handle = std::thread::spawn(move || Xmodem::new().send(&mut p1, &mut &b"ELF!"[..]));
+
+    let mut check = HeaderCheck(0);
+    let res = Xmodem::new().recv_with_sink(&mut p2, &mut check, Checksum::CRC16);
+
+    assert!(matches!(res, Err(Error::Rejected(Phase::Block(1)))));
+    assert!(matches!(handle.join().unwrap(), Err(Error::Canceled(_))));
+    assert_eq!(check.0, 0);
+}
+
+/// Counts how often it is read.
+struct CountingClock(u32);
+
+impl Clock for CountingClock {
+    fn now(&mut self) -> Duration {
+        self.0 += 1;
+        Duration::from_millis(self.0.into())
+    }
+}
+
+#[test]
+fn sink_with_buffer_and_clock() {
+    let data_out: Vec<u8> = (0..200).map(|idx| (idx * 3) as u8).collect();
+    let (mut p1, mut p2) = loopback();
+    let expected = data_out.clone();
+    let handle = std::thread::spawn(move || Xmodem::new().send(&mut p1, &mut &data_out[..]));
+
+    let mut buffer = [0; BlockLength::Standard.packet_buffer_len()];
+    let mut clock = CountingClock(0);
+    let mut report = TransferReport::default();
+    let mut flash = Flash::default();
+    let bytes = Xmodem::new()
+        .transfer()
+        .buffer(&mut buffer)
+        .clock(&mut clock)
+        .observer(&mut report)
+        .recv_to_sink(&mut p2, &mut flash, Checksum::CRC16)
+        .unwrap();
+
+    assert_eq!(handle.join().unwrap().unwrap(), 200);
+    assert_eq!(bytes, 2 * 128);
+    assert_eq!(report.blocks, 2);
+    assert!(clock.0 > 0);
+    assert_eq!(&flash.image[..200], &expected[..]);
+}
+
+/// Counts the handshakes reported.
+struct Handshakes(u32);
+
+impl TransferObserver for Handshakes {
+    fn handshake(&mut self, _checksum: Checksum, _block_length: BlockLength) {
+        self.0 += 1;
+    }
+}
+
+#[test]
+fn sink_retransmit_of_first_block_reports_one_handshake() {
+    let (mut p1, mut p2) = loopback();
+    let handle = std::thread::spawn(move || Xmodem::new().send(&mut p1, &mut &[0x42; 200][..]));
+
+    let mut flash = Flash {
+        flaky: vec![1],
+        ..Flash::default()
+    };
+    let mut handshakes = Handshakes(0);
+    Xmodem::new()
+        .transfer()
+        .observer(&mut handshakes)
+        .recv_to_sink(&mut p2, &mut flash, Checksum::CRC16)
+        .unwrap();
+
+    assert_eq!(handle.join().unwrap().unwrap(), 200);
+    assert_eq!(flash.blocks, [(1, 0), (1, 0), (2, 128)]);
+    assert_eq!(handshakes.0, 1);
+}
@@ -0,0 +1,118 @@
+//! Test the simulated link
+extern crate xmodem;
+
+use std::io::{ErrorKind, Read, Write};
+use std::thread;
+use std::time::{Duration, Instant};
+use xmodem::testing::{Faults, Link};
+use xmodem::{Checksum, Error, Xmodem};
+
+/// Writes `data` through a link with `faults` and returns what arrives.
+fn through(seed: u64, faults: &Faults, data: &[u8]) -> Vec<u8> {
+    let mut link = Link::new();
+    link.seed = seed;
+    link.a_to_b = faults.clone();
+    link.nonblocking = true;
+    let (mut a, mut b) = link.open();
+    a.write_all(data).unwrap();
+    drop(a);
+    let mut out = Vec::new();
+    let mut buf = [0; 64];
+    loop {
+        match b.read(&mut buf) {
+            Ok(n) => out.extend_from_slice(&buf[..n]),
+            Err(e) if e.kind() == ErrorKind::BrokenPipe => return out,
+            Err(e) => panic!("{}", e),
+        }
+    }
+}
+
+#[test]
+fn faults_are_reproducible() {
+    let data: Vec<u8> = (0..5000).map(|idx| idx as u8).collect();
+    let mut faults = Faults::new();
+    faults.drop = 0.01;
+    faults.corrupt = 0.01;
+    faults.duplicate = 0.01;
+    faults.noise = 0.01;
+
+    let first = through(1, &faults, &data);
+    assert_ne!(first, data);
+    assert_eq!(through(1, &faults, &data), first);
+    assert_ne!(through(2, &faults, &data), first);
+}
+
+#[test]
+fn faults_at_offsets() {
+    let mut faults = Faults::new();
+    faults.drop_at = vec![1];
+    faults.corrupt_at = vec![2];
+    faults.cut_after = Some(4);
+    assert_eq!(through(0, &faults, &[1, 2, 3, 4, 5, 6]), [1, !3, 4]);
+}
+
+#[test]
+fn delay_and_read_timeout() {
+    let mut link = Link::new();
+    link.a_to_b.delay = Duration::from_millis(50);
+    link.read_timeout = Some(Duration::from_millis(10));
+    let (mut a, mut b) = link.open();
+
+    let start = Instant::now();
+    a.write_all(b"x").unwrap();
+    let mut buf = [0];
+    let err = b.read(&mut buf).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::TimedOut);
+    while b.read(&mut buf).is_err() {}
+    assert_eq!(buf, *b"x");
+    assert!(start.elapsed() >= Duration::from_millis(50));
+}
+
+#[test]
+fn xmodem_over_noisy_link() {
+    let data: Vec<u8> = (0..10000).map(|idx| (idx * 7) as u8).collect();
+    let mut link = Link::new();
+    link.seed = 42;
+    link.a_to_b.corrupt = 0.0005;
+    link.a_to_b.drop = 0.0005;
+    link.a_to_b.noise = 0.0005;
+    // Noise on the way back could look like an ACK, which XMODEM can't
+    // recover from
+    link.b_to_a.drop = 0.01;
+    link.read_timeout = Some(Duration::from_millis(5));
+    let (mut a, mut b) = link.open();
+
+    let mut xmodem = Xmodem::new();
+    xmodem.byte_timeout = Duration::from_millis(50);
+    xmodem.ack_timeout = Duration::from_millis(200);
+    xmodem.max_errors = 100;
+    xmodem.trim_padding = true;
+
+    let expected = data.clone();
+    let sender = thread::spawn(move || xmodem.send(&mut a, &mut &data[..]).unwrap());
+    let mut received = Vec::new();
+    xmodem.recv(&mut b, &mut received, Checksum::CRC16).unwrap();
+
+    assert_eq!(sender.join().unwrap(), expected.len());
+    assert_eq!(received, expected);
+}
+
+#[test]
+fn xmodem_times_out_on_cut_link() {
+    let mut link = Link::new();
+    link.a_to_b.cut_after = Some(1000);
+    link.read_timeout = Some(Duration::from_millis(5));
+    let (mut a, mut b) = link.open();
+
+    let mut xmodem = Xmodem::new();
+    xmodem.max_errors = 3;
+    xmodem.byte_timeout = Duration::from_millis(20);
+    xmodem.ack_timeout = Duration::from_millis(20);
+
+    let sender = thread::spawn(move || Xmodem::new().send(&mut a, &mut &[0x55; 5000][..]));
+    let res = xmodem.recv(&mut b, &mut Vec::new(), Checksum::CRC16);
+    assert!(matches!(res, Err(Error::ExhaustedRetries(_))));
+    // The receiver's CAN still gets through
+    let res = sender.join().unwrap();
+    assert!(matches!(res, Err(Error::Canceled(_))));
+}